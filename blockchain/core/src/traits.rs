@@ -14,6 +14,12 @@ pub trait Block: Clone {
 	fn parent_id(&self) -> Option<Self::Identifier>;
 }
 
+/// A block that carries a proof-of-work difficulty.
+pub trait DifficultyBlock: Block {
+	/// Get the difficulty of this block.
+	fn difficulty(&self) -> u128;
+}
+
 /// A value where the key is contained in.
 pub trait Auxiliary<B: Block>: Clone {
 	/// Key type
@@ -0,0 +1,155 @@
+use std::{fmt, fs, io};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use libp2p::{identity, PeerId, Multiaddr};
+use libp2p::multiaddr::Protocol;
+
+/// Configuration of a libp2p node.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+	/// File storing the node keypair. A new keypair is generated and written
+	/// to it if the file does not exist. If not set, a fresh keypair is used
+	/// on every start.
+	pub key_file: Option<PathBuf>,
+	/// Addresses to listen on.
+	pub listen_addresses: Vec<Multiaddr>,
+	/// Bootnode addresses, each ending with `/p2p/<peer id>`.
+	pub bootnodes: Vec<Multiaddr>,
+	/// Whether to discover peers on the local network with mDNS.
+	pub mdns: bool,
+}
+
+impl NetworkConfig {
+	/// Default configuration listening on all interfaces at the given port.
+	pub fn new_with_port(port: &str) -> Result<Self, Error> {
+		let address = format!("/ip4/0.0.0.0/tcp/{}", port).parse()
+			.map_err(|_| Error::InvalidAddress)?;
+
+		Ok(Self {
+			key_file: None,
+			listen_addresses: vec![address],
+			bootnodes: Vec::new(),
+			mdns: true,
+		})
+	}
+}
+
+/// Network configuration error.
+#[derive(Debug)]
+pub enum Error {
+	/// Reading or writing the key file failed.
+	Io(io::Error),
+	/// Key file does not contain a valid keypair.
+	InvalidKey,
+	/// Key file is readable or writable by users other than its owner.
+	InsecureKeyFile,
+	/// Address is not a valid multiaddr.
+	InvalidAddress,
+	/// Bootnode address does not end with a peer id.
+	MissingPeerId,
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl std::error::Error for Error { }
+
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Error {
+		Error::Io(err)
+	}
+}
+
+/// Load an ed25519 keypair from the file, or generate one and write it to
+/// the file if it does not exist. On unix, the file is created readable only
+/// by its owner, and files with looser permissions are refused.
+pub fn load_or_generate_key(path: &Path) -> Result<identity::Keypair, Error> {
+	if path.exists() {
+		#[cfg(unix)] {
+			use std::os::unix::fs::PermissionsExt;
+			if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+				return Err(Error::InsecureKeyFile)
+			}
+		}
+
+		let mut bytes = fs::read(path)?;
+		let keypair = identity::ed25519::Keypair::decode(&mut bytes)
+			.map_err(|_| Error::InvalidKey)?;
+		Ok(identity::Keypair::Ed25519(keypair))
+	} else {
+		let keypair = identity::ed25519::Keypair::generate();
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut options = fs::OpenOptions::new();
+		options.write(true).create_new(true);
+		#[cfg(unix)] {
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+		options.open(path)?.write_all(&keypair.encode()[..])?;
+		Ok(identity::Keypair::Ed25519(keypair))
+	}
+}
+
+/// Split a bootnode address into its peer id and the address to dial.
+pub fn split_bootnode(address: &Multiaddr) -> Result<(PeerId, Multiaddr), Error> {
+	let mut peer_id = None;
+	let mut dial = Multiaddr::empty();
+
+	for protocol in address.iter() {
+		match protocol {
+			Protocol::P2p(hash) => {
+				peer_id = Some(PeerId::from_multihash(hash).map_err(|_| Error::MissingPeerId)?);
+			},
+			protocol => dial.append(protocol),
+		}
+	}
+
+	Ok((peer_id.ok_or(Error::MissingPeerId)?, dial))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_split_bootnode() {
+		let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+		let address = format!("/ip4/127.0.0.1/tcp/37365/p2p/{}", peer_id.to_base58())
+			.parse::<Multiaddr>().unwrap();
+
+		let (split_peer_id, dial) = split_bootnode(&address).unwrap();
+		assert_eq!(split_peer_id, peer_id);
+		assert_eq!(dial, "/ip4/127.0.0.1/tcp/37365".parse::<Multiaddr>().unwrap());
+
+		let address = "/ip4/127.0.0.1/tcp/37365".parse::<Multiaddr>().unwrap();
+		assert!(split_bootnode(&address).is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn should_keep_key_file_private() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let path = std::env::temp_dir()
+			.join(format!("solri-key-{}", std::process::id()));
+		let _ = fs::remove_file(&path);
+
+		let generated = load_or_generate_key(&path).unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		let loaded = load_or_generate_key(&path).unwrap();
+		assert_eq!(PeerId::from(loaded.public()), PeerId::from(generated.public()));
+
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		match load_or_generate_key(&path) {
+			Err(Error::InsecureKeyFile) => (),
+			_ => panic!("Key file with loose permissions is loaded"),
+		}
+
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,180 @@
+use std::{io, iter};
+use std::collections::{HashSet, VecDeque};
+use std::marker::PhantomData;
+use libp2p::{PeerId, Multiaddr};
+use libp2p::core::{ConnectedPoint, UpgradeInfo, InboundUpgrade, OutboundUpgrade};
+use libp2p::core::upgrade::{self, Negotiated};
+use libp2p::core::protocols_handler::OneShotHandler;
+use libp2p::core::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use futures::Async;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const PROTOCOL_NAME: &[u8] = b"/solri/direct/1.0.0";
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Upgrade for receiving a direct message on an inbound substream.
+#[derive(Clone, Default)]
+pub struct DirectConfig;
+
+impl UpgradeInfo for DirectConfig {
+	type Info = &'static [u8];
+	type InfoIter = iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		iter::once(PROTOCOL_NAME)
+	}
+}
+
+impl<TSocket: AsyncRead + AsyncWrite> InboundUpgrade<TSocket> for DirectConfig {
+	type Output = DirectMessage;
+	type Error = upgrade::ReadOneError;
+	type Future = upgrade::ReadOneThen<
+		Negotiated<TSocket>, (),
+		fn(Vec<u8>, ()) -> Result<DirectMessage, upgrade::ReadOneError>
+	>;
+
+	fn upgrade_inbound(self, socket: Negotiated<TSocket>, _: Self::Info) -> Self::Future {
+		upgrade::read_one_then(socket, MAX_MESSAGE_SIZE, (), |packet, ()| {
+			Ok(DirectMessage(packet))
+		})
+	}
+}
+
+/// A message sent to a single peer on its own substream.
+#[derive(Clone, Debug)]
+pub struct DirectMessage(pub Vec<u8>);
+
+impl UpgradeInfo for DirectMessage {
+	type Info = &'static [u8];
+	type InfoIter = iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		iter::once(PROTOCOL_NAME)
+	}
+}
+
+impl<TSocket: AsyncRead + AsyncWrite> OutboundUpgrade<TSocket> for DirectMessage {
+	type Output = ();
+	type Error = io::Error;
+	type Future = upgrade::WriteOne<Negotiated<TSocket>>;
+
+	fn upgrade_outbound(self, socket: Negotiated<TSocket>, _: Self::Info) -> Self::Future {
+		upgrade::write_one(socket, self.0)
+	}
+}
+
+/// Event produced by the direct message handler.
+pub enum DirectEvent {
+	/// A message is received.
+	Received(DirectMessage),
+	/// A message has been sent.
+	Sent,
+}
+
+impl From<DirectMessage> for DirectEvent {
+	fn from(message: DirectMessage) -> Self {
+		DirectEvent::Received(message)
+	}
+}
+
+impl From<()> for DirectEvent {
+	fn from(_: ()) -> Self {
+		DirectEvent::Sent
+	}
+}
+
+/// Event produced by the direct message behaviour.
+pub enum DirectOut {
+	/// A peer is connected.
+	Connected(PeerId),
+	/// A peer is disconnected.
+	Disconnected(PeerId),
+	/// A message is received from a peer.
+	Message(PeerId, Vec<u8>),
+}
+
+/// Network behaviour for sending messages to a single peer. It also reports
+/// connection and disconnection of peers.
+pub struct Direct<TSubstream> {
+	events: VecDeque<NetworkBehaviourAction<DirectMessage, DirectOut>>,
+	connected: HashSet<PeerId>,
+	_marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream> Direct<TSubstream> {
+	pub fn new() -> Self {
+		Self {
+			events: VecDeque::new(),
+			connected: HashSet::new(),
+			_marker: PhantomData,
+		}
+	}
+
+	/// Send a message to the given peer.
+	pub fn send(&mut self, peer_id: PeerId, data: Vec<u8>) {
+		self.events.push_back(NetworkBehaviourAction::SendEvent {
+			peer_id,
+			event: DirectMessage(data),
+		});
+	}
+
+	/// Send a message to all connected peers.
+	pub fn broadcast(&mut self, data: Vec<u8>) {
+		for peer_id in &self.connected {
+			self.events.push_back(NetworkBehaviourAction::SendEvent {
+				peer_id: peer_id.clone(),
+				event: DirectMessage(data.clone()),
+			});
+		}
+	}
+}
+
+impl<TSubstream> Default for Direct<TSubstream> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviour for Direct<TSubstream> {
+	type ProtocolsHandler = OneShotHandler<TSubstream, DirectConfig, DirectMessage, DirectEvent>;
+	type OutEvent = DirectOut;
+
+	fn new_handler(&mut self) -> Self::ProtocolsHandler {
+		Default::default()
+	}
+
+	fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+		Vec::new()
+	}
+
+	fn inject_connected(&mut self, peer_id: PeerId, _: ConnectedPoint) {
+		self.connected.insert(peer_id.clone());
+		self.events.push_back(NetworkBehaviourAction::GenerateEvent(DirectOut::Connected(peer_id)));
+	}
+
+	fn inject_disconnected(&mut self, peer_id: &PeerId, _: ConnectedPoint) {
+		self.connected.remove(peer_id);
+		self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+			DirectOut::Disconnected(peer_id.clone())
+		));
+	}
+
+	fn inject_node_event(&mut self, peer_id: PeerId, event: DirectEvent) {
+		if let DirectEvent::Received(message) = event {
+			self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+				DirectOut::Message(peer_id, message.0)
+			));
+		}
+	}
+
+	fn poll(
+		&mut self,
+		_: &mut PollParameters<'_>,
+	) -> Async<NetworkBehaviourAction<DirectMessage, DirectOut>> {
+		if let Some(event) = self.events.pop_front() {
+			return Async::Ready(event)
+		}
+
+		Async::NotReady
+	}
+}
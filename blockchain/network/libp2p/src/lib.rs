@@ -1,35 +1,58 @@
+mod direct;
+mod config;
+
+pub use crate::config::{NetworkConfig, Error, load_or_generate_key, split_bootnode};
+pub use libp2p::Multiaddr;
+
+use std::collections::HashSet;
+use std::sync::mpsc::Receiver;
+use std::thread;
 use core::fmt::Debug;
 use core::time::Duration;
 use core::ops::DerefMut;
 use parity_codec::{Encode, Decode};
 use libp2p::{identity, NetworkBehaviour, PeerId};
 use libp2p::mdns::Mdns;
-use libp2p::floodsub::{Floodsub, Topic, TopicBuilder};
+use libp2p::floodsub::{Floodsub, TopicBuilder};
 use libp2p::kad::Kademlia;
 use libp2p::core::swarm::{NetworkBehaviourEventProcess, NetworkBehaviourAction};
+use libp2p::core::swarm::toggle::Toggle;
 use futures::{Async, stream::Stream};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::Interval;
+use blockchain::Block;
 use blockchain::backend::{SharedCommittable, ChainQuery, ImportLock};
 use blockchain::import::BlockImporter;
+use blockchain::pool::{SharedPool, ExtrinsicValidator};
 use blockchain_network::{NetworkEnvironment, NetworkHandle, NetworkEvent};
-use blockchain_network::sync::{NetworkSyncMessage, NetworkSync, StatusProducer};
+use blockchain_network::sync::{NetworkSyncMessage, NetworkSync, StatusProducer, ImportError};
+use crate::direct::{Direct, DirectOut};
+
+enum BehaviourEvent<B, H, S, E> {
+	Connected(PeerId),
+	Disconnected(PeerId),
+	Message(PeerId, NetworkSyncMessage<B, H, S, E>),
+	Undecodable(PeerId),
+}
 
 #[derive(NetworkBehaviour)]
-#[behaviour(out_event = "(PeerId, NetworkSyncMessage<B, S>)", poll_method = "poll")]
-struct Behaviour<TSubstream: AsyncRead + AsyncWrite, B, S> {
+#[behaviour(out_event = "BehaviourEvent<B, H, S, E>", poll_method = "poll")]
+struct Behaviour<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> {
 	floodsub: Floodsub<TSubstream>,
+	direct: Direct<TSubstream>,
 	kademlia: Kademlia<TSubstream>,
-	mdns: Mdns<TSubstream>,
+	mdns: Toggle<Mdns<TSubstream>>,
 
 	#[behaviour(ignore)]
-	topic: Topic,
+	mdns_nodes: HashSet<(PeerId, Multiaddr)>,
+	#[behaviour(ignore)]
+	events: Vec<BehaviourEvent<B, H, S, E>>,
 	#[behaviour(ignore)]
-	events: Vec<(PeerId, NetworkSyncMessage<B, S>)>,
+	bans: Vec<(PeerId, bool)>,
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> Behaviour<TSubstream, B, S> {
-	fn poll<TEv>(&mut self) -> Async<NetworkBehaviourAction<TEv, (PeerId, NetworkSyncMessage<B, S>)>> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> Behaviour<TSubstream, B, H, S, E> {
+	fn poll<TEv>(&mut self) -> Async<NetworkBehaviourAction<TEv, BehaviourEvent<B, H, S, E>>> {
 		if !self.events.is_empty() {
 			return Async::Ready(NetworkBehaviourAction::GenerateEvent(self.events.remove(0)))
 		}
@@ -38,39 +61,67 @@ impl<TSubstream: AsyncRead + AsyncWrite, B, S> Behaviour<TSubstream, B, S> {
 	}
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkEnvironment for Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> NetworkEnvironment for Behaviour<TSubstream, B, H, S, E> {
 	type PeerId = PeerId;
-	type Message = NetworkSyncMessage<B, S>;
+	type Message = NetworkSyncMessage<B, H, S, E>;
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkHandle for Behaviour<TSubstream, B, S>  where
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> NetworkHandle for Behaviour<TSubstream, B, H, S, E>  where
 	B: Encode,
+	H: Encode,
 	S: Encode,
+	E: Encode,
 {
-	fn send(&mut self, _peer: &PeerId, message: NetworkSyncMessage<B, S>) {
-		self.floodsub.publish(&self.topic, message.encode());
+	fn send(&mut self, peer: &PeerId, message: NetworkSyncMessage<B, H, S, E>) {
+		self.direct.send(peer.clone(), message.encode());
+	}
+
+	fn broadcast(&mut self, message: NetworkSyncMessage<B, H, S, E>) {
+		self.direct.broadcast(message.encode());
+	}
+
+	fn ban_peer(&mut self, peer: &PeerId) {
+		self.bans.push((peer.clone(), true));
 	}
 
-	fn broadcast(&mut self, message: NetworkSyncMessage<B, S>) {
-		self.floodsub.publish(&self.topic, message.encode());
+	fn unban_peer(&mut self, peer: &PeerId) {
+		self.bans.push((peer.clone(), false));
 	}
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp2p::floodsub::FloodsubEvent> for Behaviour<TSubstream, B, S> where
+/// Floodsub only keeps discovered peers connected. Its messages carry the
+/// original publisher as source, which is unchecked and not the peer that
+/// relayed them, so they are ignored, and messages are broadcast on direct
+/// substreams instead.
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> NetworkBehaviourEventProcess<libp2p::floodsub::FloodsubEvent> for Behaviour<TSubstream, B, H, S, E> {
+	fn inject_event(&mut self, _event: libp2p::floodsub::FloodsubEvent) { }
+}
+
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> NetworkBehaviourEventProcess<DirectOut> for Behaviour<TSubstream, B, H, S, E> where
 	B: Encode + Decode + Debug,
+	H: Encode + Decode + Debug,
 	S: Encode + Decode + Debug,
+	E: Encode + Decode + Debug,
 {
-	fn inject_event(&mut self, floodsub_message: libp2p::floodsub::FloodsubEvent) {
-		if let libp2p::floodsub::FloodsubEvent::Message(floodsub_message) = floodsub_message {
-			let message = NetworkSyncMessage::<B, S>::decode(&mut &floodsub_message.data[..]).unwrap();
-
-			self.events.push((floodsub_message.source.clone(), message));
+	fn inject_event(&mut self, event: DirectOut) {
+		match event {
+			DirectOut::Connected(peer_id) => {
+				self.events.push(BehaviourEvent::Connected(peer_id));
+			},
+			DirectOut::Disconnected(peer_id) => {
+				self.events.push(BehaviourEvent::Disconnected(peer_id));
+			},
+			DirectOut::Message(peer_id, data) => {
+				match NetworkSyncMessage::<B, H, S, E>::decode(&mut &data[..]) {
+					Some(message) => self.events.push(BehaviourEvent::Message(peer_id, message)),
+					None => self.events.push(BehaviourEvent::Undecodable(peer_id)),
+				}
+			},
 		}
 	}
 }
 
-
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp2p::kad::KademliaOut> for Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> NetworkBehaviourEventProcess<libp2p::kad::KademliaOut> for Behaviour<TSubstream, B, H, S, E> {
 	fn inject_event(&mut self, message: libp2p::kad::KademliaOut) {
 		if let libp2p::kad::KademliaOut::Discovered { peer_id, .. } = message {
 			println!("Discovered via Kademlia {:?}", peer_id);
@@ -79,17 +130,19 @@ impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp
 	}
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp2p::mdns::MdnsEvent> for Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, H, S, E> NetworkBehaviourEventProcess<libp2p::mdns::MdnsEvent> for Behaviour<TSubstream, B, H, S, E> {
 	fn inject_event(&mut self, event: libp2p::mdns::MdnsEvent) {
 		match event {
 			libp2p::mdns::MdnsEvent::Discovered(list) => {
-				for (peer, _) in list {
+				for (peer, address) in list {
+					self.mdns_nodes.insert((peer.clone(), address));
 					self.floodsub.add_node_to_partial_view(peer);
 				}
 			},
 			libp2p::mdns::MdnsEvent::Expired(list) => {
-				for (peer, _) in list {
-					if !self.mdns.has_node(&peer) {
+				for (peer, address) in list {
+					self.mdns_nodes.remove(&(peer.clone(), address));
+					if !self.mdns_nodes.iter().any(|(other, _)| other == &peer) {
 						self.floodsub.remove_node_from_partial_view(&peer);
 					}
 				}
@@ -98,48 +151,84 @@ impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp
 	}
 }
 
-pub fn start_network_simple_sync<Ba, I, St>(
-	port: &str,
+pub fn start_network_simple_sync<Ba, I, St, V>(
+	config: NetworkConfig,
 	backend: Ba,
 	import_lock: ImportLock,
 	importer: I,
 	status: St,
+	pool: SharedPool<V>,
+	authored: Receiver<Ba::Block>,
 ) where
 	Ba: SharedCommittable + ChainQuery + Send + Sync + 'static,
 	Ba::Block: Debug + Encode + Decode + Send + Sync,
+	<Ba::Block as Block>::Identifier: Debug + Encode + Decode + Send + Sync,
 	I: BlockImporter<Block=Ba::Block> + Send + Sync + 'static,
+	I::Error: ImportError,
 	St: StatusProducer + Send + Sync + 'static,
 	St::Status: Debug + Clone + Send + Sync,
+	V: ExtrinsicValidator + Send + 'static,
+	V::Extrinsic: Debug + Encode + Decode + Send + Sync,
+	V::Hash: Send,
+	V::Sender: Send,
 {
-	// Create a random PeerId
-	let local_key = identity::Keypair::generate_ed25519();
+	let local_key = match config.key_file {
+		Some(ref key_file) => load_or_generate_key(key_file).expect("Failed to load node key"),
+		None => identity::Keypair::generate_ed25519(),
+	};
 	let local_peer_id = PeerId::from(local_key.public());
 	println!("Local peer id: {:?}", local_peer_id);
 
 	let transport = libp2p::build_tcp_ws_secio_mplex_yamux(local_key);
 	let topic = TopicBuilder::new("blocks").build();
 
-	let mut sync = NetworkSync::new(backend, import_lock, importer, status);
+	let mut sync = NetworkSync::new(backend, import_lock, importer, status, pool);
 
 	let mut swarm = {
 		let mut behaviour = Behaviour {
 			floodsub: Floodsub::new(local_peer_id.clone()),
+			direct: Direct::new(),
 			kademlia: Kademlia::new(local_peer_id.clone()),
-			mdns: libp2p::mdns::Mdns::new().expect("Failed to create mDNS service"),
+			mdns: Toggle::from(if config.mdns {
+				Some(Mdns::new().expect("Failed to create mDNS service"))
+			} else {
+				None
+			}),
 
-			topic: topic.clone(),
+			mdns_nodes: HashSet::new(),
 			events: Vec::new(),
+			bans: Vec::new(),
 		};
 
-		assert!(behaviour.floodsub.subscribe(topic.clone()));
+		assert!(behaviour.floodsub.subscribe(topic));
 		libp2p::Swarm::new(transport, behaviour, local_peer_id)
 	};
 
-	// Listen on all interfaces and whatever port the OS assigns
-	let addr = libp2p::Swarm::listen_on(&mut swarm, format!("/ip4/0.0.0.0/tcp/{}", port).parse().unwrap()).unwrap();
-	println!("Listening on {:?}", addr);
+	for address in config.listen_addresses {
+		let addr = libp2p::Swarm::listen_on(&mut swarm, address).expect("Failed to listen");
+		println!("Listening on {:?}", addr);
+	}
+
+	for bootnode in &config.bootnodes {
+		let (peer_id, address) = split_bootnode(bootnode).expect("Invalid bootnode address");
+		swarm.kademlia.add_not_connected_address(&peer_id, address.clone());
+		swarm.floodsub.add_node_to_partial_view(peer_id);
+		if let Err(err) = libp2p::Swarm::dial_addr(&mut swarm, address) {
+			println!("Failed to dial bootnode {:?}: {:?}", bootnode, err);
+		}
+	}
 
 	let mut interval = Interval::new_interval(Duration::new(5, 0));
+	// Forward authored blocks to a futures channel, so that they wake up the
+	// network task.
+	let (authored_sender, mut authored_stream) = futures::sync::mpsc::unbounded();
+	thread::spawn(move || {
+		for block in authored.iter() {
+			if authored_sender.unbounded_send(block).is_err() {
+				break
+			}
+		}
+	});
 	let mut listening = false;
 	tokio::run(futures::future::poll_fn(move || -> Result<_, ()> {
 		loop {
@@ -152,12 +241,33 @@ pub fn start_network_simple_sync<Ba, I, St>(
 			};
 		}
 
+		loop {
+			match authored_stream.poll().expect("Error while polling authored blocks") {
+				Async::Ready(Some(block)) => {
+					sync.on_block_authored(swarm.deref_mut(), block);
+				},
+				Async::Ready(None) | Async::NotReady => break,
+			}
+		}
+
 		loop {
 			match swarm.poll().expect("Error while polling swarm") {
-				Async::Ready(Some((peer_id, message))) => {
+				Async::Ready(Some(BehaviourEvent::Connected(peer_id))) => {
+					println!("Connected to {:?}", peer_id);
+					sync.on_peer_connected(swarm.deref_mut(), &peer_id);
+				},
+				Async::Ready(Some(BehaviourEvent::Disconnected(peer_id))) => {
+					println!("Disconnected from {:?}", peer_id);
+					sync.on_peer_disconnected(swarm.deref_mut(), &peer_id);
+				},
+				Async::Ready(Some(BehaviourEvent::Message(peer_id, message))) => {
 					println!("Received: {:?} from {:?}", message, peer_id);
 					sync.on_message(swarm.deref_mut(), &peer_id, message);
 				},
+				Async::Ready(Some(BehaviourEvent::Undecodable(peer_id))) => {
+					println!("Received undecodable message from {:?}", peer_id);
+					sync.on_undecodable_message(swarm.deref_mut(), &peer_id);
+				},
 				Async::Ready(None) | Async::NotReady => {
 					if !listening {
 						if let Some(a) = libp2p::Swarm::listeners(&swarm).next() {
@@ -170,6 +280,15 @@ pub fn start_network_simple_sync<Ba, I, St>(
 			}
 		}
 
+		let bans = swarm.bans.drain(..).collect::<Vec<_>>();
+		for (peer_id, ban) in bans {
+			if ban {
+				libp2p::Swarm::ban_peer_id(&mut swarm, peer_id);
+			} else {
+				libp2p::Swarm::unban_peer_id(&mut swarm, peer_id);
+			}
+		}
+
 		Ok(Async::NotReady)
 	}));
 }
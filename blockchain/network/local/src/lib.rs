@@ -1,111 +1,180 @@
-use std::collections::HashMap;
+mod sim;
+
+pub use crate::sim::{Simulator, SimulatorConfig};
+
+use std::collections::{HashMap, HashSet};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use std::sync::{Arc, mpsc::{SyncSender, Receiver, sync_channel}};
+use std::sync::{Arc, mpsc::{Sender, Receiver, channel}};
 use core::hash::Hash;
 use core::fmt::Debug;
+use parity_codec::Encode;
+use blockchain::Block;
 use blockchain::backend::{SharedCommittable, ChainQuery, ImportLock};
 use blockchain::import::BlockImporter;
+use blockchain::pool::{SharedPool, ExtrinsicValidator};
 use blockchain_network::{NetworkEnvironment, NetworkHandle, NetworkEvent};
-use blockchain_network::sync::{NetworkSync, NetworkSyncMessage, StatusProducer};
+use blockchain_network::sync::{NetworkSync, NetworkSyncMessage, StatusProducer, ImportError};
+
+const POLL_INTERVAL_MILLIS: u64 = 100;
+const POLLS_PER_TICK: usize = 10;
 
-pub struct LocalNetwork<P, B, S> {
-	senders: HashMap<P, SyncSender<(P, NetworkSyncMessage<B, S>)>>,
+pub struct LocalNetwork<P, B, H, S, E> {
+	/// Unbounded, because every peer sends from the same thread that drains
+	/// its own queue. With bounded queues, two peers sending to each other
+	/// while both queues are full would block forever, and so would the
+	/// single-threaded simulator on any full queue. Queues stay short in
+	/// practice, as each peer drains its queue on every poll.
+	senders: HashMap<P, Sender<(P, NetworkSyncMessage<B, H, S, E>)>>,
 }
 
-impl<P: Eq + Hash + Clone, B: Clone, S: Clone> LocalNetwork<P, B, S> {
-	pub fn send(&self, peer: &P, message: (P, NetworkSyncMessage<B, S>)) {
+impl<P: Eq + Hash + Clone, B: Clone, H: Clone, S: Clone, E: Clone> LocalNetwork<P, B, H, S, E> {
+	pub fn peers(&self) -> Vec<P> {
+		self.senders.keys().cloned().collect()
+	}
+
+	pub fn send(&self, peer: &P, message: (P, NetworkSyncMessage<B, H, S, E>)) {
 		self.senders.get(peer).unwrap()
 			.send(message).unwrap();
 	}
 
-	pub fn broadcast(&self, message: (P, NetworkSyncMessage<B, S>)) {
-		for sender in self.senders.values() {
-			sender.send(message.clone()).unwrap();
+	pub fn broadcast(&self, message: (P, NetworkSyncMessage<B, H, S, E>)) {
+		for (peer, sender) in &self.senders {
+			if peer != &message.0 {
+				sender.send(message.clone()).unwrap();
+			}
 		}
 	}
 }
 
 #[derive(Clone)]
-pub struct LocalNetworkHandle<P, B, S> {
+pub struct LocalNetworkHandle<P, B, H, S, E> {
 	peer_id: P,
-	network: Arc<LocalNetwork<P, B, S>>
+	network: Arc<LocalNetwork<P, B, H, S, E>>,
+	/// Peers whose messages are dropped on receipt.
+	banned: HashSet<P>,
+}
+
+impl<P: Eq + Hash, B, H, S, E> LocalNetworkHandle<P, B, H, S, E> {
+	fn is_banned(&self, peer: &P) -> bool {
+		self.banned.contains(peer)
+	}
 }
 
-impl<P, B, S> NetworkEnvironment for LocalNetworkHandle<P, B, S> {
+impl<P, B, H, S, E> NetworkEnvironment for LocalNetworkHandle<P, B, H, S, E> {
 	type PeerId = P;
-	type Message = NetworkSyncMessage<B, S>;
+	type Message = NetworkSyncMessage<B, H, S, E>;
 }
 
-impl<P: Eq + Hash + Clone, B: Clone, S: Clone> NetworkHandle for LocalNetworkHandle<P, B, S> {
-	fn send(&mut self, peer: &P, message: NetworkSyncMessage<B, S>) {
+impl<P: Eq + Hash + Clone, B: Clone, H: Clone, S: Clone, E: Clone> NetworkHandle for LocalNetworkHandle<P, B, H, S, E> {
+	fn send(&mut self, peer: &P, message: NetworkSyncMessage<B, H, S, E>) {
 		self.network.send(peer, (self.peer_id.clone(), message));
 	}
 
-	fn broadcast(&mut self, message: NetworkSyncMessage<B, S>) {
+	fn broadcast(&mut self, message: NetworkSyncMessage<B, H, S, E>) {
 		self.network.broadcast((self.peer_id.clone(), message));
 	}
+
+	fn ban_peer(&mut self, peer: &P) {
+		self.banned.insert(peer.clone());
+	}
+
+	fn unban_peer(&mut self, peer: &P) {
+		self.banned.remove(peer);
+	}
 }
 
-pub fn start_local_simple_peer<P, Ba, I, St>(
-	mut handle: LocalNetworkHandle<P, Ba::Block, St::Status>,
-	receiver: Receiver<(P, NetworkSyncMessage<Ba::Block, St::Status>)>,
+pub fn start_local_simple_peer<P, Ba, I, St, V>(
+	mut handle: LocalNetworkHandle<P, Ba::Block, <Ba::Block as Block>::Identifier, St::Status, V::Extrinsic>,
+	receiver: Receiver<(P, NetworkSyncMessage<Ba::Block, <Ba::Block as Block>::Identifier, St::Status, V::Extrinsic>)>,
 	peer_id: P,
 	backend: Ba,
 	import_lock: ImportLock,
 	importer: I,
 	status: St,
+	pool: SharedPool<V>,
+	authored: Receiver<Ba::Block>,
 ) -> JoinHandle<()> where
 	P: Debug + Eq + Hash + Clone + Send + Sync + 'static,
 	Ba: SharedCommittable + ChainQuery + Send + Sync + 'static,
-	Ba::Block: Debug + Send + Sync,
+	Ba::Block: Debug + Encode + Send + Sync,
+	<Ba::Block as Block>::Identifier: Debug + Send + Sync,
 	I: BlockImporter<Block=Ba::Block> + Send + Sync + 'static,
+	I::Error: ImportError,
 	St: StatusProducer + Send + Sync + 'static,
 	St::Status: Clone + Debug + Send + Sync,
+	V: ExtrinsicValidator + Send + 'static,
+	V::Extrinsic: Debug + Send + Sync,
+	V::Hash: Send,
+	V::Sender: Send,
 {
 	thread::spawn(move || {
 		let this_peer_id = peer_id.clone();
 
-		let mut sync = NetworkSync::new(backend, import_lock, importer, status);
+		let mut sync = NetworkSync::new(backend, import_lock, importer, status, pool);
 
+		for other in handle.network.peers() {
+			if other != this_peer_id {
+				sync.on_peer_connected(&mut handle, &other);
+			}
+		}
+
+		let mut polls = 0;
 		loop {
 			for (peer_id, message) in receiver.try_iter() {
+				if handle.is_banned(&peer_id) {
+					continue
+				}
 				println!("peer[{:?}] on message {:?}", this_peer_id, message);
 				sync.on_message(&mut handle, &peer_id, message);
 			}
+			for block in authored.try_iter() {
+				sync.on_block_authored(&mut handle, block);
+			}
 
-			thread::sleep(Duration::from_millis(1000));
-			println!("peer[{:?}] on tick", this_peer_id);
-			sync.on_tick(&mut handle);
+			thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+			polls += 1;
+			if polls >= POLLS_PER_TICK {
+				polls = 0;
+				println!("peer[{:?}] on tick", this_peer_id);
+				sync.on_tick(&mut handle);
+			}
 		}
 	})
 }
 
-pub fn start_local_simple_sync<P, Ba, I, St>(
-	peers: HashMap<P, (Ba, ImportLock, I, St)>
+pub fn start_local_simple_sync<P, Ba, I, St, V>(
+	peers: HashMap<P, (Ba, ImportLock, I, St, SharedPool<V>, Receiver<Ba::Block>)>
 ) where
 	P: Debug + Eq + Hash + Clone + Send + Sync + 'static,
 	Ba: SharedCommittable + ChainQuery + Send + Sync + 'static,
-	Ba::Block: Debug + Send + Sync,
+	Ba::Block: Debug + Encode + Send + Sync,
+	<Ba::Block as Block>::Identifier: Debug + Send + Sync,
 	I: BlockImporter<Block=Ba::Block> + Send + Sync + 'static,
+	I::Error: ImportError,
 	St: StatusProducer + Send + Sync + 'static,
 	St::Status: Clone + Debug + Send + Sync,
+	V: ExtrinsicValidator + Send + 'static,
+	V::Extrinsic: Debug + Send + Sync,
+	V::Hash: Send,
+	V::Sender: Send,
 {
-	let mut senders: HashMap<P, SyncSender<(P, NetworkSyncMessage<Ba::Block, St::Status>)>> = HashMap::new();
-	let mut peers_with_receivers: HashMap<P, (Ba, ImportLock, I, St, Receiver<(P, NetworkSyncMessage<Ba::Block, St::Status>)>)> = HashMap::new();
-	for (peer_id, (backend, import_lock, importer, status)) in peers {
-		let (sender, receiver) = sync_channel(10);
+	let mut senders: HashMap<P, Sender<(P, NetworkSyncMessage<Ba::Block, <Ba::Block as Block>::Identifier, St::Status, V::Extrinsic>)>> = HashMap::new();
+	let mut peers_with_receivers: HashMap<P, (Ba, ImportLock, I, St, SharedPool<V>, Receiver<Ba::Block>, Receiver<(P, NetworkSyncMessage<Ba::Block, <Ba::Block as Block>::Identifier, St::Status, V::Extrinsic>)>)> = HashMap::new();
+	for (peer_id, (backend, import_lock, importer, status, pool, authored)) in peers {
+		let (sender, receiver) = channel();
 		senders.insert(peer_id.clone(), sender);
-		peers_with_receivers.insert(peer_id, (backend, import_lock, importer, status, receiver));
+		peers_with_receivers.insert(peer_id, (backend, import_lock, importer, status, pool, authored, receiver));
 	}
 
 	let mut join_handles: Vec<JoinHandle<()>> = Vec::new();
 	let network = Arc::new(LocalNetwork { senders });
-	for (peer_id, (backend, import_lock, importer, status, receiver)) in peers_with_receivers {
+	for (peer_id, (backend, import_lock, importer, status, pool, authored, receiver)) in peers_with_receivers {
 		let join_handle = start_local_simple_peer(
 			LocalNetworkHandle {
 				peer_id: peer_id.clone(),
 				network: network.clone(),
+				banned: HashSet::new(),
 			},
 			receiver,
 			peer_id,
@@ -113,6 +182,8 @@ pub fn start_local_simple_sync<P, Ba, I, St>(
 			import_lock,
 			importer,
 			status,
+			pool,
+			authored,
 		);
 		join_handles.push(join_handle);
 	}
@@ -0,0 +1,454 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, mpsc::{Receiver, channel}};
+use core::cmp;
+use core::hash::Hash;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use blockchain_network::NetworkEvent;
+use blockchain_network::sync::NetworkSyncMessage;
+use crate::{LocalNetwork, LocalNetworkHandle};
+
+/// Configuration of the simulated network. Durations are in virtual
+/// milliseconds.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+	/// Seed of the random number generator.
+	pub seed: u64,
+	/// Minimum latency of a message.
+	pub min_latency: u64,
+	/// Maximum latency of a message.
+	pub max_latency: u64,
+	/// Probability that a message is dropped.
+	pub drop_rate: f64,
+	/// Whether messages between two peers can arrive out of order.
+	pub reorder: bool,
+	/// Interval between ticks of each peer.
+	pub tick_interval: u64,
+}
+
+impl Default for SimulatorConfig {
+	fn default() -> Self {
+		Self {
+			seed: 0,
+			min_latency: 10,
+			max_latency: 50,
+			drop_rate: 0.0,
+			reorder: true,
+			tick_interval: 1000,
+		}
+	}
+}
+
+struct SimulatedPeer<P, B, H, S, E, N> {
+	engine: N,
+	handle: LocalNetworkHandle<P, B, H, S, E>,
+	receiver: Receiver<(P, NetworkSyncMessage<B, H, S, E>)>,
+}
+
+/// Deterministic single-threaded network simulator, driven by a virtual
+/// clock.
+pub struct Simulator<P, B, H, S, E, N> {
+	config: SimulatorConfig,
+	rng: StdRng,
+	now: u64,
+	next_tick: u64,
+	next_seq: u64,
+	peers: BTreeMap<P, SimulatedPeer<P, B, H, S, E, N>>,
+	in_flight: BTreeMap<(u64, u64), (P, P, NetworkSyncMessage<B, H, S, E>)>,
+	last_arrivals: BTreeMap<(P, P), u64>,
+	groups: Option<BTreeMap<P, usize>>,
+}
+
+impl<P, B, H, S, E, N> Simulator<P, B, H, S, E, N> where
+	P: Ord + Eq + Hash + Clone,
+	B: Clone,
+	H: Clone,
+	S: Clone,
+	E: Clone,
+	N: NetworkEvent<PeerId=P, Message=NetworkSyncMessage<B, H, S, E>>,
+{
+	/// Create a new simulator with the given peers.
+	pub fn new(config: SimulatorConfig, engines: BTreeMap<P, N>) -> Self {
+		let mut senders = HashMap::new();
+		let mut receivers = BTreeMap::new();
+		for peer_id in engines.keys() {
+			let (sender, receiver) = channel();
+			senders.insert(peer_id.clone(), sender);
+			receivers.insert(peer_id.clone(), receiver);
+		}
+
+		let network = Arc::new(LocalNetwork { senders });
+		let peers = engines.into_iter().map(|(peer_id, engine)| {
+			let receiver = receivers.remove(&peer_id)
+				.expect("Receiver is created for each peer; qed");
+			let handle = LocalNetworkHandle {
+				peer_id: peer_id.clone(),
+				network: network.clone(),
+				banned: HashSet::new(),
+			};
+			(peer_id, SimulatedPeer { engine, handle, receiver })
+		}).collect();
+
+		let mut sim = Self {
+			rng: StdRng::seed_from_u64(config.seed),
+			now: 0,
+			next_tick: config.tick_interval,
+			next_seq: 0,
+			peers,
+			in_flight: BTreeMap::new(),
+			last_arrivals: BTreeMap::new(),
+			groups: None,
+			config,
+		};
+		sim.notify_connectivity(None);
+		sim
+	}
+
+	/// Current virtual time.
+	pub fn now(&self) -> u64 {
+		self.now
+	}
+
+	/// Number of messages in flight.
+	pub fn in_flight(&self) -> usize {
+		self.in_flight.len()
+	}
+
+	/// Get the engine of a peer.
+	pub fn engine(&self, peer: &P) -> Option<&N> {
+		self.peers.get(peer).map(|peer| &peer.engine)
+	}
+
+	/// Partition the network into groups. Messages between groups are
+	/// dropped. Peers not in any group form one more group.
+	pub fn partition(&mut self, groups: Vec<Vec<P>>) {
+		let mut map = BTreeMap::new();
+		for (index, group) in groups.into_iter().enumerate() {
+			for peer in group {
+				map.insert(peer, index);
+			}
+		}
+		let old = self.groups.replace(map);
+		self.notify_connectivity(Some(old));
+	}
+
+	/// Heal all network partitions.
+	pub fn heal(&mut self) {
+		let old = self.groups.take();
+		self.notify_connectivity(Some(old));
+	}
+
+	/// Whether two peers can currently reach each other.
+	pub fn is_connected(&self, a: &P, b: &P) -> bool {
+		Self::is_connected_in(&self.groups, a, b)
+	}
+
+	fn is_connected_in(groups: &Option<BTreeMap<P, usize>>, a: &P, b: &P) -> bool {
+		match groups {
+			Some(groups) => groups.get(a) == groups.get(b),
+			None => true,
+		}
+	}
+
+	/// Fire connect and disconnect callbacks for peer pairs whose
+	/// connectivity changed from the old groups. If there are no old groups,
+	/// all connected pairs are treated as newly connected.
+	fn notify_connectivity(&mut self, old: Option<Option<BTreeMap<P, usize>>>) {
+		let peer_ids = self.peers.keys().cloned().collect::<Vec<_>>();
+		for (peer_id, peer) in self.peers.iter_mut() {
+			for other in &peer_ids {
+				if other == peer_id {
+					continue
+				}
+
+				let was_connected = old.as_ref()
+					.map(|old| Self::is_connected_in(old, peer_id, other))
+					.unwrap_or(false);
+				let is_connected = Self::is_connected_in(&self.groups, peer_id, other);
+
+				if is_connected && !was_connected {
+					peer.engine.on_peer_connected(&mut peer.handle, other);
+				} else if !is_connected && was_connected {
+					peer.engine.on_peer_disconnected(&mut peer.handle, other);
+				}
+			}
+		}
+
+		self.flush();
+	}
+
+	/// Deliver a message immediately, regardless of latency and partitions.
+	/// Used to inject messages that well-behaved peers would not send.
+	pub fn deliver(&mut self, from: &P, to: &P, message: NetworkSyncMessage<B, H, S, E>) {
+		if let Some(peer) = self.peers.get_mut(to) {
+			if !peer.handle.is_banned(from) {
+				peer.engine.on_message(&mut peer.handle, from, message);
+			}
+		}
+
+		self.flush();
+	}
+
+	/// Process the next event, advancing the virtual clock to it.
+	pub fn step(&mut self) {
+		let next_timer = self.next_tick;
+		let next_message = self.in_flight.keys().next().cloned();
+
+		match next_message {
+			Some(key) if key.0 <= next_timer => {
+				let (from, to, message) = self.in_flight.remove(&key)
+					.expect("Key is taken from the map; qed");
+				self.now = key.0;
+
+				if self.is_connected(&from, &to) {
+					if let Some(peer) = self.peers.get_mut(&to) {
+						if !peer.handle.is_banned(&from) {
+							peer.engine.on_message(&mut peer.handle, &from, message);
+						}
+					}
+				}
+			},
+			_ => {
+				self.now = next_timer;
+
+				for peer in self.peers.values_mut() {
+					peer.engine.on_tick(&mut peer.handle);
+				}
+				self.next_tick += self.config.tick_interval;
+			},
+		}
+
+		self.flush();
+	}
+
+	/// Run until the condition holds or the deadline passes. Returns whether
+	/// the condition holds.
+	pub fn run_until<F: FnMut(&Self) -> bool>(&mut self, deadline: u64, mut condition: F) -> bool {
+		while self.now < deadline {
+			if condition(self) {
+				return true
+			}
+			self.step();
+		}
+
+		condition(self)
+	}
+
+	/// Run for the given duration.
+	pub fn run_for(&mut self, duration: u64) {
+		let deadline = self.now + duration;
+		self.run_until(deadline, |_| false);
+	}
+
+	fn flush(&mut self) {
+		let mut outgoing = Vec::new();
+		for (to, peer) in &self.peers {
+			for (from, message) in peer.receiver.try_iter() {
+				outgoing.push((from, to.clone(), message));
+			}
+		}
+
+		for (from, to, message) in outgoing {
+			self.schedule(from, to, message);
+		}
+	}
+
+	fn schedule(&mut self, from: P, to: P, message: NetworkSyncMessage<B, H, S, E>) {
+		if self.config.drop_rate > 0.0 && self.rng.gen_bool(self.config.drop_rate) {
+			return
+		}
+
+		let latency = if self.config.max_latency > self.config.min_latency {
+			self.rng.gen_range(self.config.min_latency, self.config.max_latency + 1)
+		} else {
+			self.config.min_latency
+		};
+		let mut arrival = self.now + latency;
+
+		if !self.config.reorder {
+			let last = self.last_arrivals.entry((from.clone(), to.clone())).or_insert(0);
+			arrival = cmp::max(arrival, *last);
+			*last = arrival;
+		}
+
+		self.in_flight.insert((arrival, self.next_seq), (from, to, message));
+		self.next_seq += 1;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use blockchain::backend::{SharedMemoryBackend, MemoryError, ChainQuery, ImportLock};
+	use blockchain::import::BlockImporter;
+	use blockchain::pool::{SharedPool, ExtrinsicValidator, Validity};
+	use blockchain_network::sync::{
+		NetworkSync, BestDepthImporter, BestDepthStatus, BestDepthStatusProducer,
+	};
+	use blockchain_network::test_support::{TestBlock, TestExecutor};
+
+	struct TestValidator;
+
+	impl ExtrinsicValidator for TestValidator {
+		type Extrinsic = ();
+		type Hash = ();
+		type Sender = ();
+		type Error = MemoryError;
+
+		fn hash(&self, _extrinsic: &()) { }
+
+		fn validate(&self, _extrinsic: &()) -> Result<Validity<(), ()>, MemoryError> {
+			Err(MemoryError::InvalidOperation)
+		}
+	}
+
+	type TestBackend = SharedMemoryBackend<TestBlock, (), ()>;
+	type TestSync = NetworkSync<
+		usize,
+		TestBackend,
+		BestDepthImporter<TestExecutor, TestBackend>,
+		BestDepthStatusProducer<TestBackend>,
+		TestValidator,
+	>;
+
+	/// Extend the chain of the backend from its head to the given depth.
+	fn extend(backend: &TestBackend, depth: u64) {
+		let mut importer = BestDepthImporter::new(TestExecutor, backend.clone(), ImportLock::new());
+		for id in (backend.head() + 1)..=depth {
+			importer.import_block(TestBlock::child(id, id - 1)).unwrap();
+		}
+	}
+
+	fn engines(backends: &[TestBackend]) -> BTreeMap<usize, TestSync> {
+		backends.iter().enumerate().map(|(peer_id, backend)| {
+			let lock = ImportLock::new();
+			(peer_id, NetworkSync::new(
+				backend.clone(),
+				lock.clone(),
+				BestDepthImporter::new(TestExecutor, backend.clone(), lock),
+				BestDepthStatusProducer::new(backend.clone()),
+				SharedPool::new(TestValidator, 0, 0),
+			))
+		}).collect()
+	}
+
+	#[test]
+	fn should_converge_after_partition_heals() {
+		let genesis = TestBlock::genesis(0);
+		let backends = (0..3)
+			.map(|_| TestBackend::new_with_genesis(genesis.clone(), ()))
+			.collect::<Vec<_>>();
+		extend(&backends[0], 20);
+
+		let mut sim = Simulator::new(SimulatorConfig {
+			seed: 42,
+			drop_rate: 0.1,
+			..Default::default()
+		}, engines(&backends));
+		sim.partition(vec![vec![0, 1], vec![2]]);
+
+		let head = backends[0].head();
+		assert!(sim.run_until(60_000, |_| backends[1].head() == head));
+		assert_eq!(backends[2].head(), genesis.id);
+
+		sim.heal();
+		let deadline = sim.now() + 60_000;
+		assert!(sim.run_until(deadline, |_| backends.iter().all(|backend| backend.head() == head)));
+	}
+
+	#[test]
+	fn should_reject_mismatched_genesis() {
+		let backends = vec![
+			TestBackend::new_with_genesis(TestBlock::genesis(0), ()),
+			TestBackend::new_with_genesis(TestBlock::genesis(1000), ()),
+		];
+		extend(&backends[0], 20);
+
+		let mut sim = Simulator::new(SimulatorConfig::default(), engines(&backends));
+		sim.run_for(30_000);
+		assert_eq!(backends[0].head(), 20);
+		assert_eq!(backends[1].head(), 1000);
+	}
+
+	#[test]
+	fn should_reject_mismatched_version_until_reconnected() {
+		let genesis = TestBlock::genesis(0);
+		let backends = (0..2)
+			.map(|_| TestBackend::new_with_genesis(genesis.clone(), ()))
+			.collect::<Vec<_>>();
+		extend(&backends[0], 20);
+
+		let mut sim = Simulator::new(SimulatorConfig::default(), engines(&backends));
+		sim.deliver(&1, &0, NetworkSyncMessage::Handshake {
+			genesis: 0,
+			version: u32::max_value(),
+			status: BestDepthStatus { best_depth: 0 },
+		});
+		sim.run_for(30_000);
+		assert_eq!(backends[1].head(), 0);
+
+		// Peers are handshaked afresh after reconnecting.
+		sim.partition(vec![vec![0], vec![1]]);
+		sim.heal();
+		let deadline = sim.now() + 30_000;
+		assert!(sim.run_until(deadline, |_| backends[1].head() == 20));
+	}
+
+	#[test]
+	fn should_ignore_messages_before_handshake() {
+		let genesis = TestBlock::genesis(0);
+		let backends = (0..2)
+			.map(|_| TestBackend::new_with_genesis(genesis.clone(), ()))
+			.collect::<Vec<_>>();
+
+		let mut sim = Simulator::new(SimulatorConfig::default(), engines(&backends));
+		sim.deliver(&1, &0, NetworkSyncMessage::NewBlock {
+			block: TestBlock::child(1, 0),
+		});
+		assert_eq!(backends[0].head(), 0);
+
+		// The same message is accepted once the handshake went through.
+		sim.run_for(1_000);
+		sim.deliver(&1, &0, NetworkSyncMessage::NewBlock {
+			block: TestBlock::child(1, 0),
+		});
+		assert_eq!(backends[0].head(), 1);
+	}
+
+	#[test]
+	fn should_relay_new_blocks_on_import() {
+		let genesis = TestBlock::genesis(0);
+		let backends = (0..3)
+			.map(|_| TestBackend::new_with_genesis(genesis.clone(), ()))
+			.collect::<Vec<_>>();
+
+		// Ticks are too rare to sync the block through status exchange, so
+		// it only spreads through announcements.
+		let mut sim = Simulator::new(SimulatorConfig {
+			tick_interval: 1_000_000,
+			..Default::default()
+		}, engines(&backends));
+		sim.run_for(1_000);
+		sim.deliver(&1, &0, NetworkSyncMessage::NewBlock {
+			block: TestBlock::child(1, 0),
+		});
+		assert_eq!(backends[0].head(), 1);
+
+		let deadline = sim.now() + 1_000;
+		assert!(sim.run_until(deadline, |_| backends[2].head() == 1));
+	}
+
+	#[test]
+	fn should_not_broadcast_to_sender() {
+		let (sender0, receiver0) = channel();
+		let (sender1, receiver1) = channel();
+		let network = LocalNetwork::<usize, TestBlock, u64, BestDepthStatus, ()> {
+			senders: vec![(0, sender0), (1, sender1)].into_iter().collect(),
+		};
+
+		network.broadcast((0, NetworkSyncMessage::NewBlock {
+			block: TestBlock::child(1, 0),
+		}));
+		assert!(receiver0.try_recv().is_err());
+		assert_eq!(receiver1.try_recv().map(|(peer, _)| peer), Ok(0));
+	}
+}
@@ -1,4 +1,6 @@
 pub mod sync;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 pub trait NetworkEnvironment {
 	type PeerId;
@@ -8,12 +10,23 @@ pub trait NetworkEnvironment {
 pub trait NetworkHandle: NetworkEnvironment {
 	fn send(&mut self, peer: &Self::PeerId, message: Self::Message);
 	fn broadcast(&mut self, message: Self::Message);
+	fn ban_peer(&mut self, _peer: &Self::PeerId) { }
+	fn unban_peer(&mut self, _peer: &Self::PeerId) { }
 }
 
 pub trait NetworkEvent: NetworkEnvironment {
 	fn on_tick<H: NetworkHandle>(&mut self, _handle: &mut H) where
 		H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message> { }
+	fn on_peer_connected<H: NetworkHandle>(
+		&mut self, _handle: &mut H, _peer: &Self::PeerId
+	) where H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message> { }
+	fn on_peer_disconnected<H: NetworkHandle>(
+		&mut self, _handle: &mut H, _peer: &Self::PeerId
+	) where H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message> { }
 	fn on_message<H: NetworkHandle>(
 		&mut self, _handle: &mut H, _peer: &Self::PeerId, _message: Self::Message
 	) where H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message> { }
+	fn on_undecodable_message<H: NetworkHandle>(
+		&mut self, _handle: &mut H, _peer: &Self::PeerId
+	) where H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message> { }
 }
@@ -54,6 +54,8 @@ impl<Ba: ChainQuery> StatusProducer for BestDepthStatusProducer<Ba> {
 
 #[derive(Debug)]
 pub enum BestDepthError {
+	IsGenesis,
+	UnknownParent,
 	Backend(Box<dyn std::error::Error>),
 	Executor(Box<dyn std::error::Error>),
 }
@@ -95,22 +97,26 @@ impl<E: BlockExecutor, Ba: ChainQuery + Store<Block=E::Block>> BlockImporter for
 			self.import_lock.lock()
 		);
 		let new_hash = block.id();
-		let (current_best_depth, current_best_state, new_depth) = {
+		let (current_best_depth, parent_state, new_depth) = {
 			let backend = importer.backend();
 			let current_best_hash = backend.head();
 			let current_best_depth = backend.depth_at(&current_best_hash)
 				.expect("Best block depth hash cannot fail");
-			let current_best_state = backend.state_at(&current_best_hash)
-				.expect("Best block depth state cannot fail");
-			let new_parent_depth = block.parent_id()
-				.map(|parent_hash| {
-					backend.depth_at(&parent_hash).unwrap()
-				})
-				.unwrap_or(0);
-			(current_best_depth, current_best_state, new_parent_depth + 1)
+			let parent_hash = block.parent_id()
+				.ok_or(BestDepthError::IsGenesis)?;
+			if !backend.contains(&parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?
+			{
+				return Err(BestDepthError::UnknownParent)
+			}
+			let parent_depth = backend.depth_at(&parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?;
+			let parent_state = backend.state_at(&parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?;
+			(current_best_depth, parent_state, parent_depth + 1)
 		};
 
-		let mut pending_state = current_best_state;
+		let mut pending_state = parent_state;
 		self.executor.execute_block(&block, pending_state.as_externalities())
 			.map_err(|e| BestDepthError::Executor(Box::new(e)))?;
 		importer.import_block(block, pending_state);
@@ -0,0 +1,272 @@
+use parity_codec::{Encode, Decode, Input, Output};
+use blockchain::{Block, Auxiliary, AsExternalities, BlockExecutor, DifficultyBlock};
+use blockchain::backend::{SharedCommittable, Operation, Store, ImportLock, ChainQuery};
+use blockchain::import::{ImportAction, BlockImporter};
+use core::cmp::Ordering;
+use super::StatusProducer;
+
+#[derive(Eq, PartialEq, Clone, Encode, Decode, Debug)]
+pub struct BestDifficultyStatus {
+	pub best_depth: u64,
+	pub total_difficulty: u128,
+}
+
+impl Ord for BestDifficultyStatus {
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.total_difficulty.cmp(&other.total_difficulty)
+			.then(self.best_depth.cmp(&other.best_depth))
+	}
+}
+
+impl PartialOrd for BestDifficultyStatus {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+/// Cumulative difficulty of a block, stored as an auxiliary.
+pub struct TotalDifficulty<B: Block> {
+	pub id: B::Identifier,
+	pub total_difficulty: u128,
+}
+
+impl<B: Block> Clone for TotalDifficulty<B> {
+	fn clone(&self) -> Self {
+		Self {
+			id: self.id.clone(),
+			total_difficulty: self.total_difficulty,
+		}
+	}
+}
+
+impl<B: Block> Auxiliary<B> for TotalDifficulty<B> {
+	type Key = B::Identifier;
+
+	fn key(&self) -> B::Identifier {
+		self.id.clone()
+	}
+
+	fn associated(&self) -> Vec<B::Identifier> {
+		vec![self.id.clone()]
+	}
+}
+
+impl<B: Block> Encode for TotalDifficulty<B> where
+	B::Identifier: Encode,
+{
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.id.encode_to(dest);
+		self.total_difficulty.encode_to(dest);
+	}
+}
+
+impl<B: Block> Decode for TotalDifficulty<B> where
+	B::Identifier: Decode,
+{
+	fn decode<I: Input>(input: &mut I) -> Option<Self> {
+		Some(Self {
+			id: B::Identifier::decode(input)?,
+			total_difficulty: u128::decode(input)?,
+		})
+	}
+}
+
+/// Get the total difficulty of a block. The genesis block does not have
+/// an auxiliary entry, and its total difficulty is its own difficulty.
+pub fn total_difficulty_at<Ba: ChainQuery>(
+	backend: &Ba,
+	id: &<Ba::Block as Block>::Identifier,
+) -> Result<Option<u128>, Ba::Error> where
+	Ba: Store<Auxiliary=TotalDifficulty<<Ba as Store>::Block>>,
+	Ba::Block: DifficultyBlock,
+{
+	if id == &backend.genesis() {
+		return Ok(Some(backend.block_at(id)?.difficulty()))
+	}
+
+	Ok(backend.auxiliary(id)?.map(|aux| aux.total_difficulty))
+}
+
+pub struct BestDifficultyStatusProducer<Ba> {
+	backend: Ba,
+}
+
+impl<Ba> BestDifficultyStatusProducer<Ba> {
+	pub fn new(backend: Ba) -> Self {
+		Self { backend }
+	}
+}
+
+impl<Ba: ChainQuery> StatusProducer for BestDifficultyStatusProducer<Ba> where
+	Ba: Store<Auxiliary=TotalDifficulty<<Ba as Store>::Block>>,
+	Ba::Block: DifficultyBlock,
+{
+	type Status = BestDifficultyStatus;
+
+	fn generate(&self) -> BestDifficultyStatus {
+		let best_hash = self.backend.head();
+		let best_depth = self.backend.depth_at(&best_hash)
+			.expect("Best block depth hash cannot fail");
+		let total_difficulty = total_difficulty_at(&self.backend, &best_hash)
+			.expect("Best block total difficulty cannot fail")
+			.expect("Best block total difficulty must exist");
+
+		BestDifficultyStatus {
+			best_depth: best_depth as u64,
+			total_difficulty,
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum BestDifficultyError {
+	IsGenesis,
+	UnknownParent,
+	UnknownTotalDifficulty,
+	Backend(Box<dyn std::error::Error>),
+	Executor(Box<dyn std::error::Error>),
+}
+
+impl std::fmt::Display for BestDifficultyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl std::error::Error for BestDifficultyError { }
+
+pub struct BestDifficultyImporter<E, Ba> {
+	backend: Ba,
+	import_lock: ImportLock,
+	executor: E,
+}
+
+impl<E: BlockExecutor, Ba: ChainQuery + Store<Block=E::Block>> BestDifficultyImporter<E, Ba> where
+	Ba: Store<Auxiliary=TotalDifficulty<E::Block>>,
+	Ba::State: AsExternalities<E::Externalities>,
+	E::Block: DifficultyBlock,
+{
+	pub fn new(executor: E, backend: Ba, import_lock: ImportLock) -> Self {
+		Self { backend, executor, import_lock }
+	}
+}
+
+impl<E: BlockExecutor, Ba: ChainQuery + Store<Block=E::Block>> BlockImporter for BestDifficultyImporter<E, Ba> where
+	Ba: Store<Auxiliary=TotalDifficulty<E::Block>>,
+	Ba::State: AsExternalities<E::Externalities>,
+	Ba: SharedCommittable<Operation=Operation<E::Block, <Ba as Store>::State, <Ba as Store>::Auxiliary>>,
+	E::Block: DifficultyBlock,
+{
+	type Block = E::Block;
+	type Error = BestDifficultyError;
+
+	fn import_block(&mut self, block: Ba::Block) -> Result<(), Self::Error> {
+		let mut importer = ImportAction::new(
+			&self.backend,
+			self.import_lock.lock()
+		);
+		let new_hash = block.id();
+		let (current_best_total_difficulty, parent_state, new_total_difficulty) = {
+			let backend = importer.backend();
+			let current_best_hash = backend.head();
+			let current_best_total_difficulty = total_difficulty_at(backend, &current_best_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?
+				.ok_or(BestDifficultyError::UnknownTotalDifficulty)?;
+			let parent_hash = block.parent_id()
+				.ok_or(BestDifficultyError::IsGenesis)?;
+			if !backend.contains(&parent_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?
+			{
+				return Err(BestDifficultyError::UnknownParent)
+			}
+			let parent_total_difficulty = total_difficulty_at(backend, &parent_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?
+				.ok_or(BestDifficultyError::UnknownTotalDifficulty)?;
+			let parent_state = backend.state_at(&parent_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?;
+			(
+				current_best_total_difficulty,
+				parent_state,
+				parent_total_difficulty.saturating_add(block.difficulty())
+			)
+		};
+
+		let mut pending_state = parent_state;
+		self.executor.execute_block(&block, pending_state.as_externalities())
+			.map_err(|e| BestDifficultyError::Executor(Box::new(e)))?;
+		importer.import_block(block, pending_state);
+		importer.insert_auxiliary(TotalDifficulty {
+			id: new_hash.clone(),
+			total_difficulty: new_total_difficulty,
+		});
+		if new_total_difficulty > current_best_total_difficulty {
+			importer.set_head(new_hash);
+		}
+		importer.commit().map_err(|e| BestDifficultyError::Backend(Box::new(e)))?;
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use blockchain::backend::SharedMemoryBackend;
+	use crate::test_support::{TestBlock, TestExecutor};
+
+	type TestBackend = SharedMemoryBackend<TestBlock, TotalDifficulty<TestBlock>, ()>;
+
+	fn block(id: u64, parent: u64, difficulty: u128) -> TestBlock {
+		TestBlock { id, parent: Some(parent), difficulty }
+	}
+
+	fn setup(genesis_difficulty: u128) -> (TestBackend, BestDifficultyImporter<TestExecutor, TestBackend>) {
+		let genesis = TestBlock { id: 0, parent: None, difficulty: genesis_difficulty };
+		let backend = TestBackend::new_with_genesis(genesis, ());
+		let importer = BestDifficultyImporter::new(TestExecutor, backend.clone(), ImportLock::new());
+		(backend, importer)
+	}
+
+	#[test]
+	fn should_seed_total_difficulty_from_genesis() {
+		let (backend, mut importer) = setup(5);
+		let status = BestDifficultyStatusProducer::new(backend.clone());
+		assert_eq!(status.generate(), BestDifficultyStatus { best_depth: 0, total_difficulty: 5 });
+
+		importer.import_block(block(1, 0, 3)).unwrap();
+		assert_eq!(backend.auxiliary(&1).unwrap().map(|aux| aux.total_difficulty), Some(8));
+		assert_eq!(status.generate(), BestDifficultyStatus { best_depth: 1, total_difficulty: 8 });
+	}
+
+	#[test]
+	fn should_keep_head_on_longer_lighter_fork() {
+		let (backend, mut importer) = setup(1);
+		importer.import_block(block(1, 0, 10)).unwrap();
+
+		importer.import_block(block(2, 0, 1)).unwrap();
+		importer.import_block(block(3, 2, 1)).unwrap();
+		importer.import_block(block(4, 3, 1)).unwrap();
+		assert_eq!(backend.head(), 1);
+
+		// Equal total difficulty does not switch the head either.
+		importer.import_block(block(5, 4, 7)).unwrap();
+		assert_eq!(backend.head(), 1);
+	}
+
+	#[test]
+	fn should_reorg_to_heavier_fork() {
+		let (backend, mut importer) = setup(1);
+		importer.import_block(block(1, 0, 10)).unwrap();
+		importer.import_block(block(2, 0, 4)).unwrap();
+		assert_eq!(backend.head(), 1);
+
+		importer.import_block(block(3, 2, 8)).unwrap();
+		assert_eq!(backend.head(), 3);
+
+		match importer.import_block(block(5, 4, 1)) {
+			Err(BestDifficultyError::UnknownParent) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		assert_eq!(backend.head(), 3);
+	}
+}
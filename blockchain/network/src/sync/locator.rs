@@ -0,0 +1,119 @@
+use blockchain::Block;
+use blockchain::backend::{ChainQuery, tree_route};
+
+const LOCATOR_DENSE_LEN: usize = 10;
+
+/// Build a block locator from the current head. The first hashes are
+/// consecutive canonical blocks, after which the step doubles each time.
+/// The locator always ends with genesis.
+pub fn build_locator<Ba: ChainQuery>(
+	backend: &Ba
+) -> Result<Vec<<Ba::Block as Block>::Identifier>, Ba::Error> {
+	let head = backend.head();
+	let mut depth = backend.depth_at(&head)?;
+	let mut step = 1;
+	let mut locator = Vec::new();
+
+	loop {
+		match backend.lookup_canon_depth(depth)? {
+			Some(id) => locator.push(id),
+			None => break,
+		}
+
+		if depth == 0 {
+			break
+		}
+
+		if locator.len() >= LOCATOR_DENSE_LEN {
+			step *= 2;
+		}
+		depth = depth.saturating_sub(step);
+	}
+
+	Ok(locator)
+}
+
+/// Find the first block in the locator that we know of, and return its
+/// common ancestor with our canonical chain.
+pub fn find_common_ancestor<Ba: ChainQuery>(
+	backend: &Ba,
+	locator: &[<Ba::Block as Block>::Identifier],
+) -> Result<Option<<Ba::Block as Block>::Identifier>, Ba::Error> {
+	for id in locator {
+		if !backend.contains(id)? {
+			continue
+		}
+
+		if backend.is_canon(id)? {
+			return Ok(Some(id.clone()))
+		}
+
+		let route = tree_route(backend, id, &backend.head())?;
+		return Ok(Some(route.common_block().clone()))
+	}
+
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use blockchain::backend::{SharedMemoryBackend, ImportLock};
+	use blockchain::import::BlockImporter;
+	use crate::sync::BestDepthImporter;
+	use crate::test_support::{TestBlock, TestExecutor};
+
+	type TestBackend = SharedMemoryBackend<TestBlock, (), ()>;
+
+	/// Backend with a canonical chain of the given length, where the id of
+	/// each block is its depth.
+	fn chain(len: u64) -> (TestBackend, BestDepthImporter<TestExecutor, TestBackend>) {
+		let backend = TestBackend::new_with_genesis(TestBlock::genesis(0), ());
+		let mut importer = BestDepthImporter::new(TestExecutor, backend.clone(), ImportLock::new());
+		for id in 1..=len {
+			importer.import_block(TestBlock::child(id, id - 1)).unwrap();
+		}
+		(backend, importer)
+	}
+
+	#[test]
+	fn should_space_locator_exponentially() {
+		let (backend, _) = chain(30);
+		let mut expected = (21..=30).rev().collect::<Vec<_>>();
+		expected.extend_from_slice(&[19, 15, 7, 0]);
+		assert_eq!(build_locator(&backend).unwrap(), expected);
+	}
+
+	#[test]
+	fn should_end_locator_with_genesis() {
+		let (backend, _) = chain(0);
+		assert_eq!(build_locator(&backend).unwrap(), vec![0]);
+
+		let (backend, _) = chain(3);
+		assert_eq!(build_locator(&backend).unwrap(), vec![3, 2, 1, 0]);
+	}
+
+	#[test]
+	fn should_find_canonical_ancestor() {
+		let (backend, _) = chain(30);
+		assert_eq!(find_common_ancestor(&backend, &[100, 25, 0]).unwrap(), Some(25));
+	}
+
+	#[test]
+	fn should_find_common_ancestor_on_fork() {
+		let (backend, mut importer) = chain(30);
+		// Shorter fork branching off after block 5.
+		importer.import_block(TestBlock::child(105, 5)).unwrap();
+		importer.import_block(TestBlock::child(106, 105)).unwrap();
+		assert_eq!(backend.head(), 30);
+
+		assert_eq!(find_common_ancestor(&backend, &[107, 106, 105, 5, 0]).unwrap(), Some(5));
+	}
+
+	#[test]
+	fn should_not_find_ancestor_without_overlap() {
+		let (backend, _) = chain(30);
+		assert_eq!(find_common_ancestor(&backend, &[200, 201]).unwrap(), None);
+		assert_eq!(find_common_ancestor(&backend, &[]).unwrap(), None);
+	}
+}
@@ -1,62 +1,560 @@
 mod difficulty;
 mod depth;
+mod orphan;
+mod locator;
+mod reputation;
+mod request;
+mod peers;
 
 pub use self::depth::{BestDepthStatus, BestDepthStatusProducer, BestDepthError, BestDepthImporter};
+pub use self::difficulty::{
+	BestDifficultyStatus, BestDifficultyStatusProducer, BestDifficultyError,
+	BestDifficultyImporter, TotalDifficulty, total_difficulty_at,
+};
+pub use self::orphan::OrphanPool;
+pub use self::locator::{build_locator, find_common_ancestor};
+pub use self::reputation::{PeerReputation, Misbehavior};
+pub use self::request::{Request, RequestTable, InFlight};
+pub use self::peers::PeerStatuses;
 
+use std::collections::HashSet;
+use core::cmp;
 use parity_codec::{Encode, Decode};
+use blockchain::Block;
 use blockchain::backend::{ChainQuery, Store, SharedCommittable, ImportLock};
 use blockchain::import::BlockImporter;
-use core::marker::PhantomData;
+use blockchain::pool::{SharedPool, ExtrinsicValidator};
+use core::hash::Hash;
 use crate::{NetworkHandle, NetworkEnvironment, NetworkEvent};
 
+const MAX_ORPHAN_COUNT: usize = 1024;
+const MAX_ORPHAN_BYTES: usize = 64 * 1024 * 1024;
+const ANCESTORS_REQUEST_COUNT: u64 = 256;
+const LOCATOR_REQUEST_COUNT: u64 = 256;
+const BAN_THRESHOLD: u32 = 100;
+const BAN_TICKS: u64 = 60;
+const PROTOCOL_VERSION: u32 = 5;
+const MAX_ANNOUNCE_BLOCK_BYTES: usize = 64 * 1024;
+const BLOCK_REQUEST_COUNT: u64 = 128;
+const MAX_DOWNLOAD_AHEAD: u64 = 512;
+const REQUEST_TIMEOUT_TICKS: u64 = 3;
+const MAX_REQUEST_ATTEMPTS: u32 = 3;
+const MAX_RESPONSE_COUNT: u64 = 256;
+
+/// Status that tells the best depth of a chain.
+pub trait ChainStatus {
+	fn best_depth(&self) -> u64;
+}
+
+impl ChainStatus for BestDepthStatus {
+	fn best_depth(&self) -> u64 {
+		self.best_depth
+	}
+}
+
+impl ChainStatus for BestDifficultyStatus {
+	fn best_depth(&self) -> u64 {
+		self.best_depth
+	}
+}
+
 pub trait StatusProducer {
-	type Status: Ord + Encode + Decode;
+	type Status: Ord + Encode + Decode + ChainStatus;
 
 	fn generate(&self) -> Self::Status;
 }
 
+/// Import errors that can tell whether the block's parent is missing, and
+/// whether the block itself is at fault.
+pub trait ImportError {
+	fn is_unknown_parent(&self) -> bool;
+	fn is_invalid_block(&self) -> bool;
+}
+
+impl ImportError for BestDepthError {
+	fn is_unknown_parent(&self) -> bool {
+		match self {
+			BestDepthError::UnknownParent => true,
+			_ => false,
+		}
+	}
+
+	fn is_invalid_block(&self) -> bool {
+		match self {
+			BestDepthError::IsGenesis | BestDepthError::Executor(_) => true,
+			_ => false,
+		}
+	}
+}
+
+impl ImportError for BestDifficultyError {
+	fn is_unknown_parent(&self) -> bool {
+		match self {
+			BestDifficultyError::UnknownParent => true,
+			_ => false,
+		}
+	}
+
+	fn is_invalid_block(&self) -> bool {
+		match self {
+			BestDifficultyError::IsGenesis | BestDifficultyError::Executor(_) => true,
+			_ => false,
+		}
+	}
+}
+
 #[derive(Clone, Debug, Encode, Decode)]
-pub enum NetworkSyncMessage<B, S> {
+pub enum NetworkSyncMessage<B, H, S, E> {
+	Handshake {
+		genesis: H,
+		version: u32,
+		status: S,
+	},
 	Status(S),
 	BlockRequest {
+		request_id: u64,
 		start_depth: u64,
 		count: u64,
 	},
+	AncestorsRequest {
+		request_id: u64,
+		id: H,
+		count: u64,
+	},
+	LocatorRequest {
+		request_id: u64,
+		locator: Vec<H>,
+		count: u64,
+	},
 	BlockResponse {
+		request_id: u64,
 		blocks: Vec<B>,
 	},
+	Extrinsics {
+		extrinsics: Vec<E>,
+	},
+	NewBlock {
+		block: B,
+	},
+	NewBlockId {
+		id: H,
+	},
 }
 
-pub struct NetworkSync<P, Ba, I, St> {
+pub struct NetworkSync<P, Ba: Store, I, St: StatusProducer, V: ExtrinsicValidator> {
 	backend: Ba,
 	import_lock: ImportLock,
 	importer: I,
 	status: St,
-	_marker: PhantomData<P>,
+	pool: SharedPool<V>,
+	orphans: OrphanPool<Ba::Block>,
+	reputation: PeerReputation<P>,
+	handshaked: HashSet<P>,
+	rejected: HashSet<P>,
+	located: HashSet<P>,
+	new_head: Option<Ba::Block>,
+	statuses: PeerStatuses<P, St::Status>,
+	requests: RequestTable<P, <Ba::Block as Block>::Identifier>,
+	download_cursor: u64,
+	ticks: u64,
 }
 
-impl<P, Ba, I, St> NetworkSync<P, Ba, I, St> {
-	pub fn new(backend: Ba, import_lock: ImportLock, importer: I, status: St) -> Self {
+impl<P: Eq + Hash + Clone, Ba: Store, I, St: StatusProducer, V: ExtrinsicValidator> NetworkSync<P, Ba, I, St, V> {
+	pub fn new(
+		backend: Ba, import_lock: ImportLock, importer: I, status: St, pool: SharedPool<V>
+	) -> Self {
 		Self {
-			backend, import_lock, importer, status,
-			_marker: PhantomData,
+			backend, import_lock, importer, status, pool,
+			orphans: OrphanPool::new(MAX_ORPHAN_COUNT, MAX_ORPHAN_BYTES),
+			reputation: PeerReputation::new(BAN_THRESHOLD, BAN_TICKS),
+			handshaked: HashSet::new(),
+			rejected: HashSet::new(),
+			located: HashSet::new(),
+			new_head: None,
+			statuses: PeerStatuses::new(),
+			requests: RequestTable::new(REQUEST_TIMEOUT_TICKS),
+			download_cursor: 0,
+			ticks: 0,
+		}
+	}
+
+	fn report<H: NetworkHandle<PeerId=P>>(
+		&mut self, handle: &mut H, peer: &P, misbehavior: Misbehavior
+	) {
+		if self.reputation.report(peer, misbehavior) {
+			println!("warn: banning peer for {:?}", misbehavior);
+			handle.ban_peer(peer);
 		}
 	}
 }
 
-impl<P, Ba: Store, I, St: StatusProducer> NetworkEnvironment for NetworkSync<P, Ba, I, St> {
+impl<P, Ba: Store, I, St: StatusProducer, V: ExtrinsicValidator> NetworkEnvironment for NetworkSync<P, Ba, I, St, V> {
 	type PeerId = P;
-	type Message = NetworkSyncMessage<Ba::Block, St::Status>;
+	type Message = NetworkSyncMessage<
+		Ba::Block, <Ba::Block as Block>::Identifier, St::Status, V::Extrinsic
+	>;
 }
 
-impl<P, Ba: SharedCommittable + ChainQuery, I: BlockImporter<Block=Ba::Block>, St: StatusProducer> NetworkEvent for NetworkSync<P, Ba, I, St> {
+impl<P: Eq + Hash + Clone, Ba: ChainQuery, I, St: StatusProducer, V: ExtrinsicValidator> NetworkSync<P, Ba, I, St, V> where
+	Ba::Block: Encode,
+	I: BlockImporter<Block=Ba::Block>,
+	I::Error: ImportError,
+{
+	/// Import a block, and then any orphans waiting on it. Blocks with
+	/// unknown parent are put into the orphan pool, and if requested, their
+	/// ancestors are requested from the peer. Returns the import error if the
+	/// block is invalid.
+	fn import_block<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P, block: Ba::Block, request_ancestors: bool,
+	) -> Result<(), I::Error> where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		let mut pending = vec![block];
+
+		while let Some(block) = pending.pop() {
+			let id = block.id();
+			if self.backend.contains(&id).unwrap_or(false) {
+				pending.append(&mut self.orphans.take_children(&id));
+				continue
+			}
+
+			match self.importer.import_block(block.clone()) {
+				Ok(()) => {
+					if self.backend.head() == id {
+						self.new_head = Some(block);
+					}
+					pending.append(&mut self.orphans.take_children(&id));
+				},
+				Err(ref e) if e.is_unknown_parent() => {
+					let parent_id = block.parent_id()
+						.expect("Block with unknown parent has a parent id; qed");
+					if request_ancestors {
+						// The peer is on a chain not known from the last
+						// locator, so locate it again on its next status.
+						self.located.remove(peer);
+					}
+					let size = block.encode().len();
+					if self.orphans.insert(block, size) &&
+						!self.orphans.contains(&parent_id) &&
+						request_ancestors
+					{
+						self.request(handle, peer, Request::Ancestors {
+							id: parent_id,
+							count: ANCESTORS_REQUEST_COUNT,
+						}, 0);
+					}
+				},
+				Err(e) => return Err(e),
+			}
+		}
+
+		Ok(())
+	}
+
+	fn handshake(&self) -> <Self as NetworkEnvironment>::Message {
+		NetworkSyncMessage::Handshake {
+			genesis: self.backend.genesis(),
+			version: PROTOCOL_VERSION,
+			status: self.status.generate(),
+		}
+	}
+
+	/// Announce the block that became the head during the last imports, if
+	/// any.
+	fn announce_new_head<H: NetworkHandle>(
+		&mut self, handle: &mut H
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		if let Some(block) = self.new_head.take() {
+			self.announce(handle, block);
+		}
+	}
+
+	fn announce<H: NetworkHandle>(
+		&mut self, handle: &mut H, block: Ba::Block
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		if block.encode().len() <= MAX_ANNOUNCE_BLOCK_BYTES {
+			handle.broadcast(NetworkSyncMessage::NewBlock { block });
+		} else {
+			handle.broadcast(NetworkSyncMessage::NewBlockId { id: block.id() });
+		}
+	}
+
+	/// Announce a block authored locally, which was imported without going
+	/// through the sync.
+	pub fn on_block_authored<H: NetworkHandle>(
+		&mut self, handle: &mut H, block: Ba::Block
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		self.announce(handle, block);
+	}
+
+	fn on_status<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P, peer_status: St::Status
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		// Peers are located on first contact, and again after sending blocks
+		// with unknown parents. Later statuses only extend the downloads.
+		let locate = !self.requests.is_locating() &&
+			!self.located.contains(peer) &&
+			self.is_available(peer) &&
+			peer_status > self.status.generate();
+		self.statuses.insert(peer.clone(), peer_status);
+
+		if locate {
+			self.located.insert(peer.clone());
+			self.request(handle, peer, Request::Locator {
+				count: LOCATOR_REQUEST_COUNT,
+			}, 0);
+		}
+		self.schedule_downloads(handle);
+	}
+
+	/// Send a request to the peer, and track it in the request table.
+	fn request<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P, request: Request<<Ba::Block as Block>::Identifier>,
+		attempts: u32,
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		let request_id = self.requests.insert(peer.clone(), request.clone(), attempts, self.ticks);
+		let message = match request {
+			Request::Blocks { start_depth, count } => {
+				NetworkSyncMessage::BlockRequest { request_id, start_depth, count }
+			},
+			Request::Ancestors { id, count } => {
+				NetworkSyncMessage::AncestorsRequest { request_id, id, count }
+			},
+			Request::Locator { count } => {
+				let locator = {
+					let _guard = self.import_lock.lock();
+					build_locator(&self.backend)
+						.expect("Building locator from head cannot fail")
+				};
+				NetworkSyncMessage::LocatorRequest { request_id, locator, count }
+			},
+		};
+		handle.send(peer, message);
+	}
+
+	/// Whether the peer can be sent requests.
+	fn is_available(&self, peer: &P) -> bool {
+		self.handshaked.contains(peer) && !self.reputation.is_banned(peer)
+	}
+
+	/// Retry a failed request against a different peer if possible.
+	fn retry<H: NetworkHandle>(
+		&mut self, handle: &mut H, in_flight: InFlight<P, <Ba::Block as Block>::Identifier>
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		let InFlight { peer: failed, request, attempts, .. } = in_flight;
+
+		if attempts + 1 < MAX_REQUEST_ATTEMPTS {
+			let min_depth = match request {
+				Request::Blocks { start_depth, count } => start_depth + count - 1,
+				_ => 0,
+			};
+			let peer = self.statuses.best_first().into_iter()
+				.filter(|(peer, status)| peer != &failed && status.best_depth() >= min_depth)
+				.map(|(peer, _)| peer)
+				.find(|peer| self.is_available(peer))
+				.or_else(|| if self.is_available(&failed) { Some(failed.clone()) } else { None });
+
+			if let Some(peer) = peer {
+				self.request(handle, &peer, request, attempts + 1);
+				return
+			}
+		}
+
+		if let Request::Blocks { start_depth, .. } = request {
+			self.download_cursor = cmp::min(self.download_cursor, start_depth);
+		}
+	}
+
+	/// Split the depth gap to better peers into depth-range requests, one
+	/// in flight per peer.
+	fn schedule_downloads<H: NetworkHandle>(
+		&mut self, handle: &mut H
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		let best_depth = self.status.generate().best_depth();
+		self.download_cursor = cmp::max(self.download_cursor, best_depth + 1);
+
+		let peers = self.statuses.best_first().into_iter()
+			.filter(|(peer, _)| self.is_available(peer) && !self.requests.is_downloading(peer))
+			.map(|(peer, status)| (peer, status.best_depth()))
+			.collect::<Vec<_>>();
+
+		for (peer, peer_depth) in peers {
+			if self.download_cursor > best_depth + MAX_DOWNLOAD_AHEAD {
+				break
+			}
+			if self.download_cursor > peer_depth {
+				continue
+			}
+
+			let start_depth = self.download_cursor;
+			let count = cmp::min(BLOCK_REQUEST_COUNT, peer_depth - start_depth + 1);
+			self.request(handle, &peer, Request::Blocks { start_depth, count }, 0);
+			self.download_cursor += count;
+		}
+	}
+
+	/// Check that a response matches what was requested.
+	fn is_valid_response(
+		&self, request: &Request<<Ba::Block as Block>::Identifier>, blocks: &[Ba::Block]
+	) -> bool {
+		if blocks.len() as u64 > request.count() {
+			return false
+		}
+
+		if !blocks.windows(2).all(|pair| pair[1].parent_id() == Some(pair[0].id())) {
+			return false
+		}
+
+		let first_parent = match blocks.first() {
+			Some(block) => block.parent_id(),
+			None => return true,
+		};
+		match request {
+			Request::Ancestors { id, .. } => {
+				blocks.last().map(|block| &block.id() == id).unwrap_or(true)
+			},
+			// Blocks downloaded ahead have an unknown parent, and are checked
+			// when they leave the orphan pool instead.
+			Request::Blocks { start_depth, .. } => match first_parent {
+				Some(parent) => match self.backend.contains(&parent) {
+					Ok(true) => self.backend.depth_at(&parent)
+						.map(|depth| depth as u64 + 1 == *start_depth)
+						.unwrap_or(false),
+					Ok(false) => true,
+					Err(_) => false,
+				},
+				None => false,
+			},
+			// Responses start right after the common ancestor, which is one
+			// of the locator hashes or an ancestor of one, so it is known.
+			Request::Locator { .. } => match first_parent {
+				Some(parent) => self.backend.contains(&parent).unwrap_or(false),
+				None => false,
+			},
+		}
+	}
+
+	fn on_block_response<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P, request_id: u64, blocks: Vec<Ba::Block>
+	) where
+		H: NetworkEnvironment<PeerId=P, Message=<Self as NetworkEnvironment>::Message>
+	{
+		let in_flight = match self.requests.take(request_id, peer) {
+			Some(in_flight) => in_flight,
+			None => {
+				self.report(handle, peer, Misbehavior::UnexpectedResponse);
+				return
+			},
+		};
+
+		if !self.is_valid_response(&in_flight.request, &blocks) {
+			self.report(handle, peer, Misbehavior::InvalidResponse);
+			self.retry(handle, in_flight);
+			return
+		}
+
+		if blocks.is_empty() {
+			self.retry(handle, in_flight);
+			return
+		}
+
+		let request_ancestors = match in_flight.request {
+			Request::Blocks { start_depth, .. } =>
+				start_depth == 0 || !self.requests.covers_depth(start_depth - 1),
+			_ => true,
+		};
+
+		for block in blocks {
+			if let Err(e) = self.import_block(handle, peer, block, request_ancestors) {
+				println!("warn: error happened on block response message: {:?}", e);
+				if e.is_invalid_block() {
+					self.report(handle, peer, Misbehavior::InvalidBlock);
+				}
+				break
+			}
+		}
+
+		self.announce_new_head(handle);
+		self.schedule_downloads(handle);
+	}
+}
+
+impl<P, Ba, I, St, V> NetworkEvent for NetworkSync<P, Ba, I, St, V> where
+	P: Eq + Hash + Clone,
+	Ba: SharedCommittable + ChainQuery,
+	St: StatusProducer,
+	V: ExtrinsicValidator,
+	Ba::Block: Encode,
+	I: BlockImporter<Block=Ba::Block>,
+	I::Error: ImportError,
+{
 	fn on_tick<H: NetworkHandle>(
 		&mut self, handle: &mut H
 	) where
 		H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message>
 	{
+		self.ticks += 1;
+
+		for peer in self.reputation.tick() {
+			handle.unban_peer(&peer);
+		}
+
+		for in_flight in self.requests.expire(self.ticks) {
+			println!("warn: request timed out");
+			self.retry(handle, in_flight);
+		}
+		self.schedule_downloads(handle);
+
 		let status = self.status.generate();
 		handle.broadcast(NetworkSyncMessage::Status(status));
+
+		let extrinsics = self.pool.lock().take_unpropagated();
+		if !extrinsics.is_empty() {
+			handle.broadcast(NetworkSyncMessage::Extrinsics { extrinsics });
+		}
+	}
+
+	fn on_peer_connected<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P
+	) where
+		H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message>
+	{
+		if self.rejected.contains(peer) || self.reputation.is_banned(peer) {
+			return
+		}
+
+		handle.send(peer, self.handshake());
+	}
+
+	fn on_peer_disconnected<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P
+	) where
+		H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message>
+	{
+		self.handshaked.remove(peer);
+		self.statuses.remove(peer);
+		self.located.remove(peer);
+		// A reconnecting peer is handshaked afresh, so that it is not
+		// rejected forever once its configuration is fixed.
+		self.rejected.remove(peer);
+
+		for in_flight in self.requests.remove_peer(peer) {
+			self.retry(handle, in_flight);
+		}
 	}
 
 	fn on_message<H: NetworkHandle>(
@@ -64,30 +562,55 @@ impl<P, Ba: SharedCommittable + ChainQuery, I: BlockImporter<Block=Ba::Block>, S
 	) where
 		H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message>
 	{
-		match message {
-			NetworkSyncMessage::Status(peer_status) => {
-				let status = self.status.generate();
-				let best_depth = {
-					let best_hash = self.backend.head();
-					self.backend.depth_at(&best_hash)
-						.expect("Best block depth hash cannot fail")
-				};
+		if self.rejected.contains(peer) || self.reputation.is_banned(peer) {
+			return
+		}
+
+		let message = match message {
+			NetworkSyncMessage::Handshake { genesis, version, status } => {
+				if genesis != self.backend.genesis() || version != PROTOCOL_VERSION {
+					// Not banned on the transport, so that the peer can come
+					// back on reconnection once its configuration is fixed.
+					println!("warn: rejecting peer with mismatched handshake");
+					self.handshaked.remove(peer);
+					self.statuses.remove(peer);
+					self.located.remove(peer);
+					self.rejected.insert(peer.clone());
+					for in_flight in self.requests.remove_peer(peer) {
+						self.retry(handle, in_flight);
+					}
+					return
+				}
 
-				if peer_status > status {
-					handle.send(peer, NetworkSyncMessage::BlockRequest {
-						start_depth: best_depth as u64 + 1,
-						count: 256,
-					});
+				if self.handshaked.insert(peer.clone()) {
+					handle.send(peer, self.handshake());
 				}
+				NetworkSyncMessage::Status(status)
+			},
+			message => {
+				if !self.handshaked.contains(peer) {
+					handle.send(peer, self.handshake());
+					return
+				}
+				message
+			},
+		};
+
+		match message {
+			NetworkSyncMessage::Handshake { .. } => (),
+			NetworkSyncMessage::Status(peer_status) => {
+				self.on_status(handle, peer, peer_status);
 			},
 			NetworkSyncMessage::BlockRequest {
+				request_id,
 				start_depth,
 				count,
 			} => {
+				let count = cmp::min(count, MAX_RESPONSE_COUNT);
 				let mut ret = Vec::new();
 				{
-					let _ = self.import_lock.lock();
-					for d in start_depth..(start_depth + count) {
+					let _guard = self.import_lock.lock();
+					for d in start_depth..start_depth.saturating_add(count) {
 						match self.backend.lookup_canon_depth(d as usize) {
 							Ok(Some(hash)) => {
 								let block = self.backend.block_at(&hash)
@@ -99,22 +622,121 @@ impl<P, Ba: SharedCommittable + ChainQuery, I: BlockImporter<Block=Ba::Block>, S
 					}
 				}
 				handle.send(peer, NetworkSyncMessage::BlockResponse {
+					request_id,
+					blocks: ret
+				});
+			},
+			NetworkSyncMessage::AncestorsRequest {
+				request_id,
+				id,
+				count,
+			} => {
+				let count = cmp::min(count, MAX_RESPONSE_COUNT);
+				let mut ret = Vec::new();
+				{
+					let _guard = self.import_lock.lock();
+					let mut current = Some(id);
+					while let Some(hash) = current {
+						if ret.len() as u64 >= count {
+							break
+						}
+
+						match self.backend.block_at(&hash) {
+							Ok(block) => {
+								current = block.parent_id();
+								ret.push(block);
+							},
+							Err(_) => break,
+						}
+					}
+				}
+				ret.reverse();
+				handle.send(peer, NetworkSyncMessage::BlockResponse {
+					request_id,
+					blocks: ret
+				});
+			},
+			NetworkSyncMessage::LocatorRequest {
+				request_id,
+				locator,
+				count,
+			} => {
+				let count = cmp::min(count, MAX_RESPONSE_COUNT);
+				let mut ret = Vec::new();
+				{
+					let _guard = self.import_lock.lock();
+					let start_depth = match find_common_ancestor(&self.backend, &locator) {
+						Ok(Some(ancestor)) => self.backend.depth_at(&ancestor).ok()
+							.map(|depth| depth + 1),
+						_ => None,
+					};
+
+					if let Some(start_depth) = start_depth {
+						for d in start_depth..(start_depth + count as usize) {
+							match self.backend.lookup_canon_depth(d) {
+								Ok(Some(hash)) => {
+									let block = self.backend.block_at(&hash)
+										.expect("Found hash cannot fail");
+									ret.push(block);
+								},
+								_ => break,
+							}
+						}
+					}
+				}
+				handle.send(peer, NetworkSyncMessage::BlockResponse {
+					request_id,
 					blocks: ret
 				});
 			},
 			NetworkSyncMessage::BlockResponse {
+				request_id,
 				blocks,
 			} => {
-				for block in blocks {
-					match self.importer.import_block(block) {
-						Ok(()) => (),
-						Err(_) => {
-							println!("warn: error happened on block response message");
-							break
-						},
+				self.on_block_response(handle, peer, request_id, blocks);
+			},
+			NetworkSyncMessage::Extrinsics {
+				extrinsics,
+			} => {
+				let mut pool = self.pool.lock();
+				for extrinsic in extrinsics {
+					let _ = pool.submit(extrinsic);
+				}
+			},
+			NetworkSyncMessage::NewBlock {
+				block,
+			} => {
+				if let Err(e) = self.import_block(handle, peer, block, true) {
+					println!("warn: error happened on new block message: {:?}", e);
+					if e.is_invalid_block() {
+						self.report(handle, peer, Misbehavior::InvalidBlock);
 					}
 				}
+				self.announce_new_head(handle);
+			},
+			NetworkSyncMessage::NewBlockId {
+				id,
+			} => {
+				if !self.backend.contains(&id).unwrap_or(true) && !self.orphans.contains(&id) {
+					self.request(handle, peer, Request::Ancestors {
+						id,
+						count: 1,
+					}, 0);
+				}
 			},
 		}
 	}
+
+	fn on_undecodable_message<H: NetworkHandle>(
+		&mut self, handle: &mut H, peer: &P
+	) where
+		H: NetworkEnvironment<PeerId=Self::PeerId, Message=Self::Message>
+	{
+		if self.rejected.contains(peer) || self.reputation.is_banned(peer) {
+			return
+		}
+
+		println!("warn: undecodable message received");
+		self.report(handle, peer, Misbehavior::UndecodableMessage);
+	}
 }
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use blockchain::Block;
+
+/// Pool of blocks whose parent is not yet known, keyed by the missing parent.
+pub struct OrphanPool<B: Block> {
+	blocks: HashMap<B::Identifier, (B, usize)>,
+	children: HashMap<B::Identifier, Vec<B::Identifier>>,
+	order: VecDeque<B::Identifier>,
+	bytes: usize,
+	max_count: usize,
+	max_bytes: usize,
+}
+
+impl<B: Block> OrphanPool<B> {
+	/// Create a new orphan pool bounded by block count and total bytes.
+	pub fn new(max_count: usize, max_bytes: usize) -> Self {
+		Self {
+			blocks: HashMap::new(),
+			children: HashMap::new(),
+			order: VecDeque::new(),
+			bytes: 0,
+			max_count,
+			max_bytes,
+		}
+	}
+
+	/// Number of orphan blocks in the pool.
+	pub fn len(&self) -> usize {
+		self.blocks.len()
+	}
+
+	/// Total size of orphan blocks in the pool.
+	pub fn bytes(&self) -> usize {
+		self.bytes
+	}
+
+	/// Whether the pool contains a block.
+	pub fn contains(&self, id: &B::Identifier) -> bool {
+		self.blocks.contains_key(id)
+	}
+
+	/// Insert an orphan block of the given encoded size. Returns false if the
+	/// block is already in the pool, does not have a parent, or is too large.
+	pub fn insert(&mut self, block: B, size: usize) -> bool {
+		let id = block.id();
+		let parent_id = match block.parent_id() {
+			Some(parent_id) => parent_id,
+			None => return false,
+		};
+
+		if self.blocks.contains_key(&id) || size > self.max_bytes || self.max_count == 0 {
+			return false
+		}
+
+		while self.blocks.len() + 1 > self.max_count || self.bytes + size > self.max_bytes {
+			self.evict_oldest();
+		}
+
+		self.children.entry(parent_id).or_default().push(id.clone());
+		self.order.push_back(id.clone());
+		self.blocks.insert(id, (block, size));
+		self.bytes += size;
+
+		true
+	}
+
+	/// Take all orphan blocks whose parent is the given block.
+	pub fn take_children(&mut self, parent_id: &B::Identifier) -> Vec<B> {
+		let ids = self.children.remove(parent_id).unwrap_or_default();
+
+		let mut ret = Vec::new();
+		for id in ids {
+			if let Some((block, size)) = self.blocks.remove(&id) {
+				self.bytes -= size;
+				ret.push(block);
+			}
+		}
+		if !ret.is_empty() {
+			let blocks = &self.blocks;
+			self.order.retain(|id| blocks.contains_key(id));
+		}
+		ret
+	}
+
+	fn evict_oldest(&mut self) {
+		while let Some(id) = self.order.pop_front() {
+			if let Some((block, size)) = self.blocks.remove(&id) {
+				self.bytes -= size;
+				if let Some(parent_id) = block.parent_id() {
+					let is_empty = self.children.get_mut(&parent_id).map(|children| {
+						children.retain(|child| child != &id);
+						children.is_empty()
+					}).unwrap_or(false);
+					if is_empty {
+						self.children.remove(&parent_id);
+					}
+				}
+				return
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Clone, Debug, Eq, PartialEq)]
+	struct TestBlock(u64, u64);
+
+	impl Block for TestBlock {
+		type Identifier = u64;
+
+		fn id(&self) -> u64 { self.0 }
+		fn parent_id(&self) -> Option<u64> { Some(self.1) }
+	}
+
+	#[test]
+	fn should_take_children_of_landed_parent() {
+		let mut pool = OrphanPool::new(16, 1024);
+
+		assert!(pool.insert(TestBlock(2, 1), 10));
+		assert!(pool.insert(TestBlock(3, 1), 10));
+		assert!(pool.insert(TestBlock(4, 3), 10));
+		assert!(!pool.insert(TestBlock(4, 3), 10));
+		assert_eq!(pool.len(), 3);
+		assert_eq!(pool.bytes(), 30);
+
+		let children = pool.take_children(&1);
+		assert_eq!(children, vec![TestBlock(2, 1), TestBlock(3, 1)]);
+		assert_eq!(pool.take_children(&3), vec![TestBlock(4, 3)]);
+		assert_eq!(pool.len(), 0);
+		assert_eq!(pool.bytes(), 0);
+	}
+
+	#[test]
+	fn should_evict_oldest_over_limits() {
+		let mut pool = OrphanPool::new(2, 25);
+
+		assert!(pool.insert(TestBlock(2, 1), 10));
+		assert!(pool.insert(TestBlock(3, 2), 10));
+		assert!(pool.insert(TestBlock(4, 3), 10));
+		assert!(!pool.contains(&2));
+		assert_eq!(pool.len(), 2);
+
+		assert!(pool.insert(TestBlock(5, 4), 20));
+		assert_eq!(pool.len(), 1);
+		assert!(pool.contains(&5));
+		assert!(!pool.insert(TestBlock(6, 5), 30));
+	}
+}
@@ -0,0 +1,73 @@
+/// Best status of each connected peer, kept in connection order.
+pub struct PeerStatuses<P, S> {
+	entries: Vec<(P, S)>,
+}
+
+impl<P: Eq + Clone, S: Ord> PeerStatuses<P, S> {
+	/// Create a new empty status table.
+	pub fn new() -> Self {
+		Self { entries: Vec::new() }
+	}
+
+	/// Number of peers with a known status.
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Whether there is no peer with a known status.
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// Get the status of a peer.
+	pub fn get(&self, peer: &P) -> Option<&S> {
+		self.entries.iter().find(|(other, _)| other == peer).map(|(_, status)| status)
+	}
+
+	/// Set the status of a peer.
+	pub fn insert(&mut self, peer: P, status: S) {
+		match self.entries.iter_mut().find(|(other, _)| other == &peer) {
+			Some((_, current)) => *current = status,
+			None => self.entries.push((peer, status)),
+		}
+	}
+
+	/// Remove a peer.
+	pub fn remove(&mut self, peer: &P) -> Option<S> {
+		let index = self.entries.iter().position(|(other, _)| other == peer)?;
+		Some(self.entries.remove(index).1)
+	}
+
+	/// Peers ordered from the best status to the worst. Peers with equal
+	/// status stay in connection order.
+	pub fn best_first(&self) -> Vec<(P, &S)> {
+		let mut entries = self.entries.iter().collect::<Vec<_>>();
+		entries.sort_by(|a, b| b.1.cmp(&a.1));
+		entries.into_iter().map(|(peer, status)| (peer.clone(), status)).collect()
+	}
+}
+
+impl<P: Eq + Clone, S: Ord> Default for PeerStatuses<P, S> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_order_best_first() {
+		let mut statuses = PeerStatuses::new();
+		statuses.insert(1, 10);
+		statuses.insert(2, 30);
+		statuses.insert(3, 10);
+		statuses.insert(1, 20);
+
+		assert_eq!(statuses.best_first(), vec![(2, &30), (1, &20), (3, &10)]);
+		assert_eq!(statuses.remove(&2), Some(30));
+		assert_eq!(statuses.get(&2), None);
+		assert_eq!(statuses.len(), 2);
+	}
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use core::hash::Hash;
+
+/// Misbehavior of a peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Misbehavior {
+	/// Peer sent a message that cannot be decoded.
+	UndecodableMessage,
+	/// Peer sent a block that failed to import.
+	InvalidBlock,
+	/// Peer sent a response that does not match its request.
+	InvalidResponse,
+	/// Peer sent a response that was not requested, or too late.
+	UnexpectedResponse,
+}
+
+impl Misbehavior {
+	/// Penalty added to the peer for this misbehavior.
+	pub fn penalty(&self) -> u32 {
+		match self {
+			Misbehavior::UndecodableMessage => 25,
+			Misbehavior::InvalidBlock => 100,
+			Misbehavior::InvalidResponse => 50,
+			Misbehavior::UnexpectedResponse => 10,
+		}
+	}
+}
+
+/// Penalty-based peer reputation. Peers whose penalty reaches the threshold
+/// are banned for a number of ticks. Penalties decay by one every tick.
+pub struct PeerReputation<P> {
+	penalties: HashMap<P, u32>,
+	banned: HashMap<P, u64>,
+	ban_threshold: u32,
+	ban_ticks: u64,
+}
+
+impl<P: Eq + Hash + Clone> PeerReputation<P> {
+	/// Create a new peer reputation tracker.
+	pub fn new(ban_threshold: u32, ban_ticks: u64) -> Self {
+		Self {
+			penalties: HashMap::new(),
+			banned: HashMap::new(),
+			ban_threshold,
+			ban_ticks,
+		}
+	}
+
+	/// Current penalty of a peer.
+	pub fn penalty(&self, peer: &P) -> u32 {
+		self.penalties.get(peer).cloned().unwrap_or(0)
+	}
+
+	/// Whether the peer is currently banned.
+	pub fn is_banned(&self, peer: &P) -> bool {
+		self.banned.contains_key(peer)
+	}
+
+	/// Report a misbehavior of a peer. Returns true if the peer is newly
+	/// banned by this report.
+	pub fn report(&mut self, peer: &P, misbehavior: Misbehavior) -> bool {
+		if self.is_banned(peer) {
+			return false
+		}
+
+		let penalty = self.penalties.entry(peer.clone()).or_insert(0);
+		*penalty = penalty.saturating_add(misbehavior.penalty());
+
+		if *penalty >= self.ban_threshold {
+			self.penalties.remove(peer);
+			self.banned.insert(peer.clone(), self.ban_ticks);
+			true
+		} else {
+			false
+		}
+	}
+
+	/// Advance one tick. Returns peers whose ban has expired.
+	pub fn tick(&mut self) -> Vec<P> {
+		for penalty in self.penalties.values_mut() {
+			*penalty = penalty.saturating_sub(1);
+		}
+		self.penalties.retain(|_, penalty| *penalty > 0);
+
+		let mut unbanned = Vec::new();
+		for (peer, remaining) in self.banned.iter_mut() {
+			*remaining = remaining.saturating_sub(1);
+			if *remaining == 0 {
+				unbanned.push(peer.clone());
+			}
+		}
+		for peer in &unbanned {
+			self.banned.remove(peer);
+		}
+
+		unbanned
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_ban_and_unban() {
+		let mut reputation = PeerReputation::new(100, 2);
+
+		assert!(!reputation.report(&1, Misbehavior::UndecodableMessage));
+		assert_eq!(reputation.penalty(&1), 25);
+		assert!(reputation.tick().is_empty());
+		assert_eq!(reputation.penalty(&1), 24);
+
+		assert!(reputation.report(&1, Misbehavior::InvalidBlock));
+		assert!(reputation.is_banned(&1));
+		assert!(!reputation.report(&1, Misbehavior::InvalidBlock));
+
+		assert!(reputation.tick().is_empty());
+		assert_eq!(reputation.tick(), vec![1]);
+		assert!(!reputation.is_banned(&1));
+		assert_eq!(reputation.penalty(&1), 0);
+	}
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use core::hash::Hash;
+
+/// A block request sent to a peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Request<H> {
+	/// Canonical blocks starting from a depth.
+	Blocks {
+		start_depth: u64,
+		count: u64,
+	},
+	/// A block and its ancestors.
+	Ancestors {
+		id: H,
+		count: u64,
+	},
+	/// Canonical blocks following the common ancestor of a locator.
+	Locator {
+		count: u64,
+	},
+}
+
+impl<H> Request<H> {
+	/// Maximum number of blocks expected in the response.
+	pub fn count(&self) -> u64 {
+		match self {
+			Request::Blocks { count, .. } |
+			Request::Ancestors { count, .. } |
+			Request::Locator { count } => *count,
+		}
+	}
+}
+
+/// A request waiting for its response.
+#[derive(Clone, Debug)]
+pub struct InFlight<P, H> {
+	pub peer: P,
+	pub request: Request<H>,
+	pub attempts: u32,
+	deadline: u64,
+}
+
+/// Table of in-flight requests, keyed by request id.
+pub struct RequestTable<P, H> {
+	in_flight: HashMap<u64, InFlight<P, H>>,
+	next_id: u64,
+	timeout: u64,
+}
+
+impl<P: Eq + Hash + Clone, H> RequestTable<P, H> {
+	/// Create a new request table, where requests time out after the given
+	/// number of ticks.
+	pub fn new(timeout: u64) -> Self {
+		Self {
+			in_flight: HashMap::new(),
+			next_id: 0,
+			timeout,
+		}
+	}
+
+	/// Number of in-flight requests.
+	pub fn len(&self) -> usize {
+		self.in_flight.len()
+	}
+
+	/// Insert a new request sent at the given tick. Returns its request id.
+	pub fn insert(&mut self, peer: P, request: Request<H>, attempts: u32, now: u64) -> u64 {
+		let id = self.next_id;
+		self.next_id += 1;
+		self.in_flight.insert(id, InFlight {
+			peer, request, attempts,
+			deadline: now + self.timeout,
+		});
+		id
+	}
+
+	/// Take the request answered by a response from the given peer.
+	pub fn take(&mut self, id: u64, peer: &P) -> Option<InFlight<P, H>> {
+		if self.in_flight.get(&id).map(|in_flight| &in_flight.peer != peer).unwrap_or(true) {
+			return None
+		}
+
+		self.in_flight.remove(&id)
+	}
+
+	/// Remove and return all requests that have timed out.
+	pub fn expire(&mut self, now: u64) -> Vec<InFlight<P, H>> {
+		let mut ids = self.in_flight.iter()
+			.filter(|(_, in_flight)| in_flight.deadline <= now)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		ids.sort();
+		ids.into_iter().filter_map(|id| self.in_flight.remove(&id)).collect()
+	}
+
+	/// Remove and return all requests sent to a peer.
+	pub fn remove_peer(&mut self, peer: &P) -> Vec<InFlight<P, H>> {
+		let mut ids = self.in_flight.iter()
+			.filter(|(_, in_flight)| &in_flight.peer == peer)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		ids.sort();
+		ids.into_iter().filter_map(|id| self.in_flight.remove(&id)).collect()
+	}
+
+	/// Whether a depth-range request is in flight to the peer.
+	pub fn is_downloading(&self, peer: &P) -> bool {
+		self.in_flight.values().any(|in_flight| {
+			&in_flight.peer == peer && match in_flight.request {
+				Request::Blocks { .. } => true,
+				_ => false,
+			}
+		})
+	}
+
+	/// Whether a locator request is in flight.
+	pub fn is_locating(&self) -> bool {
+		self.in_flight.values().any(|in_flight| match in_flight.request {
+			Request::Locator { .. } => true,
+			_ => false,
+		})
+	}
+
+	/// Whether a depth-range request in flight covers the given depth.
+	pub fn covers_depth(&self, depth: u64) -> bool {
+		self.in_flight.values().any(|in_flight| match in_flight.request {
+			Request::Blocks { start_depth, count } =>
+				start_depth <= depth && depth < start_depth + count,
+			_ => false,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_match_and_expire_requests() {
+		let mut table = RequestTable::<u64, u64>::new(3);
+
+		let first = table.insert(1, Request::Blocks { start_depth: 10, count: 5 }, 0, 0);
+		let second = table.insert(2, Request::Ancestors { id: 7, count: 1 }, 0, 1);
+		assert!(table.is_downloading(&1));
+		assert!(table.covers_depth(14));
+		assert!(!table.covers_depth(15));
+
+		assert!(table.take(first, &2).is_none());
+		assert_eq!(table.take(first, &1).unwrap().request, Request::Blocks { start_depth: 10, count: 5 });
+		assert!(table.take(first, &1).is_none());
+
+		assert!(table.expire(3).is_empty());
+		let expired = table.expire(4);
+		assert_eq!(expired.len(), 1);
+		assert_eq!(expired[0].peer, 2);
+		assert!(table.take(second, &2).is_none());
+		assert_eq!(table.len(), 0);
+	}
+}
@@ -0,0 +1,53 @@
+//! Block and executor fixtures shared by the tests of the sync engine and
+//! of its transports.
+
+use parity_codec::{Encode, Decode};
+use blockchain::{Block, BlockExecutor, DifficultyBlock, NullExternalities};
+use blockchain::backend::MemoryError;
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct TestBlock {
+	pub id: u64,
+	pub parent: Option<u64>,
+	pub difficulty: u128,
+}
+
+impl TestBlock {
+	/// Genesis block of difficulty one.
+	pub fn genesis(id: u64) -> Self {
+		Self { id, parent: None, difficulty: 1 }
+	}
+
+	/// Block of difficulty one on top of the given parent.
+	pub fn child(id: u64, parent: u64) -> Self {
+		Self { id, parent: Some(parent), difficulty: 1 }
+	}
+}
+
+impl Block for TestBlock {
+	type Identifier = u64;
+
+	fn id(&self) -> u64 { self.id }
+	fn parent_id(&self) -> Option<u64> { self.parent }
+}
+
+impl DifficultyBlock for TestBlock {
+	fn difficulty(&self) -> u128 { self.difficulty }
+}
+
+/// Executor accepting every block.
+pub struct TestExecutor;
+
+impl BlockExecutor for TestExecutor {
+	type Error = MemoryError;
+	type Block = TestBlock;
+	type Externalities = dyn NullExternalities + 'static;
+
+	fn execute_block(
+		&self,
+		_block: &TestBlock,
+		_state: &mut Self::Externalities,
+	) -> Result<(), MemoryError> {
+		Ok(())
+	}
+}
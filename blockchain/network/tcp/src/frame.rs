@@ -0,0 +1,52 @@
+use std::io::{self, Read, Write};
+
+/// Maximum size of a frame payload.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Write a frame, prefixed by its length as a little-endian u32.
+pub fn write_frame<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+	if data.len() > MAX_FRAME_SIZE {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame is too large"))
+	}
+
+	let len = data.len() as u32;
+	writer.write_all(&len.to_le_bytes())?;
+	writer.write_all(data)?;
+	writer.flush()
+}
+
+/// Read a frame written by `write_frame`.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+	let mut len = [0u8; 4];
+	reader.read_exact(&mut len)?;
+	let len = u32::from_le_bytes(len) as usize;
+
+	if len > MAX_FRAME_SIZE {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame is too large"))
+	}
+
+	let mut data = vec![0u8; len];
+	reader.read_exact(&mut data)?;
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_roundtrip_frames() {
+		let mut buf = Vec::new();
+		write_frame(&mut buf, b"hello").unwrap();
+		write_frame(&mut buf, b"").unwrap();
+		assert_eq!(buf.len(), 4 + 5 + 4);
+
+		let mut reader = &buf[..];
+		assert_eq!(read_frame(&mut reader).unwrap(), b"hello".to_vec());
+		assert_eq!(read_frame(&mut reader).unwrap(), Vec::<u8>::new());
+		assert!(read_frame(&mut reader).is_err());
+
+		let mut reader = &(MAX_FRAME_SIZE as u32 + 1).to_le_bytes()[..];
+		assert_eq!(read_frame(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,451 @@
+mod frame;
+
+pub use crate::frame::{read_frame, write_frame, MAX_FRAME_SIZE};
+
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown};
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex, mpsc::{Sender, Receiver, channel}};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use core::fmt::Debug;
+use parity_codec::{Encode, Decode};
+use blockchain::Block;
+use blockchain::backend::{SharedCommittable, ChainQuery, ImportLock};
+use blockchain::import::BlockImporter;
+use blockchain::pool::{SharedPool, ExtrinsicValidator};
+use blockchain_network::{NetworkEnvironment, NetworkHandle, NetworkEvent};
+use blockchain_network::sync::{NetworkSync, NetworkSyncMessage, StatusProducer, ImportError};
+
+const POLL_INTERVAL_MILLIS: u64 = 100;
+const POLLS_PER_TICK: usize = 10;
+const RECONNECT_INTERVAL_MILLIS: u64 = 1000;
+const HANDSHAKE_TIMEOUT_MILLIS: u64 = 5000;
+const MAX_PENDING_HANDSHAKES: usize = 16;
+
+/// Configuration of a TCP node. Peers are identified by their listen
+/// address. Each pair of peers shares one connection, dialed by the peer
+/// with the lower listen address, so both must list each other.
+#[derive(Clone, Debug)]
+pub struct TcpConfig {
+	/// Address to listen on, which must be the address peers list for this
+	/// node.
+	pub listen: SocketAddr,
+	/// Listen addresses of the static peers. Inbound connections from other
+	/// addresses are rejected.
+	pub peers: Vec<SocketAddr>,
+}
+
+enum TcpEvent {
+	Connected(SocketAddr),
+	Disconnected(SocketAddr),
+	Message(SocketAddr, Vec<u8>),
+}
+
+#[derive(Default)]
+struct TcpPeers {
+	writers: HashMap<SocketAddr, (u64, Sender<Vec<u8>>)>,
+	banned: HashSet<SocketAddr>,
+	next_connection: u64,
+}
+
+pub struct TcpNetworkHandle<B, H, S, E> {
+	peers: Arc<Mutex<TcpPeers>>,
+	_marker: PhantomData<(B, H, S, E)>,
+}
+
+impl<B, H, S, E> Clone for TcpNetworkHandle<B, H, S, E> {
+	fn clone(&self) -> Self {
+		Self {
+			peers: self.peers.clone(),
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<B, H, S, E> TcpNetworkHandle<B, H, S, E> {
+	fn is_banned(&self, peer: &SocketAddr) -> bool {
+		self.peers.lock().expect("Lock is poisoned").banned.contains(peer)
+	}
+}
+
+impl<B, H, S, E> NetworkEnvironment for TcpNetworkHandle<B, H, S, E> {
+	type PeerId = SocketAddr;
+	type Message = NetworkSyncMessage<B, H, S, E>;
+}
+
+impl<B: Encode, H: Encode, S: Encode, E: Encode> NetworkHandle for TcpNetworkHandle<B, H, S, E> {
+	fn send(&mut self, peer: &SocketAddr, message: NetworkSyncMessage<B, H, S, E>) {
+		let writer = {
+			let peers = self.peers.lock().expect("Lock is poisoned");
+			if peers.banned.contains(peer) {
+				return
+			}
+			peers.writers.get(peer).map(|(_, writer)| writer.clone())
+		};
+
+		if let Some(writer) = writer {
+			let _ = writer.send(message.encode());
+		}
+	}
+
+	fn broadcast(&mut self, message: NetworkSyncMessage<B, H, S, E>) {
+		let writers = {
+			let peers = self.peers.lock().expect("Lock is poisoned");
+			peers.writers.iter()
+				.filter(|(peer, _)| !peers.banned.contains(*peer))
+				.map(|(_, (_, writer))| writer.clone())
+				.collect::<Vec<_>>()
+		};
+
+		let data = message.encode();
+		for writer in writers {
+			let _ = writer.send(data.clone());
+		}
+	}
+
+	fn ban_peer(&mut self, peer: &SocketAddr) {
+		self.peers.lock().expect("Lock is poisoned").banned.insert(*peer);
+	}
+
+	fn unban_peer(&mut self, peer: &SocketAddr) {
+		self.peers.lock().expect("Lock is poisoned").banned.remove(peer);
+	}
+}
+
+/// Run a connection to a peer until it is closed. Frames queued for the
+/// peer are written by a separate thread, so that a slow peer blocks neither
+/// the sync nor other peers. A connection to an already connected peer is
+/// dropped in favor of the existing one. Returns false if the events
+/// receiver is gone.
+fn run_connection(
+	stream: TcpStream,
+	peer: SocketAddr,
+	peers: &Arc<Mutex<TcpPeers>>,
+	events: &Sender<TcpEvent>,
+) -> bool {
+	let mut writer = match stream.try_clone() {
+		Ok(writer) => writer,
+		Err(_) => return true,
+	};
+
+	let (sender, receiver) = channel::<Vec<u8>>();
+	let connection = {
+		let mut peers = peers.lock().expect("Lock is poisoned");
+		if peers.writers.contains_key(&peer) {
+			let _ = stream.shutdown(Shutdown::Both);
+			return true
+		}
+
+		let connection = peers.next_connection;
+		peers.next_connection += 1;
+		peers.writers.insert(peer, (connection, sender));
+		connection
+	};
+
+	thread::spawn(move || {
+		for data in receiver.iter() {
+			if write_frame(&mut writer, &data).is_err() {
+				break
+			}
+		}
+		// Wakes up the reader, which reports the disconnect.
+		let _ = writer.shutdown(Shutdown::Both);
+	});
+
+	if events.send(TcpEvent::Connected(peer)).is_err() {
+		return false
+	}
+
+	let mut reader = stream;
+	while let Ok(data) = read_frame(&mut reader) {
+		if events.send(TcpEvent::Message(peer, data)).is_err() {
+			return false
+		}
+	}
+
+	// Dropping the sender stops the writer thread.
+	{
+		let mut peers = peers.lock().expect("Lock is poisoned");
+		if peers.writers.get(&peer).map(|(id, _)| *id) == Some(connection) {
+			peers.writers.remove(&peer);
+		}
+	}
+	events.send(TcpEvent::Disconnected(peer)).is_ok()
+}
+
+/// Read the listen address declared by an inbound peer. It must be one of
+/// the static peers, on the address the connection comes from.
+fn read_declared_peer(stream: &mut TcpStream, allowed: &[SocketAddr]) -> Option<SocketAddr> {
+	let remote = stream.peer_addr().ok()?;
+	if !allowed.iter().any(|peer| peer.ip() == remote.ip()) {
+		return None
+	}
+
+	stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MILLIS))).ok()?;
+	let peer = read_frame(stream).ok()
+		.and_then(|data| String::from_utf8(data).ok())
+		.and_then(|address| address.parse::<SocketAddr>().ok())?;
+	stream.set_read_timeout(None).ok()?;
+
+	if peer.ip() == remote.ip() && allowed.contains(&peer) {
+		Some(peer)
+	} else {
+		None
+	}
+}
+
+fn accept_thread(
+	listener: TcpListener,
+	allowed: Vec<SocketAddr>,
+	peers: Arc<Mutex<TcpPeers>>,
+	events: Sender<TcpEvent>,
+) {
+	let allowed = Arc::new(allowed);
+	let pending = Arc::new(AtomicUsize::new(0));
+
+	for stream in listener.incoming() {
+		let mut stream = match stream {
+			Ok(stream) => stream,
+			Err(_) => continue,
+		};
+		if pending.load(Ordering::SeqCst) >= MAX_PENDING_HANDSHAKES {
+			continue
+		}
+		pending.fetch_add(1, Ordering::SeqCst);
+
+		let allowed = allowed.clone();
+		let pending = pending.clone();
+		let peers = peers.clone();
+		let events = events.clone();
+
+		thread::spawn(move || {
+			let peer = read_declared_peer(&mut stream, &allowed);
+			pending.fetch_sub(1, Ordering::SeqCst);
+
+			if let Some(peer) = peer {
+				run_connection(stream, peer, &peers, &events);
+			}
+		});
+	}
+}
+
+fn dial_thread(
+	listen: SocketAddr,
+	peer: SocketAddr,
+	peers: Arc<Mutex<TcpPeers>>,
+	events: Sender<TcpEvent>,
+) {
+	loop {
+		if let Ok(mut stream) = TcpStream::connect(peer) {
+			if write_frame(&mut stream, listen.to_string().as_bytes()).is_ok() &&
+				!run_connection(stream, peer, &peers, &events)
+			{
+				return
+			}
+		}
+
+		thread::sleep(Duration::from_millis(RECONNECT_INTERVAL_MILLIS));
+	}
+}
+
+/// Start the TCP network on the listener, and return a handle to it,
+/// together with the receiver of network events. Only peers with a higher
+/// listen address are dialed, the others are expected to dial.
+fn start_tcp_network<B, H, S, E>(
+	listener: TcpListener,
+	listen: SocketAddr,
+	allowed: Vec<SocketAddr>,
+) -> (TcpNetworkHandle<B, H, S, E>, Receiver<TcpEvent>) {
+	let peers = Arc::new(Mutex::new(TcpPeers::default()));
+	let (sender, receiver) = channel();
+
+	let dial = allowed.iter().cloned().filter(|peer| listen < *peer).collect::<Vec<_>>();
+
+	let accept_peers = peers.clone();
+	let accept_sender = sender.clone();
+	thread::spawn(move || {
+		accept_thread(listener, allowed, accept_peers, accept_sender);
+	});
+
+	for peer in dial {
+		let dial_peers = peers.clone();
+		let dial_sender = sender.clone();
+		thread::spawn(move || {
+			dial_thread(listen, peer, dial_peers, dial_sender);
+		});
+	}
+
+	(TcpNetworkHandle { peers, _marker: PhantomData }, receiver)
+}
+
+pub fn start_tcp_simple_sync<Ba, I, St, V>(
+	config: TcpConfig,
+	backend: Ba,
+	import_lock: ImportLock,
+	importer: I,
+	status: St,
+	pool: SharedPool<V>,
+	authored: Receiver<Ba::Block>,
+) where
+	Ba: SharedCommittable + ChainQuery + Send + Sync + 'static,
+	Ba::Block: Debug + Encode + Decode + Send + Sync,
+	<Ba::Block as Block>::Identifier: Debug + Encode + Decode + Send + Sync,
+	I: BlockImporter<Block=Ba::Block> + Send + Sync + 'static,
+	I::Error: ImportError,
+	St: StatusProducer + Send + Sync + 'static,
+	St::Status: Debug + Clone + Send + Sync,
+	V: ExtrinsicValidator + Send + 'static,
+	V::Extrinsic: Debug + Encode + Decode + Send + Sync,
+	V::Hash: Send,
+	V::Sender: Send,
+{
+	assert!(
+		!config.listen.ip().is_unspecified(),
+		"Listen address must be the address peers list for this node",
+	);
+	let listener = TcpListener::bind(config.listen).expect("Failed to listen");
+	println!("Listening on {:?}", config.listen);
+	let (mut handle, receiver) = start_tcp_network(listener, config.listen, config.peers);
+
+	let mut sync = NetworkSync::new(backend, import_lock, importer, status, pool);
+
+	let mut polls = 0;
+	loop {
+		for event in receiver.try_iter() {
+			match event {
+				TcpEvent::Connected(peer) => {
+					println!("Connected to {:?}", peer);
+					sync.on_peer_connected(&mut handle, &peer);
+				},
+				TcpEvent::Disconnected(peer) => {
+					println!("Disconnected from {:?}", peer);
+					sync.on_peer_disconnected(&mut handle, &peer);
+				},
+				TcpEvent::Message(peer, data) => {
+					if handle.is_banned(&peer) {
+						continue
+					}
+
+					match NetworkSyncMessage::decode(&mut &data[..]) {
+						Some(message) => {
+							println!("Received: {:?} from {:?}", message, peer);
+							sync.on_message(&mut handle, &peer, message);
+						},
+						None => {
+							println!("Received undecodable message from {:?}", peer);
+							sync.on_undecodable_message(&mut handle, &peer);
+						},
+					}
+				},
+			}
+		}
+		for block in authored.try_iter() {
+			sync.on_block_authored(&mut handle, block);
+		}
+
+		thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+		polls += 1;
+		if polls >= POLLS_PER_TICK {
+			polls = 0;
+			sync.on_tick(&mut handle);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type TestHandle = TcpNetworkHandle<u64, u64, u64, u64>;
+	type TestMessage = NetworkSyncMessage<u64, u64, u64, u64>;
+
+	fn next_event(events: &Receiver<TcpEvent>) -> TcpEvent {
+		events.recv_timeout(Duration::from_secs(5)).expect("Event is received in time")
+	}
+
+	fn expect_connected(events: &Receiver<TcpEvent>) -> SocketAddr {
+		match next_event(events) {
+			TcpEvent::Connected(peer) => peer,
+			_ => panic!("Expected a connection"),
+		}
+	}
+
+	fn expect_new_block_id(events: &Receiver<TcpEvent>) -> (SocketAddr, u64) {
+		match next_event(events) {
+			TcpEvent::Message(peer, data) => match TestMessage::decode(&mut &data[..]) {
+				Some(NetworkSyncMessage::NewBlockId { id }) => (peer, id),
+				_ => panic!("Expected a new block id message"),
+			},
+			_ => panic!("Expected a message"),
+		}
+	}
+
+	fn bind() -> (TcpListener, SocketAddr) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		(listener, address)
+	}
+
+	/// Whether the node closes a connection declaring the given address.
+	fn is_rejected(node: SocketAddr, declared: &str) -> bool {
+		let mut stream = TcpStream::connect(node).unwrap();
+		write_frame(&mut stream, declared.as_bytes()).unwrap();
+		stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		match read_frame(&mut stream) {
+			Err(ref e) => e.kind() == std::io::ErrorKind::UnexpectedEof ||
+				e.kind() == std::io::ErrorKind::ConnectionReset,
+			Ok(_) => false,
+		}
+	}
+
+	#[test]
+	fn should_share_one_connection_between_listed_peers() {
+		let (listener_a, address_a) = bind();
+		let (listener_b, address_b) = bind();
+		let (mut handle_a, events_a): (TestHandle, _) =
+			start_tcp_network(listener_a, address_a, vec![address_b]);
+		let (mut handle_b, events_b): (TestHandle, _) =
+			start_tcp_network(listener_b, address_b, vec![address_a]);
+
+		// Peers are keyed by their listen address on both sides.
+		assert_eq!(expect_connected(&events_a), address_b);
+		assert_eq!(expect_connected(&events_b), address_a);
+
+		handle_b.send(&address_a, NetworkSyncMessage::NewBlockId { id: 1 });
+		assert_eq!(expect_new_block_id(&events_a), (address_b, 1));
+		handle_a.send(&address_b, NetworkSyncMessage::NewBlockId { id: 2 });
+		assert_eq!(expect_new_block_id(&events_b), (address_a, 2));
+
+		// Banned peers are not sent to.
+		handle_a.ban_peer(&address_b);
+		handle_a.broadcast(NetworkSyncMessage::NewBlockId { id: 3 });
+		handle_a.unban_peer(&address_b);
+		handle_a.broadcast(NetworkSyncMessage::NewBlockId { id: 4 });
+		assert_eq!(expect_new_block_id(&events_b), (address_a, 4));
+
+		// Duplicate connections are dropped in favor of the existing one.
+		assert!(is_rejected(address_a, &address_b.to_string()));
+		assert!(is_rejected(address_b, &address_a.to_string()));
+		handle_a.send(&address_b, NetworkSyncMessage::NewBlockId { id: 5 });
+		assert_eq!(expect_new_block_id(&events_b), (address_a, 5));
+	}
+
+	#[test]
+	fn should_reject_unlisted_peers() {
+		let (listener, address) = bind();
+		let (_, unlisted) = bind();
+		let (listed_listener, listed) = bind();
+		drop(listed_listener);
+		let remote = SocketAddr::new("127.0.0.2".parse().unwrap(), listed.port());
+		let (_handle, events): (TestHandle, _) =
+			start_tcp_network(listener, address, vec![listed, remote]);
+
+		assert!(is_rejected(address, &unlisted.to_string()));
+		assert!(is_rejected(address, "not an address"));
+		// Declared addresses must match where the connection comes from.
+		assert!(is_rejected(address, &remote.to_string()));
+		assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+	}
+}
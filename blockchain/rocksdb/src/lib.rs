@@ -0,0 +1,679 @@
+//! RocksDB backend for blockchain.
+
+#![warn(missing_docs)]
+
+use std::{fmt, error as stderror};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use parity_codec::{Encode, Decode};
+use rocksdb::{DB, Options, WriteBatch};
+use blockchain::{Block, Auxiliary};
+use blockchain::backend::{
+	Store, ChainQuery, ChainSettlement, Operation, Committable, SharedCommittable,
+	OperationError,
+};
+
+const COLUMN_INFO: &str = "info";
+const COLUMN_BLOCKS: &str = "blocks";
+const COLUMN_STATES: &str = "states";
+const COLUMN_CANON_DEPTHS: &str = "canon_depths";
+const COLUMN_AUXILIARIES: &str = "auxiliaries";
+
+const COLUMNS: [&str; 5] = [
+	COLUMN_INFO, COLUMN_BLOCKS, COLUMN_STATES, COLUMN_CANON_DEPTHS, COLUMN_AUXILIARIES,
+];
+
+const KEY_HEAD: &[u8] = b"head";
+const KEY_GENESIS: &[u8] = b"genesis";
+
+#[derive(Debug)]
+/// RocksDB errors
+pub enum Error {
+	/// Invalid Operation
+	InvalidOperation,
+	/// Trying to import a block that is genesis
+	IsGenesis,
+	/// Query does not exist
+	NotExist,
+	/// Stored genesis does not match the given genesis
+	GenesisMismatch,
+	/// Invalid column family name
+	InvalidColumnFamily,
+	/// Stored data cannot be decoded
+	Corrupted,
+	/// RocksDB error
+	Backend(String),
+}
+
+impl OperationError for Error {
+	fn invalid_operation() -> Self {
+		Error::InvalidOperation
+	}
+
+	fn block_is_genesis() -> Self {
+		Error::IsGenesis
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl stderror::Error for Error { }
+
+impl From<rocksdb::Error> for Error {
+	fn from(err: rocksdb::Error) -> Self {
+		Error::Backend(err.into_string())
+	}
+}
+
+fn decode<T: Decode>(bytes: &[u8]) -> Result<T, Error> {
+	T::decode(&mut &bytes[..]).ok_or(Error::Corrupted)
+}
+
+/// Database backed by RocksDB.
+///
+/// Writes issued through `ChainSettlement` are kept in an overlay, and only
+/// reach the disk atomically when the database is flushed.
+pub struct RocksDatabase<B: Block, A: Auxiliary<B>, S> {
+	db: DB,
+	head: B::Identifier,
+	genesis: B::Identifier,
+	pending_head: Option<B::Identifier>,
+	overlay: HashMap<(&'static str, Vec<u8>), Option<Vec<u8>>>,
+	_marker: PhantomData<(A, S)>,
+}
+
+impl<B: Block, A: Auxiliary<B>, S> RocksDatabase<B, A, S> where
+	B::Identifier: Encode + Decode,
+{
+	fn read(&self, column: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		if let Some(value) = self.overlay.get(&(column, key.to_vec())) {
+			return Ok(value.clone())
+		}
+
+		let cf = self.db.cf_handle(column).ok_or(Error::InvalidColumnFamily)?;
+		Ok(self.db.get_cf(cf, key)?.map(|value| value.to_vec()))
+	}
+
+	fn write(&mut self, column: &'static str, key: Vec<u8>, value: Option<Vec<u8>>) {
+		self.overlay.insert((column, key), value);
+	}
+
+	fn block_data(
+		&self,
+		id: &B::Identifier,
+	) -> Result<(B, u64, Vec<B::Identifier>, bool), Error> where
+		B: Decode,
+	{
+		match self.read(COLUMN_BLOCKS, &id.encode())? {
+			Some(raw) => decode(&raw),
+			None => Err(Error::NotExist),
+		}
+	}
+
+	/// Write all pending changes to disk in one batch.
+	pub fn flush(&mut self) -> Result<(), Error> {
+		let mut batch = WriteBatch::default();
+		for ((column, key), value) in &self.overlay {
+			let cf = self.db.cf_handle(column).ok_or(Error::InvalidColumnFamily)?;
+			match value {
+				Some(value) => batch.put_cf(cf, key, value)?,
+				None => batch.delete_cf(cf, key)?,
+			}
+		}
+		if let Some(head) = &self.pending_head {
+			let cf = self.db.cf_handle(COLUMN_INFO).ok_or(Error::InvalidColumnFamily)?;
+			batch.put_cf(cf, KEY_HEAD, &head.encode())?;
+		}
+		self.db.write(batch)?;
+
+		self.overlay.clear();
+		if let Some(head) = self.pending_head.take() {
+			self.head = head;
+		}
+
+		Ok(())
+	}
+
+	/// Discard all pending changes.
+	pub fn discard(&mut self) {
+		self.overlay.clear();
+		self.pending_head = None;
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> Store for RocksDatabase<B, A, S> where
+	B::Identifier: Encode + Decode,
+{
+	type Block = B;
+	type State = S;
+	type Auxiliary = A;
+	type Error = Error;
+}
+
+impl<B: Block, A: Auxiliary<B>, S> ChainQuery for RocksDatabase<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	fn head(&self) -> B::Identifier {
+		self.pending_head.as_ref().unwrap_or(&self.head).clone()
+	}
+
+	fn genesis(&self) -> B::Identifier { self.genesis.clone() }
+
+	fn contains(
+		&self,
+		id: &B::Identifier
+	) -> Result<bool, Error> {
+		Ok(self.read(COLUMN_BLOCKS, &id.encode())?.is_some())
+	}
+
+	fn is_canon(
+		&self,
+		id: &B::Identifier
+	) -> Result<bool, Error> {
+		self.block_data(id).map(|data| data.3)
+	}
+
+	fn lookup_canon_depth(
+		&self,
+		depth: usize,
+	) -> Result<Option<B::Identifier>, Error> {
+		match self.read(COLUMN_CANON_DEPTHS, &(depth as u64).encode())? {
+			Some(raw) => Ok(Some(decode(&raw)?)),
+			None => Ok(None),
+		}
+	}
+
+	fn auxiliary(
+		&self,
+		key: &A::Key
+	) -> Result<Option<A>, Error> {
+		match self.read(COLUMN_AUXILIARIES, &key.encode())? {
+			Some(raw) => Ok(Some(decode(&raw)?)),
+			None => Ok(None),
+		}
+	}
+
+	fn children_at(
+		&self,
+		id: &B::Identifier,
+	) -> Result<Vec<B::Identifier>, Error> {
+		self.block_data(id).map(|data| data.2)
+	}
+
+	fn depth_at(
+		&self,
+		id: &B::Identifier
+	) -> Result<usize, Error> {
+		self.block_data(id).map(|data| data.1 as usize)
+	}
+
+	fn block_at(
+		&self,
+		id: &B::Identifier,
+	) -> Result<B, Error> {
+		self.block_data(id).map(|data| data.0)
+	}
+
+	fn state_at(
+		&self,
+		id: &B::Identifier,
+	) -> Result<Self::State, Error> {
+		match self.read(COLUMN_STATES, &id.encode())? {
+			Some(raw) => decode(&raw),
+			None => Err(Error::NotExist),
+		}
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> ChainSettlement for RocksDatabase<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	fn insert_block(
+		&mut self,
+		id: <Self::Block as Block>::Identifier,
+		block: Self::Block,
+		state: Self::State,
+		depth: usize,
+		children: Vec<<Self::Block as Block>::Identifier>,
+		is_canon: bool
+	) {
+		let key = id.encode();
+		self.write(COLUMN_STATES, key.clone(), Some(state.encode()));
+		self.write(COLUMN_BLOCKS, key, Some((block, depth as u64, children, is_canon).encode()));
+	}
+	fn push_child(
+		&mut self,
+		id: <Self::Block as Block>::Identifier,
+		child: <Self::Block as Block>::Identifier,
+	) {
+		let mut data = self.block_data(&id)
+			.expect("Internal database error");
+		data.2.push(child);
+		self.write(COLUMN_BLOCKS, id.encode(), Some(data.encode()));
+	}
+	fn set_canon(
+		&mut self,
+		id: <Self::Block as Block>::Identifier,
+		is_canon: bool
+	) {
+		let mut data = self.block_data(&id)
+			.expect("Internal database error");
+		data.3 = is_canon;
+		self.write(COLUMN_BLOCKS, id.encode(), Some(data.encode()));
+	}
+	fn insert_canon_depth_mapping(
+		&mut self,
+		depth: usize,
+		id: <Self::Block as Block>::Identifier,
+	) {
+		self.write(COLUMN_CANON_DEPTHS, (depth as u64).encode(), Some(id.encode()));
+	}
+	fn remove_canon_depth_mapping(
+		&mut self,
+		depth: &usize
+	) {
+		self.write(COLUMN_CANON_DEPTHS, (*depth as u64).encode(), None);
+	}
+	fn insert_auxiliary(
+		&mut self,
+		key: <Self::Auxiliary as Auxiliary<Self::Block>>::Key,
+		value: Self::Auxiliary
+	) {
+		self.write(COLUMN_AUXILIARIES, key.encode(), Some(value.encode()));
+	}
+	fn remove_auxiliary(
+		&mut self,
+		key: &<Self::Auxiliary as Auxiliary<Self::Block>>::Key,
+	) {
+		self.write(COLUMN_AUXILIARIES, key.encode(), None);
+	}
+	fn set_head(
+		&mut self,
+		head: <Self::Block as Block>::Identifier
+	) {
+		self.pending_head = Some(head);
+	}
+}
+
+/// RocksDB backend
+pub struct RocksBackend<B: Block, A: Auxiliary<B>, S>(RocksDatabase<B, A, S>);
+
+impl<B: Block, A: Auxiliary<B>, S> RocksBackend<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	/// Open a RocksDB backend at the given path. If the database is empty, it
+	/// is initialized with the genesis block. Otherwise the stored genesis must
+	/// match the given one, and the backend resumes from the stored head.
+	pub fn open_with_genesis<P: AsRef<Path>>(
+		path: P,
+		block: B,
+		genesis_state: S
+	) -> Result<Self, Error> {
+		assert!(block.parent_id().is_none(), "with_genesis must be provided with a genesis block");
+
+		let mut options = Options::default();
+		options.create_if_missing(true);
+		options.create_missing_column_families(true);
+		let db = DB::open_cf(&options, path, &COLUMNS)?;
+
+		let genesis_id = block.id();
+		let stored = {
+			let cf = db.cf_handle(COLUMN_INFO).ok_or(Error::InvalidColumnFamily)?;
+			match (db.get_cf(cf, KEY_GENESIS)?, db.get_cf(cf, KEY_HEAD)?) {
+				(Some(genesis), Some(head)) => Some((
+					decode::<B::Identifier>(&genesis)?,
+					decode::<B::Identifier>(&head)?,
+				)),
+				_ => None,
+			}
+		};
+
+		match stored {
+			Some((genesis, head)) => {
+				if genesis != genesis_id {
+					return Err(Error::GenesisMismatch)
+				}
+
+				Ok(Self(RocksDatabase {
+					db,
+					head,
+					genesis,
+					pending_head: None,
+					overlay: Default::default(),
+					_marker: PhantomData,
+				}))
+			},
+			None => {
+				let mut database = RocksDatabase {
+					db,
+					head: genesis_id.clone(),
+					genesis: genesis_id.clone(),
+					pending_head: Some(genesis_id.clone()),
+					overlay: Default::default(),
+					_marker: PhantomData,
+				};
+				database.write(COLUMN_INFO, KEY_GENESIS.to_vec(), Some(genesis_id.encode()));
+				database.insert_block(
+					genesis_id.clone(), block, genesis_state, 0, Vec::new(), true
+				);
+				database.insert_canon_depth_mapping(0, genesis_id);
+				database.flush()?;
+
+				Ok(Self(database))
+			},
+		}
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> Store for RocksBackend<B, A, S> where
+	B::Identifier: Encode + Decode,
+{
+	type Block = B;
+	type State = S;
+	type Auxiliary = A;
+	type Error = Error;
+}
+
+impl<B: Block, A: Auxiliary<B>, S> ChainQuery for RocksBackend<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	fn genesis(&self) -> <Self::Block as Block>::Identifier {
+		self.0.genesis()
+	}
+	fn head(&self) -> <Self::Block as Block>::Identifier {
+		self.0.head()
+	}
+	fn contains(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		Ok(self.0.contains(hash)?)
+	}
+	fn is_canon(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		Ok(self.0.is_canon(hash)?)
+	}
+	fn lookup_canon_depth(
+		&self,
+		depth: usize,
+	) -> Result<Option<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.lookup_canon_depth(depth)?)
+	}
+	fn auxiliary(
+		&self,
+		key: &<Self::Auxiliary as Auxiliary<Self::Block>>::Key,
+	) -> Result<Option<Self::Auxiliary>, Self::Error> {
+		Ok(self.0.auxiliary(key)?)
+	}
+	fn depth_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<usize, Self::Error> {
+		Ok(self.0.depth_at(hash)?)
+	}
+	fn children_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.children_at(hash)?)
+	}
+	fn state_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Self::State, Self::Error> {
+		Ok(self.0.state_at(hash)?)
+	}
+	fn block_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Self::Block, Self::Error> {
+		Ok(self.0.block_at(hash)?)
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> Committable for RocksBackend<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	type Operation = Operation<Self::Block, Self::State, Self::Auxiliary>;
+
+	fn commit(
+		&mut self,
+		operation: Operation<Self::Block, Self::State, Self::Auxiliary>,
+	) -> Result<(), Self::Error> {
+		match operation.settle(&mut self.0) {
+			Ok(()) => self.0.flush().map_err(|e| {
+				self.0.discard();
+				e
+			}),
+			Err(e) => {
+				self.0.discard();
+				Err(e)
+			},
+		}
+	}
+}
+
+/// Shared RocksDB backend
+pub struct SharedRocksBackend<B: Block, A: Auxiliary<B>, S>(
+	Arc<RwLock<RocksBackend<B, A, S>>>
+);
+
+impl<B: Block, A: Auxiliary<B>, S> SharedRocksBackend<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	/// Open a RocksDB backend at the given path, initializing it from genesis
+	/// if it is empty.
+	pub fn open_with_genesis<P: AsRef<Path>>(
+		path: P,
+		block: B,
+		genesis_state: S
+	) -> Result<Self, Error> {
+		Ok(Self(Arc::new(RwLock::new(RocksBackend::open_with_genesis(path, block, genesis_state)?))))
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> Store for SharedRocksBackend<B, A, S> where
+	B::Identifier: Encode + Decode,
+{
+	type Block = B;
+	type State = S;
+	type Auxiliary = A;
+	type Error = Error;
+}
+
+impl<B: Block, A: Auxiliary<B>, S> ChainQuery for SharedRocksBackend<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	fn genesis(&self) -> <Self::Block as Block>::Identifier {
+		self.0.read().expect("Lock is poisoned").genesis()
+	}
+	fn head(&self) -> <Self::Block as Block>::Identifier {
+		self.0.read().expect("Lock is poisoned").head()
+	}
+	fn contains(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").contains(hash)?)
+	}
+	fn is_canon(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").is_canon(hash)?)
+	}
+	fn lookup_canon_depth(
+		&self,
+		depth: usize,
+	) -> Result<Option<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").lookup_canon_depth(depth)?)
+	}
+	fn auxiliary(
+		&self,
+		key: &<Self::Auxiliary as Auxiliary<Self::Block>>::Key,
+	) -> Result<Option<Self::Auxiliary>, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").auxiliary(key)?)
+	}
+	fn depth_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<usize, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").depth_at(hash)?)
+	}
+	fn children_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").children_at(hash)?)
+	}
+	fn state_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Self::State, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").state_at(hash)?)
+	}
+	fn block_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Self::Block, Self::Error> {
+		Ok(self.0.read().expect("Lock is poisoned").block_at(hash)?)
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> Clone for SharedRocksBackend<B, A, S> {
+	fn clone(&self) -> Self {
+		Self(self.0.clone())
+	}
+}
+
+impl<B: Block, A: Auxiliary<B>, S> SharedCommittable for SharedRocksBackend<B, A, S> where
+	B: Encode + Decode,
+	B::Identifier: Encode + Decode,
+	A: Encode + Decode,
+	A::Key: Encode,
+	S: Encode + Decode,
+{
+	type Operation = Operation<Self::Block, Self::State, Self::Auxiliary>;
+
+	fn commit(
+		&self,
+		operation: Operation<Self::Block, Self::State, Self::Auxiliary>,
+	) -> Result<(), Self::Error> {
+		self.0.write().expect("Lock is poisoned").commit(operation)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use blockchain::backend::ImportOperation;
+
+	#[derive(Clone, Debug, Eq, PartialEq, Encode, Decode)]
+	struct TestBlock {
+		id: u64,
+		parent_id: Option<u64>,
+	}
+
+	impl Block for TestBlock {
+		type Identifier = u64;
+
+		fn id(&self) -> u64 { self.id }
+		fn parent_id(&self) -> Option<u64> { self.parent_id }
+	}
+
+	fn import(backend: &mut RocksBackend<TestBlock, (), u64>, id: u64, parent_id: u64, set_head: bool) {
+		let mut operation = Operation::default();
+		operation.import_block.push(ImportOperation {
+			block: TestBlock { id, parent_id: Some(parent_id) },
+			state: id * 10,
+		});
+		if set_head {
+			operation.set_head = Some(id);
+		}
+		backend.commit(operation).unwrap();
+	}
+
+	#[test]
+	fn should_resume_from_stored_head() {
+		let dbdir = tempfile::tempdir().unwrap();
+		let path = dbdir.path().join("chain");
+		let genesis = TestBlock { id: 0, parent_id: None };
+
+		{
+			let mut backend = RocksBackend::<_, (), u64>::open_with_genesis(
+				&path, genesis.clone(), 0
+			).unwrap();
+			import(&mut backend, 1, 0, true);
+			import(&mut backend, 2, 1, true);
+			import(&mut backend, 3, 0, false);
+			import(&mut backend, 4, 3, false);
+			import(&mut backend, 5, 4, true);
+		}
+
+		let backend = RocksBackend::<_, (), u64>::open_with_genesis(
+			&path, genesis, 0
+		).unwrap();
+		assert_eq!(backend.genesis(), 0);
+		assert_eq!(backend.head(), 5);
+		assert_eq!(backend.depth_at(&5).unwrap(), 3);
+		assert_eq!(backend.state_at(&4).unwrap(), 40);
+		assert_eq!(backend.children_at(&0).unwrap().len(), 2);
+		assert!(!backend.is_canon(&1).unwrap());
+		assert!(!backend.is_canon(&2).unwrap());
+		assert!(backend.is_canon(&3).unwrap());
+		assert_eq!(backend.lookup_canon_depth(1).unwrap(), Some(3));
+		assert_eq!(backend.lookup_canon_depth(3).unwrap(), Some(5));
+	}
+
+	#[test]
+	fn should_reject_mismatched_genesis() {
+		let dbdir = tempfile::tempdir().unwrap();
+		let path = dbdir.path().join("chain");
+
+		RocksBackend::<_, (), u64>::open_with_genesis(
+			&path, TestBlock { id: 0, parent_id: None }, 0
+		).unwrap();
+		match RocksBackend::<_, (), u64>::open_with_genesis(
+			&path, TestBlock { id: 1, parent_id: None }, 0
+		) {
+			Err(Error::GenesisMismatch) => (),
+			_ => panic!("Expected genesis mismatch"),
+		}
+	}
+}
@@ -5,3 +5,4 @@
 pub use blockchain_core::*;
 pub mod import;
 pub mod backend;
+pub mod pool;
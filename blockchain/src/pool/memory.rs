@@ -0,0 +1,338 @@
+use std::{fmt, error as stderror};
+use std::collections::{HashMap, BTreeMap};
+use super::{ExtrinsicValidator, Validity};
+
+#[derive(Debug)]
+/// Pool errors
+pub enum Error<E> {
+	/// Extrinsic is already in the pool
+	AlreadyImported,
+	/// Another extrinsic of the same sender and nonce is in the pool
+	NonceConflict,
+	/// Pool is full
+	Full,
+	/// Extrinsic is invalid
+	Invalid(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl<E: fmt::Debug> stderror::Error for Error<E> { }
+
+struct Entry<V: ExtrinsicValidator> {
+	extrinsic: V::Extrinsic,
+	validity: Validity<V::Hash, V::Sender>,
+	order: u64,
+	propagated: bool,
+}
+
+/// Transaction pool, ordering extrinsics of the same sender by nonce.
+pub struct Pool<V: ExtrinsicValidator> {
+	validator: V,
+	entries: HashMap<V::Hash, Entry<V>>,
+	senders: HashMap<V::Sender, BTreeMap<u64, V::Hash>>,
+	next_order: u64,
+	bytes: usize,
+	max_count: usize,
+	max_bytes: usize,
+}
+
+impl<V: ExtrinsicValidator> Pool<V> {
+	/// Create a new pool bounded by extrinsic count and total bytes.
+	pub fn new(validator: V, max_count: usize, max_bytes: usize) -> Self {
+		Self {
+			validator,
+			entries: HashMap::new(),
+			senders: HashMap::new(),
+			next_order: 0,
+			bytes: 0,
+			max_count,
+			max_bytes,
+		}
+	}
+
+	/// Number of extrinsics in the pool.
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Total size of extrinsics in the pool.
+	pub fn bytes(&self) -> usize {
+		self.bytes
+	}
+
+	/// Whether the pool contains an extrinsic.
+	pub fn contains(&self, hash: &V::Hash) -> bool {
+		self.entries.contains_key(hash)
+	}
+
+	/// Validate and submit an extrinsic into the pool.
+	pub fn submit(
+		&mut self,
+		extrinsic: V::Extrinsic
+	) -> Result<V::Hash, Error<V::Error>> {
+		if self.contains(&self.validator.hash(&extrinsic)) {
+			return Err(Error::AlreadyImported)
+		}
+
+		let validity = self.validator.validate(&extrinsic).map_err(Error::Invalid)?;
+		if self.contains(&validity.hash) {
+			return Err(Error::AlreadyImported)
+		}
+		if self.senders.get(&validity.sender)
+			.map(|nonces| nonces.contains_key(&validity.nonce))
+			.unwrap_or(false)
+		{
+			return Err(Error::NonceConflict)
+		}
+		if self.entries.len() + 1 > self.max_count || self.bytes + validity.size > self.max_bytes {
+			return Err(Error::Full)
+		}
+
+		let hash = validity.hash.clone();
+		self.senders.entry(validity.sender.clone()).or_default()
+			.insert(validity.nonce, hash.clone());
+		self.bytes += validity.size;
+		self.entries.insert(hash.clone(), Entry {
+			extrinsic, validity,
+			order: self.next_order,
+			propagated: false,
+		});
+		self.next_order += 1;
+
+		Ok(hash)
+	}
+
+	/// Extrinsics ready for block building. Extrinsics of the same sender are
+	/// returned in nonce order, and otherwise in submission order.
+	pub fn ready(&self) -> Vec<V::Extrinsic> {
+		let mut entries = self.entries.values().collect::<Vec<_>>();
+		entries.sort_by_key(|entry| (entry.validity.nonce, entry.order));
+		entries.into_iter().map(|entry| entry.extrinsic.clone()).collect()
+	}
+
+	/// Extrinsics that have not yet been propagated to peers. They are marked
+	/// as propagated.
+	pub fn take_unpropagated(&mut self) -> Vec<V::Extrinsic> {
+		let mut entries = self.entries.values_mut()
+			.filter(|entry| !entry.propagated)
+			.collect::<Vec<_>>();
+		entries.sort_by_key(|entry| entry.order);
+		entries.into_iter().map(|entry| {
+			entry.propagated = true;
+			entry.extrinsic.clone()
+		}).collect()
+	}
+
+	/// Remove an extrinsic from the pool.
+	pub fn remove(&mut self, hash: &V::Hash) -> Option<V::Extrinsic> {
+		let entry = self.entries.remove(hash)?;
+		self.bytes -= entry.validity.size;
+
+		let is_empty = self.senders.get_mut(&entry.validity.sender).map(|nonces| {
+			nonces.remove(&entry.validity.nonce);
+			nonces.is_empty()
+		}).unwrap_or(false);
+		if is_empty {
+			self.senders.remove(&entry.validity.sender);
+		}
+
+		Some(entry.extrinsic)
+	}
+
+	/// Update the pool after the head changed. Extrinsics in enacted blocks
+	/// are removed, the rest are revalidated against the new head, and
+	/// extrinsics in retracted blocks are resubmitted.
+	pub fn update(
+		&mut self,
+		enacted: &[V::Extrinsic],
+		retracted: Vec<V::Extrinsic>,
+	) {
+		for extrinsic in enacted {
+			self.remove(&self.validator.hash(extrinsic));
+		}
+
+		let invalid = self.entries.iter()
+			.filter(|(_, entry)| match self.validator.validate(&entry.extrinsic) {
+				Ok(validity) => validity != entry.validity,
+				Err(_) => true,
+			})
+			.map(|(hash, _)| hash.clone())
+			.collect::<Vec<_>>();
+		for hash in invalid {
+			self.remove(&hash);
+		}
+
+		for extrinsic in retracted {
+			let _ = self.submit(extrinsic);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	#[derive(Clone, Debug, Eq, PartialEq)]
+	struct TestExtrinsic {
+		id: u64,
+		sender: u64,
+		nonce: u64,
+		size: usize,
+	}
+
+	fn extrinsic(id: u64, sender: u64, nonce: u64) -> TestExtrinsic {
+		TestExtrinsic { id, sender, nonce, size: 10 }
+	}
+
+	#[derive(Debug)]
+	struct StaleNonce;
+
+	impl fmt::Display for StaleNonce {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "{:?}", self)
+		}
+	}
+
+	impl stderror::Error for StaleNonce { }
+
+	/// Validator rejecting nonces below a minimum shared by all senders.
+	#[derive(Default)]
+	struct TestValidator {
+		min_nonce: Cell<u64>,
+	}
+
+	impl ExtrinsicValidator for TestValidator {
+		type Extrinsic = TestExtrinsic;
+		type Hash = u64;
+		type Sender = u64;
+		type Error = StaleNonce;
+
+		fn hash(&self, extrinsic: &TestExtrinsic) -> u64 {
+			extrinsic.id
+		}
+
+		fn validate(&self, extrinsic: &TestExtrinsic) -> Result<Validity<u64, u64>, StaleNonce> {
+			if extrinsic.nonce < self.min_nonce.get() {
+				return Err(StaleNonce)
+			}
+
+			Ok(Validity {
+				hash: extrinsic.id,
+				sender: extrinsic.sender,
+				nonce: extrinsic.nonce,
+				size: extrinsic.size,
+			})
+		}
+	}
+
+	#[test]
+	fn should_submit_and_deduplicate() {
+		let mut pool = Pool::new(TestValidator::default(), 16, 1024);
+
+		assert_eq!(pool.submit(extrinsic(1, 1, 0)).unwrap(), 1);
+		assert!(pool.contains(&1));
+		match pool.submit(extrinsic(1, 1, 0)) {
+			Err(Error::AlreadyImported) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		assert_eq!(pool.len(), 1);
+		assert_eq!(pool.bytes(), 10);
+
+		assert_eq!(pool.remove(&1), Some(extrinsic(1, 1, 0)));
+		assert_eq!(pool.len(), 0);
+		assert_eq!(pool.bytes(), 0);
+		assert_eq!(pool.submit(extrinsic(1, 1, 0)).unwrap(), 1);
+	}
+
+	#[test]
+	fn should_reject_invalid_and_conflicting_nonces() {
+		let validator = TestValidator::default();
+		validator.min_nonce.set(1);
+		let mut pool = Pool::new(validator, 16, 1024);
+
+		match pool.submit(extrinsic(1, 1, 0)) {
+			Err(Error::Invalid(StaleNonce)) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+
+		pool.submit(extrinsic(2, 1, 1)).unwrap();
+		match pool.submit(extrinsic(3, 1, 1)) {
+			Err(Error::NonceConflict) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		// Nonces are per sender.
+		pool.submit(extrinsic(4, 2, 1)).unwrap();
+		assert_eq!(pool.len(), 2);
+	}
+
+	#[test]
+	fn should_reject_over_limits() {
+		let mut pool = Pool::new(TestValidator::default(), 2, 1024);
+		pool.submit(extrinsic(1, 1, 0)).unwrap();
+		pool.submit(extrinsic(2, 1, 1)).unwrap();
+		match pool.submit(extrinsic(3, 1, 2)) {
+			Err(Error::Full) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+
+		let mut pool = Pool::new(TestValidator::default(), 16, 25);
+		pool.submit(extrinsic(1, 1, 0)).unwrap();
+		pool.submit(extrinsic(2, 1, 1)).unwrap();
+		match pool.submit(extrinsic(3, 1, 2)) {
+			Err(Error::Full) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		pool.submit(TestExtrinsic { id: 4, sender: 1, nonce: 2, size: 5 }).unwrap();
+		assert_eq!(pool.bytes(), 25);
+	}
+
+	#[test]
+	fn should_order_ready_by_nonce_then_submission() {
+		let mut pool = Pool::new(TestValidator::default(), 16, 1024);
+		pool.submit(extrinsic(1, 1, 1)).unwrap();
+		pool.submit(extrinsic(2, 2, 0)).unwrap();
+		pool.submit(extrinsic(3, 1, 0)).unwrap();
+
+		assert_eq!(
+			pool.ready().into_iter().map(|extrinsic| extrinsic.id).collect::<Vec<_>>(),
+			vec![2, 3, 1],
+		);
+
+		assert_eq!(
+			pool.take_unpropagated().into_iter().map(|extrinsic| extrinsic.id).collect::<Vec<_>>(),
+			vec![1, 2, 3],
+		);
+		assert!(pool.take_unpropagated().is_empty());
+	}
+
+	#[test]
+	fn should_revalidate_on_update() {
+		let mut pool = Pool::new(TestValidator::default(), 16, 1024);
+		pool.submit(extrinsic(1, 1, 0)).unwrap();
+		pool.submit(extrinsic(2, 2, 0)).unwrap();
+		pool.submit(extrinsic(3, 2, 1)).unwrap();
+
+		// Extrinsic 1 is enacted, making extrinsic 2 stale as well.
+		pool.validator.min_nonce.set(1);
+		pool.update(&[extrinsic(1, 1, 0)], Vec::new());
+		assert!(!pool.contains(&1));
+		assert!(!pool.contains(&2));
+		assert!(pool.contains(&3));
+		assert_eq!(pool.bytes(), 10);
+
+		// Retracted extrinsics are resubmitted if still valid.
+		pool.validator.min_nonce.set(0);
+		pool.update(&[], vec![extrinsic(1, 1, 0), extrinsic(2, 2, 0)]);
+		assert_eq!(pool.len(), 3);
+		assert_eq!(
+			pool.ready().into_iter().map(|extrinsic| extrinsic.id).collect::<Vec<_>>(),
+			vec![1, 2, 3],
+		);
+	}
+}
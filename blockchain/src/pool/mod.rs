@@ -0,0 +1,54 @@
+//! Transaction pool.
+
+mod memory;
+mod traits;
+
+pub use self::memory::{Pool, Error};
+pub use self::traits::{ExtrinsicValidator, Validity};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A transaction pool that can be shared across threads.
+pub struct SharedPool<V: ExtrinsicValidator> {
+	pool: Arc<Mutex<Pool<V>>>,
+}
+
+impl<V: ExtrinsicValidator> SharedPool<V> {
+	/// Create a new shared pool bounded by extrinsic count and total bytes.
+	pub fn new(validator: V, max_count: usize, max_bytes: usize) -> Self {
+		Self {
+			pool: Arc::new(Mutex::new(Pool::new(validator, max_count, max_bytes))),
+		}
+	}
+
+	/// Lock the pool.
+	pub fn lock(&self) -> MutexGuard<Pool<V>> {
+		self.pool.lock().expect("Lock is poisoned")
+	}
+
+	/// Validate and submit an extrinsic into the pool.
+	pub fn submit(
+		&self,
+		extrinsic: V::Extrinsic
+	) -> Result<V::Hash, Error<V::Error>> {
+		self.lock().submit(extrinsic)
+	}
+
+	/// Extrinsics ready for block building.
+	pub fn ready(&self) -> Vec<V::Extrinsic> {
+		self.lock().ready()
+	}
+
+	/// Remove an extrinsic from the pool.
+	pub fn remove(&self, hash: &V::Hash) -> Option<V::Extrinsic> {
+		self.lock().remove(hash)
+	}
+}
+
+impl<V: ExtrinsicValidator> Clone for SharedPool<V> {
+	fn clone(&self) -> Self {
+		Self {
+			pool: self.pool.clone(),
+		}
+	}
+}
@@ -0,0 +1,35 @@
+use std::error as stderror;
+use std::hash;
+
+/// Validity information of an extrinsic.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Validity<H, S> {
+	/// Hash of the extrinsic, used for deduplication.
+	pub hash: H,
+	/// Sender of the extrinsic.
+	pub sender: S,
+	/// Nonce of the extrinsic, ordering extrinsics of the same sender.
+	pub nonce: u64,
+	/// Encoded size of the extrinsic.
+	pub size: usize,
+}
+
+/// Validator of extrinsics against the current head state.
+pub trait ExtrinsicValidator {
+	/// Extrinsic type
+	type Extrinsic: Clone;
+	/// Hash type of the extrinsic
+	type Hash: Clone + Eq + hash::Hash;
+	/// Sender type of the extrinsic
+	type Sender: Clone + Eq + hash::Hash;
+	/// Error type
+	type Error: stderror::Error + 'static;
+
+	/// Get the hash of an extrinsic.
+	fn hash(&self, extrinsic: &Self::Extrinsic) -> Self::Hash;
+	/// Validate an extrinsic against the current head state.
+	fn validate(
+		&self,
+		extrinsic: &Self::Extrinsic
+	) -> Result<Validity<Self::Hash, Self::Sender>, Self::Error>;
+}
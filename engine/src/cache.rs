@@ -0,0 +1,126 @@
+use crate::{Error, ExecutionLimits, Instance, Metadata};
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Default number of instances kept by a cache.
+pub const DEFAULT_CACHE_SIZE: usize = 8;
+
+/// Hit and miss counters of an instance cache.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+	pub hits: u64,
+	pub misses: u64,
+}
+
+/// Runtime code prepared for execution, which can be kept in a cache.
+pub trait CachedInstance: Sized {
+	/// Context shared by all instances of a cache.
+	type Context;
+
+	fn prepare(
+		context: &Self::Context,
+		code: Arc<Vec<u8>>,
+		limits: ExecutionLimits,
+	) -> Result<Self, Error>;
+}
+
+impl CachedInstance for Instance {
+	type Context = ();
+
+	fn prepare(_: &(), code: Arc<Vec<u8>>, limits: ExecutionLimits) -> Result<Self, Error> {
+		Instance::new_with_limits(code, limits)
+	}
+}
+
+/// Bounded cache of instances keyed by code hash, evicting the least
+/// recently used instance when full.
+pub struct Cache<I: CachedInstance = Instance> {
+	instances: HashMap<Vec<u8>, (I, u64)>,
+	context: I::Context,
+	capacity: usize,
+	limits: ExecutionLimits,
+	tick: u64,
+	stats: CacheStats,
+}
+
+impl Default for Cache {
+	fn default() -> Self {
+		Self::new(DEFAULT_CACHE_SIZE)
+	}
+}
+
+impl Cache {
+	pub fn new(capacity: usize) -> Self {
+		Self::new_with_limits(capacity, ExecutionLimits::default())
+	}
+
+	pub fn new_with_limits(capacity: usize, limits: ExecutionLimits) -> Self {
+		Self::with_context((), capacity, limits)
+	}
+
+	pub fn execute(&mut self, block: &[u8], code: &[u8]) -> Result<Metadata, Error> {
+		self.instance(code)?.execute(block)
+	}
+}
+
+impl<I: CachedInstance> Cache<I> {
+	/// Create a cache preparing instances with the given context.
+	pub fn with_context(context: I::Context, capacity: usize, limits: ExecutionLimits) -> Self {
+		assert!(capacity > 0, "Cache must hold at least one instance");
+
+		Self {
+			instances: HashMap::new(),
+			tick: 0,
+			stats: CacheStats::default(),
+			context, capacity, limits,
+		}
+	}
+
+	/// Get the instance of code, preparing it if it is not cached.
+	pub fn instance(&mut self, code: &[u8]) -> Result<&I, Error> {
+		let hash = Sha3_256::digest(code).to_vec();
+		self.tick += 1;
+
+		if self.instances.contains_key(&hash) {
+			self.stats.hits += 1;
+		} else {
+			self.stats.misses += 1;
+			let instance = I::prepare(
+				&self.context,
+				Arc::new(code.to_vec()),
+				self.limits.clone(),
+			)?;
+			if self.instances.len() >= self.capacity {
+				self.evict();
+			}
+			self.instances.insert(hash.clone(), (instance, 0));
+		}
+
+		let (instance, last_used) = self.instances.get_mut(&hash)
+			.expect("Instance was inserted above");
+		*last_used = self.tick;
+		Ok(instance)
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		self.stats
+	}
+
+	pub fn len(&self) -> usize {
+		self.instances.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.instances.is_empty()
+	}
+
+	fn evict(&mut self) {
+		let oldest = self.instances.iter()
+			.min_by_key(|(_, (_, last_used))| *last_used)
+			.map(|(hash, _)| hash.clone());
+		if let Some(oldest) = oldest {
+			self.instances.remove(&oldest);
+		}
+	}
+}
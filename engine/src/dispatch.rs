@@ -0,0 +1,71 @@
+use crate::{Instance, RuntimeVersion};
+use crate::cache::DEFAULT_CACHE_SIZE;
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Executor picked for a runtime.
+pub enum Dispatch<'a, N, W> {
+	/// The runtime has the same version as the native executor.
+	Native(&'a N),
+	/// The runtime has a different version, or none at all.
+	Wasm(&'a W),
+}
+
+/// Dispatcher between a native executor and a wasm executor, picking the
+/// native one only for runtimes reporting exactly its version.
+pub struct Dispatcher<N, W> {
+	native: N,
+	native_version: RuntimeVersion,
+	wasm: W,
+	versions: Mutex<HashMap<Vec<u8>, Option<RuntimeVersion>>>,
+}
+
+impl<N, W> Dispatcher<N, W> {
+	pub fn new(native: N, native_version: RuntimeVersion, wasm: W) -> Self {
+		Self {
+			native, native_version, wasm,
+			versions: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn native(&self) -> &N {
+		&self.native
+	}
+
+	pub fn wasm(&self) -> &W {
+		&self.wasm
+	}
+
+	/// Version reported by code. `None` if the code does not export a
+	/// version or cannot be instantiated.
+	pub fn version(&self, code: &[u8]) -> Option<RuntimeVersion> {
+		let hash = Sha3_256::digest(code).to_vec();
+		let mut versions = self.versions.lock().expect("Lock is poisoned");
+
+		if let Some(version) = versions.get(&hash) {
+			return version.clone()
+		}
+
+		let version = Instance::new(Arc::new(code.to_vec()))
+			.and_then(|instance| instance.version())
+			.ok()
+			.and_then(|version| version);
+		// Runtime upgrades are rare, so only a few versions are ever looked
+		// up. Just start over if it grows.
+		if versions.len() >= DEFAULT_CACHE_SIZE {
+			versions.clear();
+		}
+		versions.insert(hash, version.clone());
+		version
+	}
+
+	/// Pick the executor for code.
+	pub fn dispatch(&self, code: &[u8]) -> Dispatch<N, W> {
+		if self.version(code).as_ref() == Some(&self.native_version) {
+			Dispatch::Native(&self.native)
+		} else {
+			Dispatch::Wasm(&self.wasm)
+		}
+	}
+}
@@ -1,5 +1,9 @@
-use crate::{Error, Instance};
-use std::sync::Arc;
+use crate::{
+	Error, Cache, CacheStats, CachedInstance, ExecutionLimits, Metadata, Trace,
+	ValidationError, validate_code,
+};
+use crate::cache::DEFAULT_CACHE_SIZE;
+use std::sync::Mutex;
 use blockchain::BlockExecutor;
 use metadata::GenericBlock;
 
@@ -8,8 +12,56 @@ pub trait CodeExternalities {
 	fn code_mut(&mut self) -> &mut Vec<u8>;
 }
 
-#[derive(Default)]
-pub struct Executor;
+/// Block executor reusing instances of recently executed code.
+pub struct Executor {
+	cache: Mutex<Cache>,
+}
+
+impl Default for Executor {
+	fn default() -> Self {
+		Self::new(DEFAULT_CACHE_SIZE)
+	}
+}
+
+impl Executor {
+	/// Create an executor caching at most `cache_size` instances.
+	pub fn new(cache_size: usize) -> Self {
+		Self::new_with_limits(cache_size, ExecutionLimits::default())
+	}
+
+	pub fn new_with_limits(cache_size: usize, limits: ExecutionLimits) -> Self {
+		Self { cache: Mutex::new(Cache::new_with_limits(cache_size, limits)) }
+	}
+
+	pub fn cache_stats(&self) -> CacheStats {
+		self.cache.lock().expect("Lock is poisoned").stats()
+	}
+
+	/// Execute a block as `execute_block` does, while recording a trace of
+	/// the execution.
+	pub fn execute_block_traced(
+		&self,
+		block: &GenericBlock,
+		state: &mut dyn CodeExternalities,
+	) -> (Result<(), Error>, Trace) {
+		let mut cache = self.cache.lock().expect("Lock is poisoned");
+		let (metadata, mut trace) = match cache.instance(state.code()) {
+			Ok(instance) => instance.execute_traced(&block.data, Some(&*state)),
+			Err(err) => {
+				let trace = Trace { error: Some(err.to_string()), ..Default::default() };
+				return (Err(err), trace)
+			},
+		};
+
+		let result = metadata.and_then(|metadata| {
+			apply_metadata(block, state, metadata, &mut *cache)
+		});
+		if let Err(ref err) = result {
+			trace.error = Some(err.to_string());
+		}
+		(result, trace)
+	}
+}
 
 impl BlockExecutor for Executor {
 	type Error = Error;
@@ -21,18 +73,38 @@ impl BlockExecutor for Executor {
 		block: &Self::Block,
 		state: &mut Self::Externalities,
 	) -> Result<(), Error> {
-		let instance = Instance::new(Arc::new(state.code().to_vec()))?;
-		let metadata = instance.execute(&block.data)?;
-
-		if metadata.id != block.id ||
-			Some(metadata.parent_id) != block.parent_id ||
-			metadata.difficulty != block.difficulty ||
-			metadata.timestamp != block.timestamp
-		{
-			return Err(Error::InvalidMetadata)
-		}
-		*state.code_mut() = metadata.code.clone();
+		let mut cache = self.cache.lock().expect("Lock is poisoned");
+		let metadata = cache.instance(state.code())?
+			.execute_with_code(&block.data, &*state)?;
 
-		Ok(())
+		apply_metadata(block, state, metadata, &mut *cache)
 	}
 }
+
+/// Check metadata returned by the runtime against the block, and apply the
+/// code it returns to the state. New code is prepared in the cache of the
+/// executor before being applied, so that an upgrade to code that cannot be
+/// executed under the executor's limits is rejected.
+pub(crate) fn apply_metadata<I: CachedInstance>(
+	block: &GenericBlock,
+	state: &mut dyn CodeExternalities,
+	metadata: Metadata,
+	cache: &mut Cache<I>,
+) -> Result<(), Error> {
+	if metadata.id != block.id ||
+		Some(metadata.parent_id) != block.parent_id ||
+		metadata.difficulty != block.difficulty ||
+		metadata.timestamp != block.timestamp
+	{
+		return Err(Error::InvalidMetadata)
+	}
+	if &metadata.code != state.code() {
+		validate_code(&metadata.code).map_err(Error::InvalidRuntimeCode)?;
+		cache.instance(&metadata.code).map_err(|err| {
+			Error::InvalidRuntimeCode(ValidationError::Instantiation(err.to_string()))
+		})?;
+	}
+	*state.code_mut() = metadata.code;
+
+	Ok(())
+}
@@ -0,0 +1,455 @@
+use crate::CodeExternalities;
+use std::fmt;
+use sha3::{Digest, Sha3_256};
+use wasmi::{
+	Externals, FuncInstance, FuncRef, HostError, MemoryInstance, MemoryRef,
+	ModuleImportResolver, RuntimeArgs, RuntimeValue, Signature, Trap, TrapKind, ValueType,
+};
+use wasmi::memory_units::Pages;
+use parity_wasm::elements::ValueType as ElementValueType;
+
+/// Latest host ABI version supported by the engine.
+pub const HOST_VERSION: u32 = 2;
+
+/// Name of the import module of a host ABI version. A runtime declares the
+/// host ABI it targets by importing host functions from this module.
+pub fn host_module_name(version: u32) -> String {
+	format!("solri_host_v{}", version)
+}
+
+/// Error raised by a host function, trapping the execution.
+#[derive(Debug)]
+pub enum HostFunctionError {
+	/// Host function does not exist.
+	UnknownFunction,
+	/// Memory access out of bounds.
+	MemoryAccess,
+	/// Allocation failed.
+	OutOfMemory,
+	/// Storage access is not available in this execution.
+	StorageNotAvailable,
+	/// Execution ran out of gas.
+	OutOfGas,
+	/// Host function was called with arguments of the wrong type.
+	InvalidArguments,
+}
+
+impl fmt::Display for HostFunctionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl HostError for HostFunctionError { }
+
+impl From<HostFunctionError> for Trap {
+	fn from(err: HostFunctionError) -> Trap {
+		Trap::new(TrapKind::Host(Box::new(err)))
+	}
+}
+
+pub(crate) struct HostFunction {
+	pub name: &'static str,
+	pub since: u32,
+	pub params: &'static [ValueType],
+	pub ret: Option<ValueType>,
+}
+
+const SHA3_256: usize = 0;
+const LOG: usize = 1;
+const DEBUG_PRINT: usize = 2;
+const MALLOC: usize = 3;
+const FREE: usize = 4;
+const CODE_LEN: usize = 5;
+const CODE_READ: usize = 6;
+const SR25519_VERIFY: usize = 7;
+/// Gas charging function, imported by instrumented code. Not part of the
+/// versioned host ABI.
+pub(crate) const GAS: usize = usize::max_value();
+
+/// Host function registry, indexed by the function index.
+pub(crate) const HOST_FUNCTIONS: &[HostFunction] = &[
+	// ext_sha3_256(data_ptr, data_len, out_ptr): write the 32-byte hash.
+	HostFunction {
+		name: "ext_sha3_256",
+		since: 1,
+		params: &[ValueType::I32, ValueType::I32, ValueType::I32],
+		ret: None,
+	},
+	// ext_log(level, message_ptr, message_len)
+	HostFunction {
+		name: "ext_log",
+		since: 1,
+		params: &[ValueType::I32, ValueType::I32, ValueType::I32],
+		ret: None,
+	},
+	// ext_debug_print(message_ptr, message_len)
+	HostFunction {
+		name: "ext_debug_print",
+		since: 1,
+		params: &[ValueType::I32, ValueType::I32],
+		ret: None,
+	},
+	// ext_malloc(size) -> ptr
+	HostFunction {
+		name: "ext_malloc",
+		since: 1,
+		params: &[ValueType::I32],
+		ret: Some(ValueType::I32),
+	},
+	// ext_free(ptr)
+	HostFunction {
+		name: "ext_free",
+		since: 1,
+		params: &[ValueType::I32],
+		ret: None,
+	},
+	// ext_code_len() -> len
+	HostFunction {
+		name: "ext_code_len",
+		since: 1,
+		params: &[],
+		ret: Some(ValueType::I32),
+	},
+	// ext_code_read(offset, len, out_ptr) -> read_len
+	HostFunction {
+		name: "ext_code_read",
+		since: 1,
+		params: &[ValueType::I32, ValueType::I32, ValueType::I32],
+		ret: Some(ValueType::I32),
+	},
+	// ext_sr25519_verify(context_ptr, context_len, message_ptr, message_len,
+	// signature_ptr, public_ptr) -> valid: verify a 64-byte signature with a
+	// 32-byte public key.
+	HostFunction {
+		name: "ext_sr25519_verify",
+		since: 2,
+		params: &[
+			ValueType::I32, ValueType::I32, ValueType::I32,
+			ValueType::I32, ValueType::I32, ValueType::I32,
+		],
+		ret: Some(ValueType::I32),
+	},
+];
+
+/// Verify an sr25519 signature over a message in a signing context.
+pub(crate) fn sr25519_verify(
+	context: &[u8],
+	message: &[u8],
+	signature: &[u8],
+	public: &[u8],
+) -> bool {
+	let public = match schnorrkel::PublicKey::from_bytes(public) {
+		Ok(public) => public,
+		Err(_) => return false,
+	};
+	let signature = match schnorrkel::Signature::from_bytes(signature) {
+		Ok(signature) => signature,
+		Err(_) => return false,
+	};
+	public.verify(schnorrkel::signing_context(context).bytes(message), &signature)
+}
+
+/// Resolver of host functions for one host ABI version.
+pub struct HostResolver {
+	version: u32,
+}
+
+impl HostResolver {
+	/// Create a resolver for the given host ABI version.
+	pub fn new(version: u32) -> Self {
+		Self { version }
+	}
+}
+
+impl ModuleImportResolver for HostResolver {
+	fn resolve_func(
+		&self,
+		field_name: &str,
+		signature: &Signature,
+	) -> Result<FuncRef, wasmi::Error> {
+		let (index, function) = HOST_FUNCTIONS.iter().enumerate()
+			.find(|(_, function)| function.name == field_name && function.since <= self.version)
+			.ok_or_else(|| wasmi::Error::Instantiation(format!(
+				"Host function {} not found in host ABI version {}", field_name, self.version
+			)))?;
+
+		let expected = Signature::new(function.params, function.ret);
+		if signature != &expected {
+			return Err(wasmi::Error::Instantiation(format!(
+				"Host function {} has signature {:?}, expected {:?}",
+				field_name, signature, expected
+			)))
+		}
+
+		Ok(FuncInstance::alloc_host(expected, index))
+	}
+}
+
+/// Whether an import of a function with the given signature is provided by
+/// a supported host ABI version.
+pub fn is_host_function(
+	module_name: &str,
+	field_name: &str,
+	params: &[ElementValueType],
+	ret: Option<ElementValueType>,
+) -> bool {
+	fn value_type(ty: ElementValueType) -> ValueType {
+		match ty {
+			ElementValueType::I32 => ValueType::I32,
+			ElementValueType::I64 => ValueType::I64,
+			ElementValueType::F32 => ValueType::F32,
+			ElementValueType::F64 => ValueType::F64,
+		}
+	}
+
+	let params = params.iter().cloned().map(value_type).collect::<Vec<_>>();
+	let signature = Signature::new(params, ret.map(value_type));
+	(1..=HOST_VERSION)
+		.filter(|version| host_module_name(*version) == module_name)
+		.any(|version| HostResolver::new(version).resolve_func(field_name, &signature).is_ok())
+}
+
+/// Resolver of the gas charging function injected by gas metering.
+pub struct GasResolver;
+
+impl ModuleImportResolver for GasResolver {
+	fn resolve_func(
+		&self,
+		field_name: &str,
+		signature: &Signature,
+	) -> Result<FuncRef, wasmi::Error> {
+		let expected = Signature::new(&[ValueType::I32][..], None);
+		if field_name != "gas" || signature != &expected {
+			return Err(wasmi::Error::Instantiation(format!(
+				"Import {} with signature {:?} not found", field_name, signature
+			)))
+		}
+
+		Ok(FuncInstance::alloc_host(expected, GAS))
+	}
+}
+
+/// Gas charged for every host function call.
+const HOST_CALL_GAS: u64 = 100;
+/// Gas charged per byte read, hashed or written by a host function.
+const HOST_BYTE_GAS: u64 = 4;
+/// Gas charged for verifying a signature, on top of the bytes signed.
+const SR25519_VERIFY_GAS: u64 = 10_000;
+
+/// Longest runtime message kept in a trace, in bytes. Longer messages are
+/// truncated.
+const MAX_MESSAGE_LEN: u32 = 1024;
+/// Most runtime messages kept in the trace of one execution.
+const MAX_MESSAGES: usize = 256;
+
+/// Size of a wasm memory page, in bytes.
+const PAGE_SIZE: u32 = 64 * 1024;
+
+/// Memory of an instance, as accessed by host functions.
+pub(crate) trait HostMemory {
+	fn read(&self, ptr: u32, len: u32) -> Result<Vec<u8>, HostFunctionError>;
+	fn write(&self, ptr: u32, data: &[u8]) -> Result<(), HostFunctionError>;
+	/// Grow memory by a number of pages, returning the previous size in
+	/// pages.
+	fn grow_pages(&self, pages: u32) -> Result<u32, HostFunctionError>;
+}
+
+impl HostMemory for MemoryInstance {
+	fn read(&self, ptr: u32, len: u32) -> Result<Vec<u8>, HostFunctionError> {
+		self.get(ptr, len as usize).map_err(|_| HostFunctionError::MemoryAccess)
+	}
+
+	fn write(&self, ptr: u32, data: &[u8]) -> Result<(), HostFunctionError> {
+		self.set(ptr, data).map_err(|_| HostFunctionError::MemoryAccess)
+	}
+
+	fn grow_pages(&self, pages: u32) -> Result<u32, HostFunctionError> {
+		self.grow(Pages(pages as usize))
+			.map(|previous| previous.0 as u32)
+			.map_err(|_| HostFunctionError::OutOfMemory)
+	}
+}
+
+/// Host-side bump allocator over memory pages grown by the host. All
+/// allocations are released together with the instance at the end of an
+/// execution.
+#[derive(Default)]
+pub struct Arena {
+	next: u32,
+	end: u32,
+}
+
+impl Arena {
+	fn alloc(&mut self, memory: &dyn HostMemory, size: u32) -> Result<u32, HostFunctionError> {
+		let size = size.checked_add(7).ok_or(HostFunctionError::OutOfMemory)? & !7;
+
+		if self.end - self.next < size {
+			let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+			let pages = if pages == 0 { 1 } else { pages };
+			let previous = memory.grow_pages(pages)?;
+			self.next = previous * PAGE_SIZE;
+			self.end = (previous + pages) * PAGE_SIZE;
+		}
+
+		let ptr = self.next;
+		self.next += size;
+		Ok(ptr)
+	}
+}
+
+/// State kept by the host across calls into an instance.
+#[derive(Default)]
+pub struct HostState {
+	pub arena: Arena,
+	pub gas_left: Option<u64>,
+	/// Messages printed by the runtime, collected while tracing.
+	pub messages: Option<Vec<String>>,
+}
+
+/// Host functions as seen by one call into an instance. Executors only
+/// translate arguments and results, so that every executor runs the same
+/// host function bodies.
+pub(crate) struct Host<'a> {
+	pub memory: &'a dyn HostMemory,
+	pub state: &'a mut HostState,
+	pub code: Option<&'a [u8]>,
+}
+
+impl<'a> Host<'a> {
+	/// Deduct gas for work done by a host function, before doing it.
+	fn charge(&mut self, amount: u64) -> Result<(), HostFunctionError> {
+		if let Some(ref mut gas_left) = self.state.gas_left {
+			if amount > *gas_left {
+				*gas_left = 0;
+				return Err(HostFunctionError::OutOfGas)
+			}
+			*gas_left -= amount;
+		}
+		Ok(())
+	}
+
+	/// Gas of a host function call processing the given number of bytes.
+	fn call_gas(bytes: u64) -> u64 {
+		HOST_CALL_GAS + bytes * HOST_BYTE_GAS
+	}
+
+	/// Record a message printed by the runtime in the trace, if tracing.
+	/// Only the first `MAX_MESSAGE_LEN` bytes are read, whether tracing or
+	/// not, so that tracing never changes the outcome of an execution.
+	fn message(&mut self, level: &str, ptr: u32, len: u32) -> Result<(), HostFunctionError> {
+		let len = len.min(MAX_MESSAGE_LEN);
+		self.charge(Self::call_gas(u64::from(len)))?;
+		let message = self.memory.read(ptr, len)?;
+
+		if let Some(ref mut messages) = self.state.messages {
+			if messages.len() < MAX_MESSAGES {
+				messages.push(format!("[runtime {}] {}", level, String::from_utf8_lossy(&message)));
+			}
+		}
+		Ok(())
+	}
+
+	/// Call the host function with the given index. Host functions only
+	/// take and return `i32`s.
+	pub fn invoke(&mut self, index: usize, args: &[i32]) -> Result<Option<i32>, HostFunctionError> {
+		let arg = |n: usize| {
+			args.get(n).map(|arg| *arg as u32).ok_or(HostFunctionError::InvalidArguments)
+		};
+
+		match index {
+			SHA3_256 => {
+				self.charge(Self::call_gas(u64::from(arg(1)?)))?;
+				let data = self.memory.read(arg(0)?, arg(1)?)?;
+				let hash = Sha3_256::digest(&data);
+				self.memory.write(arg(2)?, hash.as_slice())?;
+				Ok(None)
+			},
+			LOG => {
+				let level = match arg(0)? {
+					0 => "ERROR",
+					1 => "WARN",
+					2 => "INFO",
+					3 => "DEBUG",
+					_ => "TRACE",
+				};
+				self.message(level, arg(1)?, arg(2)?)?;
+				Ok(None)
+			},
+			DEBUG_PRINT => {
+				self.message("debug", arg(0)?, arg(1)?)?;
+				Ok(None)
+			},
+			MALLOC => {
+				self.charge(Self::call_gas(0))?;
+				let ptr = self.state.arena.alloc(self.memory, arg(0)?)?;
+				Ok(Some(ptr as i32))
+			},
+			FREE => {
+				// Allocations are released when the execution finishes.
+				arg(0)?;
+				self.charge(Self::call_gas(0))?;
+				Ok(None)
+			},
+			CODE_LEN => {
+				self.charge(Self::call_gas(0))?;
+				let code = self.code.ok_or(HostFunctionError::StorageNotAvailable)?;
+				Ok(Some(code.len() as i32))
+			},
+			CODE_READ => {
+				let (offset, len, out) = (arg(0)?, arg(1)?, arg(2)?);
+				let code = self.code.ok_or(HostFunctionError::StorageNotAvailable)?;
+
+				let start = (offset as usize).min(code.len());
+				let end = start.saturating_add(len as usize).min(code.len());
+				self.charge(Self::call_gas((end - start) as u64))?;
+				self.memory.write(out, &code[start..end])?;
+				Ok(Some((end - start) as i32))
+			},
+			SR25519_VERIFY => {
+				let bytes = u64::from(arg(1)?) + u64::from(arg(3)?) + 64 + 32;
+				self.charge(Self::call_gas(bytes) + SR25519_VERIFY_GAS)?;
+				let context = self.memory.read(arg(0)?, arg(1)?)?;
+				let message = self.memory.read(arg(2)?, arg(3)?)?;
+				let signature = self.memory.read(arg(4)?, 64)?;
+				let public = self.memory.read(arg(5)?, 32)?;
+				let valid = sr25519_verify(&context, &message, &signature, &public);
+				Ok(Some(valid as i32))
+			},
+			GAS => {
+				self.charge(u64::from(arg(0)?))?;
+				Ok(None)
+			},
+			_ => Err(HostFunctionError::UnknownFunction),
+		}
+	}
+}
+
+/// Externals passed to every call into a wasmi instance.
+pub struct HostExternals<'a> {
+	pub memory: &'a MemoryRef,
+	pub state: &'a mut HostState,
+	pub code: Option<&'a dyn CodeExternalities>,
+}
+
+impl<'a> Externals for HostExternals<'a> {
+	fn invoke_index(
+		&mut self,
+		index: usize,
+		args: RuntimeArgs,
+	) -> Result<Option<RuntimeValue>, Trap> {
+		let args = args.as_ref().iter()
+			.map(|arg| match arg {
+				RuntimeValue::I32(arg) => Ok(*arg),
+				_ => Err(HostFunctionError::InvalidArguments),
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		let mut host = Host {
+			memory: &**self.memory,
+			state: &mut *self.state,
+			code: self.code.map(|code| &code.code()[..]),
+		};
+
+		Ok(host.invoke(index, &args)?.map(RuntimeValue::I32))
+	}
+}
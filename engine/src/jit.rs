@@ -0,0 +1,356 @@
+//! Block executor compiling runtimes with wasmtime instead of interpreting
+//! them with wasmi.
+
+use crate::{
+	Cache, CachedInstance, Error, ExecutionLimits, HostFunctionError, Metadata, RuntimeError,
+	HOST_VERSION, host_module_name,
+};
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::executor::{CodeExternalities, apply_metadata};
+use crate::host::{GAS, HOST_FUNCTIONS, Host, HostMemory, HostState};
+use crate::limits::{self, STACK_HEIGHT_EXPORT};
+use blockchain::BlockExecutor;
+use metadata::{GenericBlock, RawMetadata, RawArray};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use wasmi::ValueType;
+use wasmtime::{
+	Caller, Engine, Extern, Func, FuncType, Instance, Linker, Memory, Module, Store, Trap,
+	Val, ValType,
+};
+
+/// Host state of one execution, shared by all host functions.
+#[derive(Default)]
+struct JitState {
+	code: Option<Vec<u8>>,
+	host: HostState,
+	error: Option<HostFunctionError>,
+}
+
+type SharedState = Rc<RefCell<JitState>>;
+
+impl HostMemory for Memory {
+	fn read(&self, ptr: u32, len: u32) -> Result<Vec<u8>, HostFunctionError> {
+		let start = ptr as usize;
+		// SAFETY: the slice is copied out before returning, and nothing
+		// else accesses the memory meanwhile. Stores are never shared
+		// between threads, and host functions run to completion without
+		// calling back into wasm, so no wasm code runs and the memory cannot
+		// grow while the slice is alive.
+		let data = unsafe { self.data_unchecked() };
+		data.get(start..start.checked_add(len as usize).ok_or(HostFunctionError::MemoryAccess)?)
+			.map(|slice| slice.to_vec())
+			.ok_or(HostFunctionError::MemoryAccess)
+	}
+
+	fn write(&self, ptr: u32, data: &[u8]) -> Result<(), HostFunctionError> {
+		let start = ptr as usize;
+		// SAFETY: as in `read`, no wasm code runs, the memory does not grow
+		// and no other slice of it is alive while this one is written to.
+		let memory = unsafe { self.data_unchecked_mut() };
+		memory.get_mut(start..start.checked_add(data.len()).ok_or(HostFunctionError::MemoryAccess)?)
+			.ok_or(HostFunctionError::MemoryAccess)?
+			.copy_from_slice(data);
+		Ok(())
+	}
+
+	fn grow_pages(&self, pages: u32) -> Result<u32, HostFunctionError> {
+		self.grow(pages).map_err(|_| HostFunctionError::OutOfMemory)
+	}
+}
+
+fn memory(caller: &Caller) -> Result<Memory, HostFunctionError> {
+	caller.get_export("memory")
+		.and_then(Extern::into_memory)
+		.ok_or(HostFunctionError::MemoryAccess)
+}
+
+/// Record a host function error and turn it into a trap.
+fn trap(state: &SharedState, err: HostFunctionError) -> Trap {
+	let trap = Trap::new(err.to_string());
+	state.borrow_mut().error = Some(err);
+	trap
+}
+
+fn val_type(ty: ValueType) -> ValType {
+	match ty {
+		ValueType::I32 => ValType::I32,
+		ValueType::I64 => ValType::I64,
+		ValueType::F32 => ValType::F32,
+		ValueType::F64 => ValType::F64,
+	}
+}
+
+/// Wrap the host function with the given index, forwarding calls to the
+/// shared host function bodies.
+fn host_func(
+	store: &Store,
+	state: &SharedState,
+	index: usize,
+	params: &[ValueType],
+	ret: Option<ValueType>,
+) -> Func {
+	let ty = FuncType::new(
+		params.iter().cloned().map(val_type).collect::<Vec<_>>().into_boxed_slice(),
+		ret.into_iter().map(val_type).collect::<Vec<_>>().into_boxed_slice(),
+	);
+	let s = state.clone();
+
+	Func::new(store, ty, move |caller: Caller, args: &[Val], results: &mut [Val]| {
+		let memory = memory(&caller).map_err(|e| trap(&s, e))?;
+		let args = args.iter()
+			.map(|arg| arg.i32().ok_or(HostFunctionError::InvalidArguments))
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|e| trap(&s, e))?;
+
+		let result = {
+			let mut state = s.borrow_mut();
+			let JitState { ref code, ref mut host, .. } = *state;
+			Host {
+				memory: &memory,
+				state: host,
+				code: code.as_ref().map(|code| &code[..]),
+			}.invoke(index, &args)
+		};
+
+		if let Some(value) = result.map_err(|e| trap(&s, e))? {
+			results[0] = Val::I32(value);
+		}
+		Ok(())
+	})
+}
+
+fn link(store: &Store, state: &SharedState) -> Result<Linker, Error> {
+	let mut linker = Linker::new(store);
+
+	for version in 1..=HOST_VERSION {
+		let module = host_module_name(version);
+		for (index, function) in HOST_FUNCTIONS.iter().enumerate() {
+			if function.since <= version {
+				let func = host_func(store, state, index, function.params, function.ret);
+				linker.define(&module, function.name, func).map_err(jit_error)?;
+			}
+		}
+	}
+
+	let gas = host_func(store, state, GAS, &[ValueType::I32], None);
+	linker.define("env", "gas", gas).map_err(jit_error)?;
+
+	Ok(linker)
+}
+
+fn jit_error<E: ToString>(err: E) -> Error {
+	Error::Jit(err.to_string())
+}
+
+/// One execution of a compiled runtime on a fresh instance.
+struct Execution {
+	instance: Instance,
+	memory: Memory,
+	state: SharedState,
+	limits: ExecutionLimits,
+}
+
+impl Execution {
+	fn new(
+		module: &Module,
+		limits: &ExecutionLimits,
+		code: Option<Vec<u8>>,
+	) -> Result<Self, Error> {
+		let store = Store::new(module.engine());
+		let state = Rc::new(RefCell::new(JitState {
+			code,
+			host: HostState {
+				gas_left: limits.gas,
+				..Default::default()
+			},
+			..Default::default()
+		}));
+		let instance = link(&store, &state)?.instantiate(module).map_err(jit_error)?;
+		let memory = instance.get_memory("memory")
+			.ok_or(Error::InstanceMemoryNotExported)?;
+
+		Ok(Self { instance, memory, state, limits: limits.clone() })
+	}
+
+	fn call0(&self, name: &str) -> Result<i32, Error> {
+		let func = self.instance.get_func(name)
+			.ok_or(Error::InvalidFunctionSignature)?
+			.get0::<i32>()
+			.map_err(|_| Error::InvalidFunctionSignature)?;
+		func().map_err(|trap| self.trap_error(trap))
+	}
+
+	fn call1(&self, name: &str, arg: i32) -> Result<i32, Error> {
+		let func = self.instance.get_func(name)
+			.ok_or(Error::InvalidFunctionSignature)?
+			.get1::<i32, i32>()
+			.map_err(|_| Error::InvalidFunctionSignature)?;
+		func(arg).map_err(|trap| self.trap_error(trap))
+	}
+
+	/// Convert a trap into the error of the execution limit or host
+	/// function that caused it.
+	fn trap_error(&self, trap: Trap) -> Error {
+		match self.state.borrow_mut().error.take() {
+			Some(HostFunctionError::OutOfGas) => return Error::OutOfGas,
+			Some(HostFunctionError::OutOfMemory) => return Error::MemoryLimitExceeded,
+			_ => (),
+		}
+
+		let height = self.instance.get_func(STACK_HEIGHT_EXPORT)
+			.and_then(|func| func.get0::<i32>().ok())
+			.and_then(|func| func().ok());
+		if let (Some(height), Some(max)) = (height, self.limits.max_stack_height) {
+			if height as u32 > max {
+				return Error::StackLimitExceeded
+			}
+		}
+
+		Error::Jit(trap.to_string())
+	}
+
+	fn read(&self, ptr: u32, len: u32) -> Result<Vec<u8>, Error> {
+		HostMemory::read(&self.memory, ptr, len).map_err(|_| Error::InvalidMetadata)
+	}
+
+	fn write(&self, ptr: i32, bytes: &[u8]) -> Result<(), Error> {
+		HostMemory::write(&self.memory, ptr as u32, bytes).map_err(|e| Error::Jit(e.to_string()))
+	}
+
+	fn execute(&self, block: &[u8], code: &[u8]) -> Result<Metadata, Error> {
+		let ptr = self.call1("write_block", block.len() as i32)?;
+		self.write(ptr, block)?;
+		let ptr = self.call1("write_code", code.len() as i32)?;
+		self.write(ptr, code)?;
+
+		let status = self.call0("execute")?;
+		if status != 0 {
+			return Err(match RuntimeError::decode(status as u32) {
+				Some(err) => Error::Runtime(err),
+				None => self.read_error(),
+			})
+		}
+
+		let ptr = self.call0("read_metadata")?;
+		let bytes = self.read(ptr as u32, RawMetadata::bytes_len() as u32)?;
+		let metadata = RawMetadata::decode(&bytes).ok_or(Error::InvalidMetadata)?;
+		Ok(Metadata {
+			timestamp: metadata.timestamp,
+			difficulty: metadata.difficulty,
+			parent_id: self.read(metadata.parent_id.ptr, metadata.parent_id.len)?,
+			id: self.read(metadata.id.ptr, metadata.id.len)?,
+			code: self.read(metadata.code.ptr, metadata.code.len)?,
+		})
+	}
+
+	fn read_error(&self) -> Error {
+		if self.instance.get_func("debug_read_error").is_none() {
+			return Error::ExecutionFailed
+		}
+
+		let reason = self.call0("debug_read_error")
+			.and_then(|ptr| self.read(ptr as u32, RawArray::bytes_len() as u32))
+			.and_then(|bytes| RawArray::decode(&bytes).ok_or(Error::InvalidMetadata))
+			.and_then(|array| self.read(array.ptr, array.len));
+		match reason {
+			Ok(reason) => Error::ExecutionFailedWithReason(
+				String::from_utf8_lossy(&reason).into_owned()
+			),
+			Err(e) => Error::ExecutionFailedWithReason(
+				format!("read debug error failed ({:?})", e)
+			),
+		}
+	}
+}
+
+/// Runtime compiled to native code.
+pub struct JitInstance {
+	code: Vec<u8>,
+	module: Module,
+	limits: ExecutionLimits,
+}
+
+impl JitInstance {
+	pub fn new(engine: &Engine, code: Vec<u8>, limits: ExecutionLimits) -> Result<Self, Error> {
+		let instrumented = parity_wasm::serialize(limits::instrument(&code, &limits)?)
+			.map_err(|_| Error::InvalidCode)?;
+		let module = Module::new(engine, &instrumented).map_err(jit_error)?;
+		// Instantiate once, so that code failing to instantiate is rejected
+		// here rather than on its first execution.
+		Execution::new(&module, &limits, None)?;
+
+		Ok(Self { code, module, limits })
+	}
+
+	pub fn execute(&self, block: &[u8]) -> Result<Metadata, Error> {
+		Execution::new(&self.module, &self.limits, None)?
+			.execute(block, &self.code)
+	}
+
+	/// Execute a block, giving the runtime read access to the code storage
+	/// through host functions.
+	pub fn execute_with_code(
+		&self,
+		block: &[u8],
+		code: &dyn CodeExternalities,
+	) -> Result<Metadata, Error> {
+		Execution::new(&self.module, &self.limits, Some(code.code().clone()))?
+			.execute(block, &self.code)
+	}
+}
+
+impl CachedInstance for JitInstance {
+	type Context = Engine;
+
+	fn prepare(
+		engine: &Engine,
+		code: Arc<Vec<u8>>,
+		limits: ExecutionLimits,
+	) -> Result<Self, Error> {
+		Self::new(engine, code.to_vec(), limits)
+	}
+}
+
+/// Block executor backed by wasmtime, keeping compiled code of recently
+/// executed runtimes.
+pub struct JitExecutor {
+	cache: Mutex<Cache<JitInstance>>,
+}
+
+impl Default for JitExecutor {
+	fn default() -> Self {
+		Self::new(DEFAULT_CACHE_SIZE)
+	}
+}
+
+impl JitExecutor {
+	pub fn new(cache_size: usize) -> Self {
+		Self::new_with_limits(cache_size, ExecutionLimits::default())
+	}
+
+	pub fn new_with_limits(cache_size: usize, limits: ExecutionLimits) -> Self {
+		Self {
+			cache: Mutex::new(Cache::with_context(Engine::default(), cache_size, limits)),
+		}
+	}
+}
+
+impl BlockExecutor for JitExecutor {
+	type Error = Error;
+	type Block = GenericBlock;
+	type Externalities = dyn CodeExternalities + 'static;
+
+	fn execute_block(
+		&self,
+		block: &Self::Block,
+		state: &mut Self::Externalities,
+	) -> Result<(), Error> {
+		let mut cache = self.cache.lock().expect("Lock is poisoned");
+		let metadata = cache.instance(state.code())?
+			.execute_with_code(&block.data, &*state)?;
+
+		apply_metadata(block, state, metadata, &mut *cache)
+	}
+}
@@ -1,15 +1,34 @@
+mod cache;
+mod dispatch;
 mod executor;
+mod host;
+#[cfg(feature = "jit")]
+mod jit;
+mod limits;
+mod trace;
+mod validation;
 
+pub use self::cache::{Cache, CacheStats, CachedInstance};
+pub use self::dispatch::{Dispatch, Dispatcher};
 pub use self::executor::{Executor, CodeExternalities};
-pub use metadata::GenericBlock;
+#[cfg(feature = "jit")]
+pub use self::jit::{JitExecutor, JitInstance};
+pub use self::host::{HOST_VERSION, HostResolver, HostFunctionError, host_module_name};
+pub use self::limits::ExecutionLimits;
+pub use self::trace::{CallTrace, Trace};
+pub use self::validation::{ValidationError, validate_code};
+pub use metadata::{GenericBlock, RuntimeError, RuntimeVersion};
 
-use wasmi::RuntimeValue;
+use wasmi::{RuntimeValue, TrapKind};
+use crate::host::{HostState, HostExternals, GasResolver};
+use crate::limits::STACK_HEIGHT_EXPORT;
 use metadata::{RawMetadata, RawArray};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::cell::RefCell;
 use std::error as stderror;
+use std::time::Instant;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Metadata {
 	pub timestamp: u64,
 	pub difficulty: u64,
@@ -28,6 +47,17 @@ pub enum Error {
 	ExecutionFailed,
 	ExecutionFailedWithReason(String),
 	OutdatedRuntime,
+	InvalidCode,
+	OutOfGas,
+	MemoryLimitExceeded,
+	StackLimitExceeded,
+	InvalidRuntimeCode(ValidationError),
+	QueryNotSupported,
+	QueryFailed,
+	/// Error reported by the runtime.
+	Runtime(RuntimeError),
+	#[cfg(feature = "jit")]
+	Jit(String),
 }
 
 impl std::fmt::Display for Error {
@@ -38,25 +68,129 @@ impl std::fmt::Display for Error {
 
 impl stderror::Error for Error { }
 
+impl Error {
+	/// Whether the error is caused by the executed block or its runtime
+	/// code, rather than by the executor or the node.
+	pub fn is_invalid_block(&self) -> bool {
+		match self {
+			Error::Runtime(RuntimeError::Backend) => false,
+			Error::Runtime(_) |
+			Error::OutOfGas |
+			Error::MemoryLimitExceeded |
+			Error::StackLimitExceeded |
+			Error::InvalidMetadata |
+			Error::InvalidRuntimeCode(_) => true,
+			_ => false,
+		}
+	}
+}
+
 impl From<wasmi::Error> for Error {
 	fn from(err: wasmi::Error) -> Error {
 		Error::Interpreter(err)
 	}
 }
 
+/// Runtime code parsed, instrumented and validated once. Every execution
+/// runs on a fresh instantiation of it, so that no state leaks from one
+/// execution to the next.
 pub struct Instance {
 	code: Arc<Vec<u8>>,
-	instance: wasmi::ModuleRef,
-	memory: wasmi::MemoryRef,
+	module: wasmi::Module,
+	limits: ExecutionLimits,
 }
 
 impl Instance {
 	pub fn new(code: Arc<Vec<u8>>) -> Result<Self, Error> {
-		let module = wasmi::Module::from_buffer(code.as_ref())?;
-		let instance = wasmi::ModuleInstance::new(
-			&module,
-			&wasmi::ImportsBuilder::default()
+		Self::new_with_limits(code, ExecutionLimits::default())
+	}
+
+	pub fn new_with_limits(code: Arc<Vec<u8>>, limits: ExecutionLimits) -> Result<Self, Error> {
+		let module = wasmi::Module::from_parity_wasm_module(
+			limits::instrument(code.as_ref(), &limits)?
 		)?;
+		let instance = Self { code, module, limits };
+		// Instantiate once, so that code failing to instantiate is rejected
+		// here rather than on its first execution.
+		instance.instantiate(false)?;
+		Ok(instance)
+	}
+
+	pub fn execute(&self, block: &[u8]) -> Result<Metadata, Error> {
+		self.instantiate(false)?.execute(block, &self.code, None)
+	}
+
+	/// Execute a block, giving the runtime read access to the code storage
+	/// through host functions.
+	pub fn execute_with_code(
+		&self,
+		block: &[u8],
+		code: &dyn CodeExternalities,
+	) -> Result<Metadata, Error> {
+		self.instantiate(false)?.execute(block, &self.code, Some(code))
+	}
+
+	/// Execute a block while recording export call timings, memory growth,
+	/// bytes transferred and runtime messages.
+	pub fn execute_traced(
+		&self,
+		block: &[u8],
+		code: Option<&dyn CodeExternalities>,
+	) -> (Result<Metadata, Error>, Trace) {
+		let execution = match self.instantiate(true) {
+			Ok(execution) => execution,
+			Err(err) => {
+				let trace = Trace { error: Some(err.to_string()), ..Default::default() };
+				return (Err(err), trace)
+			},
+		};
+
+		let result = execution.execute(block, &self.code, code);
+		let trace = execution.into_trace(&result);
+		(result, trace)
+	}
+
+	/// Read the version of the runtime, or `None` if it does not export one.
+	pub fn version(&self) -> Result<Option<RuntimeVersion>, Error> {
+		self.instantiate(false)?.version()
+	}
+
+	/// Answer a read-only query from a proof of the state with the given
+	/// root. The runtime rejects proofs of any other state, and has no
+	/// access to the code storage while answering.
+	pub fn query(&self, request: &[u8], proof: &[u8], root: &[u8]) -> Result<Vec<u8>, Error> {
+		self.instantiate(false)?.query(request, proof, root)
+	}
+
+	fn instantiate(&self, traced: bool) -> Result<Execution, Error> {
+		Execution::new(&self.module, &self.limits, traced)
+	}
+}
+
+/// Fresh instance of a module, used for a single execution.
+struct Execution {
+	instance: wasmi::ModuleRef,
+	memory: wasmi::MemoryRef,
+	host: RefCell<HostState>,
+	max_stack_height: Option<u32>,
+	trace: RefCell<Option<Trace>>,
+}
+
+impl Execution {
+	fn new(
+		module: &wasmi::Module,
+		limits: &ExecutionLimits,
+		traced: bool,
+	) -> Result<Self, Error> {
+		let resolvers = (1..=HOST_VERSION)
+			.map(|version| (host_module_name(version), HostResolver::new(version)))
+			.collect::<Vec<_>>();
+		let mut imports = wasmi::ImportsBuilder::default();
+		for (name, resolver) in &resolvers {
+			imports = imports.with_resolver(name.as_str(), resolver);
+		}
+		imports = imports.with_resolver("env", &GasResolver);
+		let instance = wasmi::ModuleInstance::new(module, &imports)?;
 		if instance.has_start() {
 			return Err(Error::InstanceHasStart)
 		}
@@ -66,51 +200,246 @@ impl Instance {
 			.as_memory()
 			.ok_or_else(|| Error::InstanceMemoryNotExported)?
 			.clone();
-		Ok(Self { instance, memory, code })
+
+		let execution = Self {
+			instance, memory,
+			host: RefCell::new(HostState {
+				gas_left: limits.gas,
+				messages: if traced { Some(Vec::new()) } else { None },
+				..Default::default()
+			}),
+			max_stack_height: limits.max_stack_height,
+			trace: RefCell::new(None),
+		};
+		if traced {
+			*execution.trace.borrow_mut() = Some(Trace {
+				initial_memory_pages: execution.memory_pages(),
+				..Default::default()
+			});
+		}
+		Ok(execution)
 	}
 
-	pub fn execute(&self, block: &[u8]) -> Result<Metadata, Error> {
-		self.call_write_block(block)?;
-		self.call_write_code(self.code.as_ref())?;
-		self.call_execute()?;
-		let metadata = self.call_read_metadata()?;
-		self.call_free()?;
+	fn execute(
+		&self,
+		block: &[u8],
+		data: &[u8],
+		code: Option<&dyn CodeExternalities>,
+	) -> Result<Metadata, Error> {
+		let result = self.call_write_block(block, code)
+			.and_then(|()| self.call_write_code(data, code))
+			.and_then(|()| self.call_execute(code))
+			.and_then(|()| self.call_read_metadata(code));
+		let free = self.call_free(code);
+
+		let metadata = result?;
+		free?;
 		Ok(metadata)
 	}
 
-	fn call_write_block(&self, block: &[u8]) -> Result<(), Error> {
-		match self.instance.invoke_export(
+	/// Finish the trace of a traced execution.
+	fn into_trace(self, result: &Result<Metadata, Error>) -> Trace {
+		let mut trace = self.trace.borrow_mut().take().unwrap_or_default();
+		trace.messages = self.host.borrow_mut().messages.take().unwrap_or_default();
+		trace.final_memory_pages = self.memory_pages();
+		match result {
+			Ok(metadata) => trace.block_id = Some(trace::hex(&metadata.id)),
+			Err(err) => trace.error = Some(err.to_string()),
+		}
+		trace
+	}
+
+	fn version(&self) -> Result<Option<RuntimeVersion>, Error> {
+		if self.instance.export_by_name("version").is_none() {
+			return Ok(None)
+		}
+
+		match self.invoke("version", &[], None)? {
+			Some(RuntimeValue::I32(ptr)) => {
+				let bytes = self.memory.get(ptr as u32, RawArray::bytes_len())?;
+				let array = RawArray::decode(&bytes).ok_or(Error::InvalidMetadata)?;
+				let bytes = self.memory.get(array.ptr, array.len as usize)?;
+				RuntimeVersion::decode(&bytes).map(Some).ok_or(Error::InvalidMetadata)
+			},
+			_ => Err(Error::InvalidFunctionSignature),
+		}
+	}
+
+	fn query(&self, request: &[u8], proof: &[u8], root: &[u8]) -> Result<Vec<u8>, Error> {
+		if self.instance.export_by_name("query").is_none() ||
+			self.instance.export_by_name("write_root").is_none()
+		{
+			return Err(Error::QueryNotSupported)
+		}
+
+		let result = self.call_write("write_query", request)
+			.and_then(|()| self.call_write("write_proof", proof))
+			.and_then(|()| self.call_write("write_root", root))
+			.and_then(|()| self.call_query());
+		let free = self.call_free(None);
+
+		let response = result?;
+		free?;
+		Ok(response)
+	}
+
+	fn invoke(
+		&self,
+		name: &str,
+		args: &[RuntimeValue],
+		code: Option<&dyn CodeExternalities>,
+	) -> Result<Option<RuntimeValue>, Error> {
+		let mut state = self.host.borrow_mut();
+		let mut externals = HostExternals {
+			memory: &self.memory,
+			state: &mut state,
+			code,
+		};
+		let start = Instant::now();
+		let result = self.instance.invoke_export(name, args, &mut externals)
+			.map_err(|err| self.limit_error(err));
+
+		if let Some(ref mut trace) = *self.trace.borrow_mut() {
+			let elapsed = start.elapsed();
+			trace.calls.push(CallTrace {
+				export: name.to_string(),
+				micros: elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros()),
+				memory_pages: self.memory_pages(),
+				..Default::default()
+			});
+		}
+		result
+	}
+
+	fn memory_pages(&self) -> u32 {
+		self.memory.current_size().0 as u32
+	}
+
+	/// Record bytes transferred for the last traced call.
+	fn trace_bytes(&self, bytes_in: usize, bytes_out: usize) {
+		if let Some(ref mut trace) = *self.trace.borrow_mut() {
+			if let Some(call) = trace.calls.last_mut() {
+				call.bytes_in += bytes_in;
+				call.bytes_out += bytes_out;
+			}
+		}
+	}
+
+	fn stack_height(&self) -> Option<u32> {
+		match self.instance.invoke_export(STACK_HEIGHT_EXPORT, &[], &mut wasmi::NopExternals) {
+			Ok(Some(RuntimeValue::I32(height))) => Some(height as u32),
+			_ => None,
+		}
+	}
+
+	/// Convert traps caused by exceeding an execution limit into the
+	/// dedicated error.
+	fn limit_error(&self, err: wasmi::Error) -> Error {
+		if let wasmi::Error::Trap(ref trap) = err {
+			match trap.kind() {
+				TrapKind::Host(host_error) => {
+					match host_error.downcast_ref::<HostFunctionError>() {
+						Some(HostFunctionError::OutOfGas) => return Error::OutOfGas,
+						Some(HostFunctionError::OutOfMemory) => return Error::MemoryLimitExceeded,
+						_ => (),
+					}
+				},
+				TrapKind::StackOverflow => return Error::StackLimitExceeded,
+				// The stack limiter traps with unreachable once the height
+				// goes over the limit.
+				TrapKind::Unreachable => {
+					if let (Some(height), Some(max)) = (self.stack_height(), self.max_stack_height) {
+						if height > max {
+							return Error::StackLimitExceeded
+						}
+					}
+				},
+				_ => (),
+			}
+		}
+
+		Error::Interpreter(err)
+	}
+
+	fn call_write_block(
+		&self,
+		block: &[u8],
+		code: Option<&dyn CodeExternalities>,
+	) -> Result<(), Error> {
+		match self.invoke(
 			"write_block",
 			&[RuntimeValue::I32(block.len() as i32)],
-			&mut wasmi::NopExternals,
+			code,
 		)? {
 			Some(RuntimeValue::I32(ptr)) => {
 				self.memory.set(ptr as u32, block)?;
+				self.trace_bytes(block.len(), 0);
 				Ok(())
 			},
 			_ => return Err(Error::InvalidFunctionSignature),
 		}
 	}
 
-	fn call_write_code(&self, code: &[u8]) -> Result<(), Error> {
-		match self.instance.invoke_export(
+	fn call_write_code(
+		&self,
+		data: &[u8],
+		code: Option<&dyn CodeExternalities>,
+	) -> Result<(), Error> {
+		match self.invoke(
 			"write_code",
-			&[RuntimeValue::I32(code.len() as i32)],
-			&mut wasmi::NopExternals,
+			&[RuntimeValue::I32(data.len() as i32)],
+			code,
 		)? {
 			Some(RuntimeValue::I32(ptr)) => {
-				self.memory.set(ptr as u32, code)?;
+				self.memory.set(ptr as u32, data)?;
+				self.trace_bytes(data.len(), 0);
 				Ok(())
 			},
 			_ => return Err(Error::InvalidFunctionSignature),
 		}
 	}
 
-	fn call_read_metadata(&self) -> Result<Metadata, Error> {
-		match self.instance.invoke_export(
+	fn call_write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+		match self.invoke(
+			name,
+			&[RuntimeValue::I32(data.len() as i32)],
+			None,
+		)? {
+			Some(RuntimeValue::I32(ptr)) => {
+				self.memory.set(ptr as u32, data)?;
+				self.trace_bytes(data.len(), 0);
+				Ok(())
+			},
+			_ => Err(Error::InvalidFunctionSignature),
+		}
+	}
+
+	fn call_query(&self) -> Result<Vec<u8>, Error> {
+		match self.invoke("query", &[], None)? {
+			Some(RuntimeValue::I32(0)) => (),
+			Some(RuntimeValue::I32(status)) => return Err(
+				RuntimeError::decode(status as u32)
+					.map(Error::Runtime)
+					.unwrap_or(Error::QueryFailed)
+			),
+			_ => return Err(Error::InvalidFunctionSignature),
+		}
+
+		match self.invoke("read_query_response", &[], None)? {
+			Some(RuntimeValue::I32(ptr)) => {
+				let bytes = self.memory.get(ptr as u32, RawArray::bytes_len())?;
+				let array = RawArray::decode(&bytes).ok_or(Error::InvalidMetadata)?;
+				Ok(self.memory.get(array.ptr, array.len as usize)?)
+			},
+			_ => Err(Error::InvalidFunctionSignature),
+		}
+	}
+
+	fn call_read_metadata(&self, code: Option<&dyn CodeExternalities>) -> Result<Metadata, Error> {
+		match self.invoke(
 			"read_metadata",
 			&[],
-			&mut wasmi::NopExternals,
+			code,
 		)? {
 			Some(RuntimeValue::I32(ptr)) => {
 				let len = RawMetadata::bytes_len();
@@ -129,6 +458,7 @@ impl Instance {
 					metadata_ptr.code.ptr,
 					metadata_ptr.code.len as usize
 				)?;
+				self.trace_bytes(0, len + parent_id.len() + id.len() + code.len());
 				Ok(Metadata {
 					timestamp: metadata_ptr.timestamp,
 					difficulty: metadata_ptr.difficulty,
@@ -141,21 +471,23 @@ impl Instance {
 		}
 	}
 
-	fn call_execute(&self) -> Result<(), Error> {
-		match self.instance.invoke_export(
+	fn call_execute(&self, code: Option<&dyn CodeExternalities>) -> Result<(), Error> {
+		match self.invoke(
 			"execute",
 			&[],
-			&mut wasmi::NopExternals,
+			code,
 		)? {
 			Some(RuntimeValue::I32(status)) => {
 				if status == 0 {
 					Ok(())
+				} else if let Some(err) = RuntimeError::decode(status as u32) {
+					Err(Error::Runtime(err))
 				} else {
 					if self.instance.export_by_name("debug_read_error").is_some() {
-						match self.instance.invoke_export(
+						match self.invoke(
 							"debug_read_error",
 							&[],
-							&mut wasmi::NopExternals,
+							code,
 						) {
 							Ok(Some(RuntimeValue::I32(ptr))) => {
 								let len = RawArray::bytes_len();
@@ -193,27 +525,14 @@ impl Instance {
 		}
 	}
 
-	fn call_free(&self) -> Result<(), Error> {
-		match self.instance.invoke_export(
+	fn call_free(&self, code: Option<&dyn CodeExternalities>) -> Result<(), Error> {
+		match self.invoke(
 			"free",
 			&[],
-			&mut wasmi::NopExternals,
+			code,
 		)? {
 			None => Ok(()),
 			_ => Err(Error::InvalidFunctionSignature),
 		}
 	}
 }
-
-#[derive(Default)]
-pub struct Cache {
-	cache: HashMap<Vec<u8>, Instance>,
-}
-
-impl Cache {
-	pub fn execute(&mut self, block: &[u8], code: &[u8]) -> Result<Metadata, Error> {
-		let code = code.to_vec();
-		self.cache.entry(code.clone()).or_insert(Instance::new(Arc::new(code))?)
-			.execute(block)
-	}
-}
@@ -0,0 +1,104 @@
+use crate::Error;
+use parity_wasm::elements::{
+	self, External, Instruction, Instructions, MemoryType, Module,
+};
+use parity_wasm::builder;
+
+/// Export reading the stack height counter injected by the stack limiter.
+pub const STACK_HEIGHT_EXPORT: &str = "__solri_stack_height";
+
+/// Limits applied to a wasm execution.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExecutionLimits {
+	/// Gas available to each block execution, charged per instruction. No
+	/// metering if not set.
+	pub gas: Option<u64>,
+	/// Maximum number of memory pages.
+	pub max_memory_pages: u32,
+	/// Maximum stack height, as computed by the stack limiter. No limit if
+	/// not set.
+	pub max_stack_height: Option<u32>,
+}
+
+impl Default for ExecutionLimits {
+	fn default() -> Self {
+		Self {
+			gas: Some(10_000_000_000),
+			max_memory_pages: 2048,
+			max_stack_height: Some(64 * 1024),
+		}
+	}
+}
+
+/// Instrument code with gas metering and stack limiting, and cap its memory.
+pub fn instrument(code: &[u8], limits: &ExecutionLimits) -> Result<Module, Error> {
+	let mut module = parity_wasm::deserialize_buffer::<Module>(code)
+		.map_err(|_| Error::InvalidCode)?;
+
+	if let Some(memory_section) = module.memory_section_mut() {
+		for entry in memory_section.entries_mut() {
+			let initial = entry.limits().initial();
+			if initial > limits.max_memory_pages {
+				return Err(Error::MemoryLimitExceeded)
+			}
+			let maximum = entry.limits().maximum()
+				.map(|maximum| maximum.min(limits.max_memory_pages))
+				.unwrap_or(limits.max_memory_pages);
+			*entry = MemoryType::new(initial, Some(maximum));
+		}
+	}
+
+	if limits.gas.is_some() {
+		module = pwasm_utils::inject_gas_counter(module, &Default::default())
+			.map_err(|_| Error::InvalidCode)?;
+	}
+
+	if let Some(max_stack_height) = limits.max_stack_height {
+		let globals_before = global_count(&module);
+		module = pwasm_utils::stack_height::inject_limiter(module, max_stack_height)
+			.map_err(|_| Error::InvalidCode)?;
+		if global_count(&module) != globals_before + 1 {
+			return Err(Error::InvalidCode)
+		}
+		module = export_stack_height(module, globals_before);
+	}
+
+	Ok(module)
+}
+
+fn global_count(module: &Module) -> u32 {
+	let imported = module.import_section()
+		.map(|section| section.entries().iter().filter(|entry| match entry.external() {
+			External::Global(_) => true,
+			_ => false,
+		}).count())
+		.unwrap_or(0);
+	let defined = module.global_section()
+		.map(|section| section.entries().len())
+		.unwrap_or(0);
+
+	(imported + defined) as u32
+}
+
+fn function_count(module: &Module) -> u32 {
+	(module.import_count(elements::ImportCountType::Function) +
+		module.function_section().map(|section| section.entries().len()).unwrap_or(0)) as u32
+}
+
+/// Mutable globals cannot be exported, so export a function getting the
+/// stack height counter instead.
+fn export_stack_height(module: Module, global: u32) -> Module {
+	let getter = function_count(&module);
+
+	builder::from_module(module)
+		.function()
+			.signature().return_type().i32().build()
+			.body().with_instructions(Instructions::new(vec![
+				Instruction::GetGlobal(global),
+				Instruction::End,
+			])).build()
+			.build()
+		.export().field(STACK_HEIGHT_EXPORT).internal().func(getter).build()
+		.build()
+}
+
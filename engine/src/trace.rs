@@ -0,0 +1,51 @@
+use serde::Serialize;
+use std::time::Duration;
+
+/// Call into an export of the runtime.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CallTrace {
+	/// Name of the export.
+	pub export: String,
+	/// Wall-clock duration of the call, in microseconds.
+	pub micros: u64,
+	/// Bytes written into the runtime memory for the call.
+	pub bytes_in: usize,
+	/// Bytes read from the runtime memory after the call.
+	pub bytes_out: usize,
+	/// Memory size after the call, in pages.
+	pub memory_pages: u32,
+}
+
+/// Record of one block execution, collected when tracing is enabled.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Trace {
+	/// Hex-encoded id of the block as reported by the runtime, if the
+	/// execution succeeded.
+	pub block_id: Option<String>,
+	/// Calls into runtime exports, in order.
+	pub calls: Vec<CallTrace>,
+	/// Memory size before the execution, in pages.
+	pub initial_memory_pages: u32,
+	/// Memory size after the execution, in pages.
+	pub final_memory_pages: u32,
+	/// Messages printed by the runtime through host functions, truncated
+	/// to their first kilobyte.
+	pub messages: Vec<String>,
+	/// Error of the execution, if it failed.
+	pub error: Option<String>,
+}
+
+impl Trace {
+	/// Total time spent in runtime exports.
+	pub fn duration(&self) -> Duration {
+		Duration::from_micros(self.calls.iter().map(|call| call.micros).sum())
+	}
+
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).expect("Trace only contains serializable data")
+	}
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,192 @@
+use crate::host::is_host_function;
+use parity_wasm::elements::{
+	External, FunctionType, Instruction, Internal, Module, Type, ValueType,
+};
+use std::fmt;
+
+/// Reason runtime code was rejected.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ValidationError {
+	/// Code is not a valid wasm module.
+	Parse,
+	/// Module has a start function.
+	HasStart,
+	/// Required export is missing.
+	MissingExport(&'static str),
+	/// Export has the wrong kind or signature.
+	InvalidExport(&'static str),
+	/// Import is not a function of a supported host ABI version.
+	InvalidImport(String, String),
+	/// Module uses floating point types or instructions.
+	FloatingPoint,
+	/// Module cannot be instrumented or instantiated under the limits of
+	/// the executor.
+	Instantiation(String),
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+/// Exported functions required by the engine ABI, with their parameters
+/// and return type.
+const REQUIRED_FUNCTIONS: &[(&str, &[ValueType], Option<ValueType>)] = &[
+	("write_block", &[ValueType::I32], Some(ValueType::I32)),
+	("write_code", &[ValueType::I32], Some(ValueType::I32)),
+	("execute", &[], Some(ValueType::I32)),
+	("read_metadata", &[], Some(ValueType::I32)),
+	("free", &[], None),
+];
+
+/// Exported functions the engine uses if present, with their parameters
+/// and return type.
+const OPTIONAL_FUNCTIONS: &[(&str, &[ValueType], Option<ValueType>)] = &[
+	("version", &[], Some(ValueType::I32)),
+	("write_query", &[ValueType::I32], Some(ValueType::I32)),
+	("write_proof", &[ValueType::I32], Some(ValueType::I32)),
+	("write_root", &[ValueType::I32], Some(ValueType::I32)),
+	("query", &[], Some(ValueType::I32)),
+	("read_query_response", &[], Some(ValueType::I32)),
+];
+
+/// Check that code can be used as the runtime of the chain.
+pub fn validate_code(code: &[u8]) -> Result<(), ValidationError> {
+	let module = parity_wasm::deserialize_buffer::<Module>(code)
+		.map_err(|_| ValidationError::Parse)?;
+
+	if module.start_section().is_some() {
+		return Err(ValidationError::HasStart)
+	}
+
+	match export(&module, "memory") {
+		Some(Internal::Memory(_)) => (),
+		Some(_) => return Err(ValidationError::InvalidExport("memory")),
+		None => return Err(ValidationError::MissingExport("memory")),
+	}
+
+	let functions = REQUIRED_FUNCTIONS.iter().map(|function| (true, function))
+		.chain(OPTIONAL_FUNCTIONS.iter().map(|function| (false, function)));
+	for (required, (name, params, ret)) in functions {
+		let index = match export(&module, name) {
+			Some(Internal::Function(index)) => index,
+			Some(_) => return Err(ValidationError::InvalidExport(*name)),
+			None if required => return Err(ValidationError::MissingExport(*name)),
+			None => continue,
+		};
+		match function_type(&module, index) {
+			Some(ty) if ty.params() == *params && ty.return_type() == *ret => (),
+			_ => return Err(ValidationError::InvalidExport(*name)),
+		}
+	}
+
+	if let Some(section) = module.import_section() {
+		for entry in section.entries() {
+			let valid = match entry.external() {
+				External::Function(type_ref) => match type_at(&module, *type_ref) {
+					Some(ty) => is_host_function(
+						entry.module(), entry.field(), ty.params(), ty.return_type(),
+					),
+					None => false,
+				},
+				_ => false,
+			};
+			if !valid {
+				return Err(ValidationError::InvalidImport(
+					entry.module().to_string(), entry.field().to_string(),
+				))
+			}
+		}
+	}
+
+	if uses_floating_point(&module) {
+		return Err(ValidationError::FloatingPoint)
+	}
+
+	Ok(())
+}
+
+fn export(module: &Module, name: &str) -> Option<Internal> {
+	module.export_section()?.entries().iter()
+		.find(|entry| entry.field() == name)
+		.map(|entry| *entry.internal())
+}
+
+fn type_at(module: &Module, index: u32) -> Option<&FunctionType> {
+	match module.type_section()?.types().get(index as usize)? {
+		Type::Function(ty) => Some(ty),
+	}
+}
+
+fn function_type(module: &Module, index: u32) -> Option<&FunctionType> {
+	let imported = module.import_section()
+		.map(|section| section.entries().iter().filter_map(|entry| match entry.external() {
+			External::Function(type_ref) => Some(*type_ref),
+			_ => None,
+		}).collect::<Vec<_>>())
+		.unwrap_or_default();
+
+	let type_ref = match imported.get(index as usize) {
+		Some(type_ref) => *type_ref,
+		None => module.function_section()?.entries()
+			.get(index as usize - imported.len())?
+			.type_ref(),
+	};
+	type_at(module, type_ref)
+}
+
+fn is_float(ty: ValueType) -> bool {
+	match ty {
+		ValueType::F32 | ValueType::F64 => true,
+		_ => false,
+	}
+}
+
+fn uses_floating_point(module: &Module) -> bool {
+	let types = module.type_section()
+		.map(|section| section.types().iter().any(|ty| match ty {
+			Type::Function(ty) => ty.params().iter().cloned().any(is_float) ||
+				ty.return_type().map(is_float).unwrap_or(false),
+		}))
+		.unwrap_or(false);
+	let globals = module.global_section()
+		.map(|section| section.entries().iter()
+			.any(|entry| is_float(entry.global_type().content_type())))
+		.unwrap_or(false);
+	let imported_globals = module.import_section()
+		.map(|section| section.entries().iter().any(|entry| match entry.external() {
+			External::Global(ty) => is_float(ty.content_type()),
+			_ => false,
+		}))
+		.unwrap_or(false);
+	let code = module.code_section()
+		.map(|section| section.bodies().iter().any(|body| {
+			body.locals().iter().any(|local| is_float(local.value_type())) ||
+				body.code().elements().iter().any(is_float_instruction)
+		}))
+		.unwrap_or(false);
+
+	types || globals || imported_globals || code
+}
+
+fn is_float_instruction(instruction: &Instruction) -> bool {
+	use parity_wasm::elements::Instruction::*;
+
+	match instruction {
+		F32Load(_, _) | F64Load(_, _) | F32Store(_, _) | F64Store(_, _) |
+		F32Const(_) | F64Const(_) |
+		F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge |
+		F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge |
+		F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt |
+		F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign |
+		F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt |
+		F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign |
+		I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 |
+		I64TruncSF32 | I64TruncUF32 | I64TruncSF64 | I64TruncUF64 |
+		F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64 |
+		F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32 |
+		I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => true,
+		_ => false,
+	}
+}
@@ -0,0 +1,101 @@
+extern crate solri_engine as engine;
+
+mod common;
+
+use common::TestRuntime;
+use engine::{Cache, CacheStats, ExecutionLimits};
+
+/// Runtime counting its executions in both memory and a global, and
+/// reporting the counts as timestamp and difficulty.
+fn counting_runtime() -> Vec<u8> {
+	TestRuntime::new()
+		.item("(global $count (mut i64) (i64.const 0))")
+		.item("(global $code_len (mut i32) (i32.const 0))")
+		.write_code("(set_global $code_len (get_local 0)) (i32.const 2048)")
+		.execute("
+			(i64.store (i32.const 64) (i64.add (i64.load (i32.const 64)) (i64.const 1)))
+			(set_global $count (i64.add (get_global $count) (i64.const 1)))
+			(i64.store (i32.const 0) (i64.load (i32.const 64)))
+			(i64.store (i32.const 8) (get_global $count))
+			(i32.store (i32.const 32) (i32.const 2048))
+			(i32.store (i32.const 36) (get_global $code_len))
+			(i32.const 0)")
+		.build()
+}
+
+fn runtime(id: u32) -> Vec<u8> {
+	// Append a custom section, giving distinct code with identical behavior.
+	let mut code = counting_runtime();
+	code.extend_from_slice(&[0, 2, 1, id as u8]);
+	code
+}
+
+#[test]
+fn reuse_instances() {
+	let mut cache = Cache::new(2);
+	let code = counting_runtime();
+
+	for _ in 0..3 {
+		let metadata = cache.execute(b"block", &code).unwrap();
+		assert_eq!(metadata.code, code);
+	}
+	assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 1 });
+	assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn reset_state_between_executions() {
+	let mut cache = Cache::new(1);
+	let code = counting_runtime();
+
+	for _ in 0..3 {
+		let metadata = cache.execute(b"block", &code).unwrap();
+		assert_eq!(metadata.timestamp, 1);
+		assert_eq!(metadata.difficulty, 1);
+	}
+}
+
+#[test]
+fn evict_least_recently_used() {
+	let mut cache = Cache::new(2);
+	let (a, b, c) = (runtime(1), runtime(2), runtime(3));
+
+	cache.execute(b"block", &a).unwrap();
+	cache.execute(b"block", &b).unwrap();
+	cache.execute(b"block", &a).unwrap();
+	// Evicts b, the least recently used.
+	cache.execute(b"block", &c).unwrap();
+	assert_eq!(cache.len(), 2);
+	assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 3 });
+
+	cache.execute(b"block", &a).unwrap();
+	assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 3 });
+	cache.execute(b"block", &b).unwrap();
+	assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 4 });
+}
+
+#[test]
+fn grow_memory_on_every_execution() {
+	// Runtime allocating a fresh page for every block, as the allocator of
+	// a runtime starting over with an empty heap does.
+	let code = TestRuntime::new()
+		.item("(global $code_len (mut i32) (i32.const 0))")
+		.write_code("(set_global $code_len (get_local 0)) (i32.const 2048)")
+		.execute("
+			(if (i32.eq (grow_memory (i32.const 1)) (i32.const -1))
+				(then (return (i32.const 1))))
+			(i32.store (i32.const 32) (i32.const 2048))
+			(i32.store (i32.const 36) (get_global $code_len))
+			(i32.const 0)")
+		.build();
+	let max_memory_pages = 4;
+	let mut cache = Cache::new_with_limits(1, ExecutionLimits {
+		max_memory_pages,
+		..Default::default()
+	});
+
+	for _ in 0..(max_memory_pages * 3) {
+		cache.execute(b"block", &code).unwrap();
+	}
+	assert_eq!(cache.stats().misses, 1);
+}
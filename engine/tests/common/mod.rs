@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use engine::CodeExternalities;
+
+/// Code storage holding only the runtime code.
+pub struct Code(pub Vec<u8>);
+
+impl CodeExternalities for Code {
+	fn code(&self) -> &Vec<u8> { &self.0 }
+	fn code_mut(&mut self) -> &mut Vec<u8> { &mut self.0 }
+}
+
+/// Minimal runtime implementing the engine ABI. Tests override the parts
+/// they exercise, and keep the defaults for the rest.
+pub struct TestRuntime {
+	imports: Vec<String>,
+	memory_pages: u32,
+	items: Vec<String>,
+	write_block: String,
+	write_code: String,
+	execute: String,
+	read_metadata: String,
+}
+
+impl Default for TestRuntime {
+	fn default() -> Self {
+		Self {
+			imports: Vec::new(),
+			memory_pages: 1,
+			items: Vec::new(),
+			write_block: "(i32.const 1024)".to_string(),
+			write_code: "(i32.const 2048)".to_string(),
+			execute: "(i32.const 0)".to_string(),
+			read_metadata: "(i32.const 0)".to_string(),
+		}
+	}
+}
+
+impl TestRuntime {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add an import. Imports come before every other definition.
+	pub fn import(mut self, import: &str) -> Self {
+		self.imports.push(import.to_string());
+		self
+	}
+
+	pub fn memory_pages(mut self, memory_pages: u32) -> Self {
+		self.memory_pages = memory_pages;
+		self
+	}
+
+	/// Add a definition, such as a global, a data segment or a function.
+	pub fn item(mut self, item: &str) -> Self {
+		self.items.push(item.to_string());
+		self
+	}
+
+	/// Body of `write_block`, which gets the block length as local 0.
+	pub fn write_block(mut self, body: &str) -> Self {
+		self.write_block = body.to_string();
+		self
+	}
+
+	/// Body of `write_code`, which gets the code length as local 0.
+	pub fn write_code(mut self, body: &str) -> Self {
+		self.write_code = body.to_string();
+		self
+	}
+
+	pub fn execute(mut self, body: &str) -> Self {
+		self.execute = body.to_string();
+		self
+	}
+
+	pub fn read_metadata(mut self, body: &str) -> Self {
+		self.read_metadata = body.to_string();
+		self
+	}
+
+	pub fn wat(&self) -> String {
+		format!(r#"
+			(module
+				{}
+				(memory (export "memory") {})
+				{}
+				(func (export "write_block") (param i32) (result i32)
+					{})
+				(func (export "write_code") (param i32) (result i32)
+					{})
+				(func (export "execute") (result i32)
+					{})
+				(func (export "read_metadata") (result i32)
+					{})
+				(func (export "free"))
+			)
+		"#,
+			self.imports.join("\n"),
+			self.memory_pages,
+			self.items.join("\n"),
+			self.write_block,
+			self.write_code,
+			self.execute,
+			self.read_metadata,
+		)
+	}
+
+	pub fn build(&self) -> Vec<u8> {
+		wabt::wat2wasm(self.wat()).unwrap()
+	}
+
+	/// Build without checking the module, for runtimes the engine must
+	/// reject.
+	pub fn build_unchecked(&self) -> Vec<u8> {
+		wabt::Wat2Wasm::new().validate(false).convert(self.wat()).unwrap().as_ref().to_vec()
+	}
+}
@@ -0,0 +1,92 @@
+extern crate solri_engine as engine;
+extern crate solri_runtime as runtime;
+
+mod common;
+
+use common::TestRuntime;
+use engine::{Dispatch, Dispatcher, Instance, RuntimeVersion};
+use std::sync::Arc;
+
+struct Native;
+struct Wasm;
+
+/// Runtime exporting the given version, encoded as spec version, ABI
+/// version and spec name.
+fn runtime_with_version(spec_version: u8, abi_version: u8, spec_name: &str) -> Vec<u8> {
+	TestRuntime::new()
+		.item(&format!(
+			r#"(data (i32.const 16) "\{:02x}\00\00\00\{:02x}\00\00\00{}")"#,
+			spec_version, abi_version, spec_name,
+		))
+		.item(&format!(r#"
+			(func (export "version") (result i32)
+				(i32.store (i32.const 0) (i32.const 16))
+				(i32.store (i32.const 4) (i32.const {}))
+				(i32.const 0))"#, 8 + spec_name.len()))
+		.execute("(i32.const 1)")
+		.build()
+}
+
+fn is_native(dispatch: Dispatch<Native, Wasm>) -> bool {
+	match dispatch {
+		Dispatch::Native(_) => true,
+		Dispatch::Wasm(_) => false,
+	}
+}
+
+#[test]
+fn read_runtime_version() {
+	let instance = Instance::new(Arc::new(runtime::WASM_BINARY.to_vec())).unwrap();
+	assert_eq!(instance.version().unwrap(), Some(runtime::version()));
+
+	let instance = Instance::new(Arc::new(runtime_with_version(3, 1, "solri"))).unwrap();
+	assert_eq!(instance.version().unwrap(), Some(RuntimeVersion {
+		spec_name: b"solri".to_vec(),
+		spec_version: 3,
+		abi_version: 1,
+	}));
+}
+
+#[test]
+fn dispatch_by_version() {
+	let native_version = RuntimeVersion {
+		spec_name: b"solri".to_vec(),
+		spec_version: 2,
+		abi_version: 1,
+	};
+	let dispatcher = Dispatcher::new(Native, native_version, Wasm);
+
+	assert!(is_native(dispatcher.dispatch(&runtime_with_version(2, 1, "solri"))));
+	assert!(!is_native(dispatcher.dispatch(&runtime_with_version(2, 1, "other"))));
+	assert!(!is_native(dispatcher.dispatch(&runtime_with_version(2, 2, "solri"))));
+	assert!(!is_native(dispatcher.dispatch(b"not wasm")));
+}
+
+#[test]
+fn dispatch_across_upgrades() {
+	let dispatcher = Dispatcher::new(Native, runtime::version(), Wasm);
+	let native_version = runtime::version();
+
+	// The genesis runtime runs natively.
+	assert!(is_native(dispatcher.dispatch(runtime::WASM_BINARY)));
+
+	// An upgrade bumping the spec version falls back to wasm.
+	let upgraded = runtime_with_version(
+		native_version.spec_version as u8 + 1,
+		native_version.abi_version as u8,
+		"solri",
+	);
+	assert!(!is_native(dispatcher.dispatch(&upgraded)));
+
+	// Code changes not bumping the version keep running natively.
+	let same_version = runtime_with_version(
+		native_version.spec_version as u8,
+		native_version.abi_version as u8,
+		"solri",
+	);
+	assert!(is_native(dispatcher.dispatch(&same_version)));
+
+	// Switching back is picked up again.
+	assert!(is_native(dispatcher.dispatch(runtime::WASM_BINARY)));
+	assert!(!is_native(dispatcher.dispatch(&upgraded)));
+}
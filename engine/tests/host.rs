@@ -0,0 +1,90 @@
+extern crate solri_engine as engine;
+
+mod common;
+
+use common::{Code, TestRuntime};
+use sha3::{Digest, Sha3_256};
+use std::sync::Arc;
+
+fn host_runtime() -> Vec<u8> {
+	TestRuntime::new()
+		.import(r#"(import "solri_host_v1" "ext_sha3_256" (func $sha3_256 (param i32 i32 i32)))"#)
+		.import(r#"(import "solri_host_v1" "ext_debug_print" (func $debug_print (param i32 i32)))"#)
+		.import(r#"(import "solri_host_v1" "ext_malloc" (func $malloc (param i32) (result i32)))"#)
+		.import(r#"(import "solri_host_v1" "ext_code_len" (func $code_len (result i32)))"#)
+		.item("(global $block_len (mut i32) (i32.const 0))")
+		.item("(global $code_len (mut i32) (i32.const 0))")
+		.write_block("(set_global $block_len (get_local 0)) (i32.const 1024)")
+		.write_code("(set_global $code_len (get_local 0)) (i32.const 8192)")
+		.execute("
+			(local $ptr i32)
+			(call $sha3_256 (i32.const 1024) (get_global $block_len) (i32.const 512))
+			(call $debug_print (i32.const 1024) (get_global $block_len))
+			(set_local $ptr (call $malloc (i32.const 4)))
+			(i32.store (get_local $ptr) (call $code_len))
+
+			(i64.store (i32.const 0) (i64.const 1))
+			(i64.store (i32.const 8) (i64.const 2))
+			(i32.store (i32.const 16) (get_local $ptr))
+			(i32.store (i32.const 20) (i32.const 4))
+			(i32.store (i32.const 24) (i32.const 512))
+			(i32.store (i32.const 28) (i32.const 32))
+			(i32.store (i32.const 32) (i32.const 8192))
+			(i32.store (i32.const 36) (get_global $code_len))
+			(i32.const 0)")
+		.build()
+}
+
+#[test]
+fn call_host_functions() {
+	let code = host_runtime();
+	let instance = engine::Instance::new(Arc::new(code.clone())).unwrap();
+	let storage = Code(vec![0u8; 42]);
+
+	let block = b"hello host".to_vec();
+	let metadata = instance.execute_with_code(&block, &storage).unwrap();
+	assert_eq!(metadata.timestamp, 1);
+	assert_eq!(metadata.difficulty, 2);
+	assert_eq!(metadata.id, Sha3_256::digest(&block).to_vec());
+	assert_eq!(metadata.parent_id, 42u32.to_le_bytes().to_vec());
+	assert_eq!(metadata.code, code);
+
+	// Host allocations are released after each execution.
+	let metadata = instance.execute_with_code(&block, &storage).unwrap();
+	assert_eq!(metadata.parent_id, 42u32.to_le_bytes().to_vec());
+
+	// Storage access traps without code externalities.
+	assert!(instance.execute(&block).is_err());
+}
+
+#[test]
+fn reject_unknown_host_imports() {
+	let unknown_version = wabt::wat2wasm(r#"
+		(module
+			(import "solri_host_v1000" "ext_debug_print" (func (param i32 i32)))
+			(memory (export "memory") 1))
+	"#).unwrap();
+	assert!(engine::Instance::new(Arc::new(unknown_version)).is_err());
+
+	let unknown_function = wabt::wat2wasm(r#"
+		(module
+			(import "solri_host_v1" "ext_unknown" (func))
+			(memory (export "memory") 1))
+	"#).unwrap();
+	assert!(engine::Instance::new(Arc::new(unknown_function)).is_err());
+
+	let invalid_signature = wabt::wat2wasm(r#"
+		(module
+			(import "solri_host_v1" "ext_debug_print" (func (param i32)))
+			(memory (export "memory") 1))
+	"#).unwrap();
+	assert!(engine::Instance::new(Arc::new(invalid_signature)).is_err());
+
+	let newer_function = wabt::wat2wasm(r#"
+		(module
+			(import "solri_host_v1" "ext_sr25519_verify"
+				(func (param i32 i32 i32 i32 i32 i32) (result i32)))
+			(memory (export "memory") 1))
+	"#).unwrap();
+	assert!(engine::Instance::new(Arc::new(newer_function)).is_err());
+}
@@ -0,0 +1,106 @@
+#![cfg(feature = "jit")]
+
+extern crate solri_engine as engine;
+extern crate solri_runtime as runtime;
+
+mod common;
+
+use blockchain::{BlockExecutor, ExtrinsicBuilder};
+use common::{Code, TestRuntime};
+use engine::{ExecutionLimits, GenericBlock, Instance, JitExecutor, JitInstance};
+use parity_codec::Encode;
+use runtime::{Block, Call, Extrinsic};
+use std::sync::Arc;
+
+fn blocks(count: usize) -> Vec<Block> {
+	let executor = runtime::Executor;
+	let mut trie = runtime::InMemoryTrie::default();
+	let keypair = schnorrkel::Keypair::generate(&mut rand::rngs::OsRng::new().unwrap());
+	let mut parent = Block::genesis();
+	let mut blocks = Vec::new();
+
+	for i in 0..count {
+		let mut build_block = executor.initialize_block(&parent, &mut trie, 1234 + i as u64).unwrap();
+		let extrinsic = Extrinsic::sign(Call::Add(i as u64), i as u64, &keypair);
+		executor.apply_extrinsic(&mut build_block, extrinsic, &mut trie).unwrap();
+		executor.finalize_block(&mut build_block, &mut trie).unwrap();
+		let block = build_block.seal();
+		blocks.push(block.clone());
+		parent = block;
+	}
+
+	blocks
+}
+
+#[test]
+fn same_metadata_as_interpreter() {
+	let code = runtime::WASM_BINARY.to_vec();
+	let interpreted = Instance::new(Arc::new(code.clone())).unwrap();
+	let compiled = JitInstance::new(&Default::default(), code.clone(), ExecutionLimits::default())
+		.unwrap();
+	let storage = Code(code);
+
+	for block in blocks(4) {
+		let block = block.encode();
+		assert_eq!(
+			interpreted.execute_with_code(&block, &storage).unwrap(),
+			compiled.execute_with_code(&block, &storage).unwrap(),
+		);
+	}
+}
+
+/// Limits leaving the runtime only a few pages over its initial memory, so
+/// that memory leaking from one execution to the next runs out quickly.
+fn tight_limits() -> ExecutionLimits {
+	let module = parity_wasm::deserialize_buffer::<parity_wasm::elements::Module>(
+		runtime::WASM_BINARY
+	).unwrap();
+	let initial = module.memory_section()
+		.map(|section| section.entries()[0].limits().initial())
+		.unwrap_or(0);
+
+	ExecutionLimits {
+		max_memory_pages: initial + 32,
+		..Default::default()
+	}
+}
+
+#[test]
+fn same_result_as_interpreter() {
+	let limits = tight_limits();
+	let interpreted = engine::Executor::new_with_limits(1, limits.clone());
+	let compiled = JitExecutor::new_with_limits(1, limits);
+	let mut interpreted_state = Code(runtime::WASM_BINARY.to_vec());
+	let mut compiled_state = Code(runtime::WASM_BINARY.to_vec());
+
+	// More blocks than pages left, all on the same cached instances.
+	for block in blocks(64) {
+		let block: GenericBlock = block.into();
+		interpreted.execute_block(&block, &mut interpreted_state).unwrap();
+		compiled.execute_block(&block, &mut compiled_state).unwrap();
+		assert_eq!(interpreted_state.0, compiled_state.0);
+	}
+}
+
+#[test]
+fn stop_infinite_loop() {
+	let code = TestRuntime::new()
+		.execute("(loop $forever (br $forever)) (i32.const 0)")
+		.build();
+	let instance = JitInstance::new(&Default::default(), code, ExecutionLimits {
+		gas: Some(100_000),
+		..Default::default()
+	}).unwrap();
+
+	match instance.execute(b"block") {
+		Err(engine::Error::OutOfGas) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn reject_code_failing_to_instantiate() {
+	let code = TestRuntime::new().execute("(i64.const 0)").build_unchecked();
+
+	assert!(JitInstance::new(&Default::default(), code, ExecutionLimits::default()).is_err());
+}
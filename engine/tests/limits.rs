@@ -0,0 +1,94 @@
+extern crate solri_engine as engine;
+
+mod common;
+
+use common::TestRuntime;
+use engine::{Error, ExecutionLimits, Instance};
+use std::sync::Arc;
+
+fn runtime(execute: &str, memory_pages: u32) -> Vec<u8> {
+	TestRuntime::new()
+		.memory_pages(memory_pages)
+		.item("
+			(func $recurse (param i32) (result i32)
+				(call $recurse (i32.add (get_local 0) (i32.const 1))))")
+		.execute(execute)
+		.build()
+}
+
+#[test]
+fn stop_infinite_loop() {
+	let code = runtime("(loop $forever (br $forever)) (i32.const 0)", 1);
+	let instance = Instance::new_with_limits(Arc::new(code), ExecutionLimits {
+		gas: Some(100_000),
+		..Default::default()
+	}).unwrap();
+
+	match instance.execute(b"block") {
+		Err(Error::OutOfGas) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+	// Gas is refilled for every execution.
+	match instance.execute(b"block") {
+		Err(Error::OutOfGas) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn stop_unbounded_recursion() {
+	let code = runtime("(drop (call $recurse (i32.const 0))) (i32.const 0)", 1);
+	let instance = Instance::new_with_limits(Arc::new(code), ExecutionLimits {
+		gas: None,
+		max_stack_height: Some(1024),
+		..Default::default()
+	}).unwrap();
+
+	match instance.execute(b"block") {
+		Err(Error::StackLimitExceeded) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn cap_memory_pages() {
+	let code = runtime("(i32.const 0)", 16);
+	let limits = ExecutionLimits {
+		max_memory_pages: 8,
+		..Default::default()
+	};
+	match Instance::new_with_limits(Arc::new(code), limits.clone()) {
+		Err(Error::MemoryLimitExceeded) => (),
+		Err(err) => panic!("unexpected error {:?}", err),
+		Ok(_) => panic!("instance over the memory limit was created"),
+	}
+
+	let code = runtime("(grow_memory (i32.const 16))", 1);
+	let instance = Instance::new_with_limits(Arc::new(code), limits).unwrap();
+	// Growing over the limit fails inside the runtime, which returns -1.
+	assert!(instance.execute(b"block").is_err());
+}
+
+#[test]
+fn charge_host_function_work() {
+	// Few instructions, but each call hashes a whole page.
+	let code = TestRuntime::new()
+		.import(r#"(import "solri_host_v1" "ext_sha3_256" (func $sha3_256 (param i32 i32 i32)))"#)
+		.execute("
+			(local $i i32)
+			(loop $hash
+				(call $sha3_256 (i32.const 0) (i32.const 65536) (i32.const 0))
+				(set_local $i (i32.add (get_local $i) (i32.const 1)))
+				(br_if $hash (i32.lt_u (get_local $i) (i32.const 1000))))
+			(i32.const 0)")
+		.build();
+	let instance = Instance::new_with_limits(Arc::new(code), ExecutionLimits {
+		gas: Some(1_000_000),
+		..Default::default()
+	}).unwrap();
+
+	match instance.execute(b"block") {
+		Err(Error::OutOfGas) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
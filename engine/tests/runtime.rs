@@ -1,21 +1,31 @@
 extern crate solri_engine as engine;
 extern crate solri_runtime as runtime;
 
-use parity_codec::Encode;
-use runtime::{Block, Executor, Extrinsic};
+mod common;
+
+use common::TestRuntime;
+use parity_codec::{Encode, Decode};
+use primitive_types::H256;
+use runtime::{Block, Call, Executor, Extrinsic, Query, QueryResponse};
 use blockchain::{Block as _, ExtrinsicBuilder};
 
 use std::sync::Arc;
 
+fn keypair() -> schnorrkel::Keypair {
+	schnorrkel::Keypair::generate(&mut rand::rngs::OsRng::new().unwrap())
+}
+
 #[test]
 fn call_runtime() {
 	let instance = engine::Instance::new(Arc::new(runtime::WASM_BINARY.to_vec())).unwrap();
 	let genesis_block = Block::genesis();
 	let executor = Executor;
 	let mut trie = runtime::InMemoryTrie::default();
+	let keypair = keypair();
 
 	let mut build_block = executor.initialize_block(&genesis_block, &mut trie, 1234).unwrap();
-	executor.apply_extrinsic(&mut build_block, Extrinsic::Add(5), &mut trie).unwrap();
+	let extrinsic = Extrinsic::sign(Call::Add(5), 0, &keypair);
+	executor.apply_extrinsic(&mut build_block, extrinsic, &mut trie).unwrap();
 	executor.finalize_block(&mut build_block, &mut trie).unwrap();
 	let block = build_block.seal();
 
@@ -26,3 +36,131 @@ fn call_runtime() {
 	assert_eq!(metadata.id, block.id()[..].to_vec());
 	assert_eq!(metadata.code, runtime::WASM_BINARY.to_vec());
 }
+
+#[test]
+fn query_runtime() {
+	let instance = engine::Instance::new(Arc::new(runtime::WASM_BINARY.to_vec())).unwrap();
+	let executor = Executor;
+	let mut trie = runtime::InMemoryTrie::default();
+	let keypair = keypair();
+	let signer = H256::from_slice(&keypair.public.to_bytes());
+
+	let mut build_block = executor.initialize_block(&Block::genesis(), &mut trie, 1234).unwrap();
+	for (nonce, value) in vec![5, 7].into_iter().enumerate() {
+		let extrinsic = Extrinsic::sign(Call::Add(value), nonce as u64, &keypair);
+		executor.apply_extrinsic(&mut build_block, extrinsic, &mut trie).unwrap();
+	}
+	executor.finalize_block(&mut build_block, &mut trie).unwrap();
+	let block = build_block.seal();
+	let root = block.state.encode();
+
+	for (query, expected) in vec![
+		(Query::Length, QueryResponse::Length(2)),
+		(Query::Get(1), QueryResponse::Get(Some(H256::from_low_u64_le(7)))),
+		(Query::Get(2), QueryResponse::Get(None)),
+		(Query::Nonce(signer), QueryResponse::Nonce(2)),
+		(Query::Nonce(H256::zero()), QueryResponse::Nonce(0)),
+	] {
+		let proof = runtime::query_proof(&query, block.state.clone(), &mut trie).unwrap();
+		let response = instance.query(&query.encode(), &proof.encode(), &root).unwrap();
+		assert_eq!(response, runtime::query(&query.encode(), &proof.encode(), &root).unwrap());
+		assert_eq!(QueryResponse::decode(&mut &response[..]), Some(expected));
+	}
+
+	// Proofs not covering the query are rejected.
+	let proof = runtime::query_proof(&Query::Length, block.state.clone(), &mut trie).unwrap();
+	match instance.query(&Query::Get(0).encode(), &proof.encode(), &root) {
+		Err(engine::Error::Runtime(engine::RuntimeError::InvalidQuery)) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	// Proofs of another state are rejected, even if they answer the query.
+	let genesis_root = Block::genesis().state.encode();
+	match instance.query(&Query::Length.encode(), &proof.encode(), &genesis_root) {
+		Err(engine::Error::Runtime(engine::RuntimeError::InvalidQuery)) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn decode_runtime_errors() {
+	let instance = engine::Instance::new(Arc::new(runtime::WASM_BINARY.to_vec())).unwrap();
+	match instance.execute(b"not a block") {
+		Err(engine::Error::Runtime(engine::RuntimeError::InvalidBlock)) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	let executor = Executor;
+	let mut trie = runtime::InMemoryTrie::default();
+	let mut build_block = executor.initialize_block(&Block::genesis(), &mut trie, 1234).unwrap();
+	let keypair = keypair();
+	let extrinsic = Extrinsic::sign(Call::Add(5), 0, &keypair);
+	executor.apply_extrinsic(&mut build_block, extrinsic, &mut trie).unwrap();
+	let block = build_block.seal();
+
+	let mut invalid_state = block.clone();
+	invalid_state.state = Default::default();
+	match instance.execute(&invalid_state.encode()) {
+		Err(engine::Error::Runtime(engine::RuntimeError::InvalidBlock)) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	// Blocks carrying an incomplete proof of their parent state are invalid,
+	// rather than a fault of the node executing them.
+	let mut build_block = executor.initialize_block(&block, &mut trie, 1235).unwrap();
+	let extrinsic = Extrinsic::sign(Call::Add(7), 1, &keypair);
+	executor.apply_extrinsic(&mut build_block, extrinsic, &mut trie).unwrap();
+	let mut incomplete_proof = build_block.seal();
+	incomplete_proof.parent_state =
+		runtime::query_proof(&Query::Length, block.state.clone(), &mut trie).unwrap();
+	match instance.execute(&incomplete_proof.encode()) {
+		Err(engine::Error::Runtime(engine::RuntimeError::InvalidBlock)) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	let mut invalid_signature = block.clone();
+	invalid_signature.extrinsics[0].call = Call::Add(6);
+	match instance.execute(&invalid_signature.encode()) {
+		Err(engine::Error::Runtime(engine::RuntimeError::InvalidExtrinsic)) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	// Runtimes without error codes still fail with a generic error.
+	let legacy = TestRuntime::new().execute("(i32.const 1)").build();
+	let instance = engine::Instance::new(Arc::new(legacy)).unwrap();
+	match instance.execute(b"block") {
+		Err(engine::Error::ExecutionFailed) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn reject_invalid_extrinsics() {
+	let executor = Executor;
+	let mut trie = runtime::InMemoryTrie::default();
+	let keypair = keypair();
+	let mut build_block = executor.initialize_block(&Block::genesis(), &mut trie, 1234).unwrap();
+
+	let mut tampered = Extrinsic::sign(Call::Add(5), 0, &keypair);
+	tampered.call = Call::Add(6);
+	match executor.apply_extrinsic(&mut build_block, tampered, &mut trie) {
+		Err(runtime::Error::InvalidSignature) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	let wrong_nonce = Extrinsic::sign(Call::Add(5), 1, &keypair);
+	match executor.apply_extrinsic(&mut build_block, wrong_nonce, &mut trie) {
+		Err(runtime::Error::InvalidNonce) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	let extrinsic = Extrinsic::sign(Call::Add(5), 0, &keypair);
+	executor.apply_extrinsic(&mut build_block, extrinsic.clone(), &mut trie).unwrap();
+
+	// Replaying an applied extrinsic fails on its nonce.
+	match executor.apply_extrinsic(&mut build_block, extrinsic, &mut trie) {
+		Err(runtime::Error::InvalidNonce) => (),
+		other => panic!("unexpected result {:?}", other),
+	}
+	assert_eq!(build_block.extrinsics.len(), 1);
+}
@@ -0,0 +1,107 @@
+extern crate solri_engine as engine;
+
+mod common;
+
+use common::{Code, TestRuntime};
+use engine::{Executor, GenericBlock};
+use std::sync::Arc;
+
+const DEBUG_PRINT: &str =
+	r#"(import "solri_host_v1" "ext_debug_print" (func $debug_print (param i32 i32)))"#;
+
+fn traced_runtime() -> Vec<u8> {
+	TestRuntime::new()
+		.import(DEBUG_PRINT)
+		.import(r#"(import "solri_host_v1" "ext_malloc" (func $malloc (param i32) (result i32)))"#)
+		.item(r#"(data (i32.const 512) "executing")"#)
+		.item("(global $code_len (mut i32) (i32.const 0))")
+		.write_code("(set_global $code_len (get_local 0)) (i32.const 8192)")
+		.execute("
+			(call $debug_print (i32.const 512) (i32.const 9))
+			(drop (call $malloc (i32.const 65536)))
+
+			(i64.store (i32.const 0) (i64.const 1))
+			(i64.store (i32.const 8) (i64.const 2))
+			(i32.store (i32.const 16) (i32.const 1024))
+			(i32.store (i32.const 20) (i32.const 0))
+			(i32.store (i32.const 24) (i32.const 1024))
+			(i32.store (i32.const 28) (i32.const 4))
+			(i32.store (i32.const 32) (i32.const 8192))
+			(i32.store (i32.const 36) (get_global $code_len))
+			(i32.const 0)")
+		.build()
+}
+
+#[test]
+fn trace_execution() {
+	let code = traced_runtime();
+	let instance = engine::Instance::new(Arc::new(code.clone())).unwrap();
+
+	let (metadata, trace) = instance.execute_traced(b"blockdata", None);
+	let metadata = metadata.unwrap();
+	assert_eq!(metadata.id, b"bloc".to_vec());
+
+	assert_eq!(
+		trace.calls.iter().map(|call| call.export.as_str()).collect::<Vec<_>>(),
+		vec!["write_block", "write_code", "execute", "read_metadata", "free"],
+	);
+	assert_eq!(trace.calls[0].bytes_in, 9);
+	assert_eq!(trace.calls[1].bytes_in, code.len());
+	assert_eq!(trace.calls[3].bytes_out, 40 + 4 + code.len());
+	assert_eq!(trace.initial_memory_pages, 1);
+	assert!(trace.final_memory_pages > trace.initial_memory_pages);
+	assert_eq!(trace.messages, vec!["[runtime debug] executing".to_string()]);
+	assert_eq!(trace.block_id, Some("626c6f63".to_string()));
+	assert_eq!(trace.error, None);
+
+	let json = trace.to_json();
+	assert!(json.contains("\"block_id\": \"626c6f63\""));
+	assert!(json.contains("\"export\": \"execute\""));
+
+	// Tracing is opt-in.
+	instance.execute(b"blockdata").unwrap();
+	let (_, trace) = instance.execute_traced(b"blockdata", None);
+	assert_eq!(trace.calls.len(), 5);
+}
+
+#[test]
+fn trace_executor() {
+	let code = traced_runtime();
+	let executor = Executor::default();
+	let mut block = GenericBlock {
+		id: b"bloc".to_vec(),
+		parent_id: Some(Vec::new()),
+		difficulty: 2,
+		timestamp: 1,
+		data: b"blockdata".to_vec(),
+	};
+
+	let (result, trace) = executor.execute_block_traced(&block, &mut Code(code.clone()));
+	result.unwrap();
+	assert_eq!(trace.calls.len(), 5);
+	assert_eq!(trace.messages, vec!["[runtime debug] executing".to_string()]);
+	assert_eq!(trace.block_id, Some("626c6f63".to_string()));
+	assert_eq!(trace.error, None);
+
+	// Blocks not matching the metadata fail after the runtime is done.
+	block.timestamp = 2;
+	let (result, trace) = executor.execute_block_traced(&block, &mut Code(code));
+	assert!(result.is_err());
+	assert_eq!(trace.calls.len(), 5);
+	assert_eq!(trace.error, Some("InvalidMetadata".to_string()));
+}
+
+#[test]
+fn truncate_messages() {
+	let code = TestRuntime::new()
+		.import(DEBUG_PRINT)
+		.execute("
+			(call $debug_print (i32.const 16384) (i32.const 40000))
+			(i32.const 0)")
+		.build();
+	let instance = engine::Instance::new(Arc::new(code)).unwrap();
+
+	let (_, trace) = instance.execute_traced(b"blockdata", None);
+	assert_eq!(trace.messages.len(), 1);
+	assert_eq!(trace.messages[0].len(), "[runtime debug] ".len() + 1024);
+}
@@ -0,0 +1,127 @@
+extern crate solri_engine as engine;
+extern crate solri_runtime as runtime;
+
+mod common;
+
+use blockchain::BlockExecutor;
+use common::{Code, TestRuntime};
+use engine::{
+	Error, ExecutionLimits, Executor, GenericBlock, ValidationError, validate_code,
+};
+
+fn module(import: &str, item: &str, execute: &str) -> Vec<u8> {
+	TestRuntime::new().import(import).item(item).execute(execute).build()
+}
+
+/// Runtime replacing itself with the code given as block data.
+fn upgrading_runtime() -> Vec<u8> {
+	TestRuntime::new()
+		.item("(global $block_len (mut i32) (i32.const 0))")
+		.write_block("(set_global $block_len (get_local 0)) (i32.const 1024)")
+		.write_code("(i32.const 32768)")
+		.execute("
+			(i32.store (i32.const 32) (i32.const 1024))
+			(i32.store (i32.const 36) (get_global $block_len))
+			(i32.const 0)")
+		.build()
+}
+
+fn upgrade_block(code: Vec<u8>) -> GenericBlock {
+	GenericBlock {
+		id: Vec::new(),
+		parent_id: Some(Vec::new()),
+		difficulty: 0,
+		timestamp: 0,
+		data: code,
+	}
+}
+
+/// Module passing `validate_code`, without being checked by wabt.
+fn unchecked_module(memory_pages: u32, item: &str, execute: &str) -> Vec<u8> {
+	TestRuntime::new()
+		.memory_pages(memory_pages)
+		.item(item)
+		.execute(execute)
+		.build_unchecked()
+}
+
+#[test]
+fn accept_valid_runtimes() {
+	assert_eq!(validate_code(runtime::WASM_BINARY), Ok(()));
+	assert_eq!(validate_code(&module("", "", "(i32.const 0)")), Ok(()));
+	assert_eq!(validate_code(&module(
+		r#"(import "solri_host_v1" "ext_code_len" (func (result i32)))"#,
+		"",
+		"(i32.const 0)",
+	)), Ok(()));
+}
+
+#[test]
+fn reject_invalid_runtimes() {
+	assert_eq!(validate_code(b"not wasm"), Err(ValidationError::Parse));
+	assert_eq!(
+		validate_code(&module("", "(func $start) (start $start)", "(i32.const 0)")),
+		Err(ValidationError::HasStart),
+	);
+	assert_eq!(
+		validate_code(&wabt::wat2wasm(r#"(module (memory (export "memory") 1))"#).unwrap()),
+		Err(ValidationError::MissingExport("write_block")),
+	);
+	assert_eq!(
+		validate_code(&wabt::wat2wasm(r#"
+			(module
+				(memory (export "memory") 1)
+				(func (export "write_block") (result i32) (i32.const 0)))
+		"#).unwrap()),
+		Err(ValidationError::InvalidExport("write_block")),
+	);
+	assert_eq!(
+		validate_code(&module(r#"(import "env" "abort" (func))"#, "", "(i32.const 0)")),
+		Err(ValidationError::InvalidImport("env".to_string(), "abort".to_string())),
+	);
+	assert_eq!(
+		validate_code(&module(
+			r#"(import "solri_host_v1" "ext_code_len" (func (result i64)))"#,
+			"",
+			"(i32.const 0)",
+		)),
+		Err(ValidationError::InvalidImport(
+			"solri_host_v1".to_string(), "ext_code_len".to_string(),
+		)),
+	);
+	assert_eq!(
+		validate_code(&module("", "", "(i32.trunc_s/f32 (f32.const 1.5))")),
+		Err(ValidationError::FloatingPoint),
+	);
+}
+
+#[test]
+fn reject_upgrades_failing_to_instantiate() {
+	let executor = Executor::new_with_limits(2, ExecutionLimits {
+		max_memory_pages: 8,
+		..Default::default()
+	});
+
+	let valid = unchecked_module(1, "", "(i32.const 0)");
+	let mut state = Code(upgrading_runtime());
+	executor.execute_block(&upgrade_block(valid.clone()), &mut state).unwrap();
+	assert_eq!(state.0, valid);
+
+	for code in vec![
+		// Function body returning the wrong type.
+		unchecked_module(1, "", "(i64.const 0)"),
+		// Initial memory over the limit of the executor.
+		unchecked_module(16, "", "(i32.const 0)"),
+		// Stack underflow, failing the stack limiter.
+		unchecked_module(1, "(func (drop))", "(i32.const 0)"),
+	] {
+		assert_eq!(validate_code(&code), Ok(()));
+
+		let mut state = Code(upgrading_runtime());
+		match executor.execute_block(&upgrade_block(code), &mut state) {
+			Err(Error::InvalidRuntimeCode(ValidationError::Instantiation(_))) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		assert_eq!(state.0, upgrading_runtime());
+	}
+}
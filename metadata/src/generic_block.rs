@@ -1,4 +1,4 @@
-use blockchain_core::Block as BlockT;
+use blockchain_core::{Block as BlockT, DifficultyBlock};
 use alloc::vec::Vec;
 #[cfg(feature = "parity-codec")]
 use parity_codec::{Encode, Decode};
@@ -24,3 +24,9 @@ impl BlockT for GenericBlock {
 		self.id.clone()
 	}
 }
+
+impl DifficultyBlock for GenericBlock {
+	fn difficulty(&self) -> u128 {
+		self.difficulty as u128
+	}
+}
@@ -3,6 +3,10 @@ extern crate alloc;
 
 mod raw_metadata;
 mod generic_block;
+mod runtime_error;
+mod runtime_version;
 
 pub use crate::raw_metadata::{RawMetadata, RawArray};
 pub use crate::generic_block::GenericBlock;
+pub use crate::runtime_error::RuntimeError;
+pub use crate::runtime_version::RuntimeVersion;
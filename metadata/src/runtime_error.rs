@@ -0,0 +1,44 @@
+/// Error reported by a runtime, encoded as the non-zero status returned by
+/// `execute` or `query`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuntimeError {
+	/// Block is malformed, does not match its state, or carries an
+	/// incomplete proof of its parent state.
+	InvalidBlock,
+	/// Query or its state proof is malformed, or the proof does not cover
+	/// the query.
+	InvalidQuery,
+	/// Block does not meet the required difficulty.
+	DifficultyTooLow,
+	/// Extrinsic has an invalid signature or nonce.
+	InvalidExtrinsic,
+	/// State needed by the execution is not available.
+	Backend,
+}
+
+impl RuntimeError {
+	/// Status of a failed call not reporting a known error. Runtimes
+	/// predating error codes return this for every failure.
+	pub const UNKNOWN: u32 = 1;
+
+	pub fn encode(&self) -> u32 {
+		match self {
+			RuntimeError::InvalidBlock => 2,
+			RuntimeError::InvalidQuery => 3,
+			RuntimeError::DifficultyTooLow => 4,
+			RuntimeError::Backend => 5,
+			RuntimeError::InvalidExtrinsic => 6,
+		}
+	}
+
+	pub fn decode(status: u32) -> Option<Self> {
+		match status {
+			2 => Some(RuntimeError::InvalidBlock),
+			3 => Some(RuntimeError::InvalidQuery),
+			4 => Some(RuntimeError::DifficultyTooLow),
+			5 => Some(RuntimeError::Backend),
+			6 => Some(RuntimeError::InvalidExtrinsic),
+			_ => None,
+		}
+	}
+}
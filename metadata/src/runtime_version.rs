@@ -0,0 +1,45 @@
+use alloc::vec::Vec;
+
+/// Version of a runtime, compared to decide whether its native counterpart
+/// can execute blocks instead of the wasm code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuntimeVersion {
+	/// Name of the runtime specification.
+	pub spec_name: Vec<u8>,
+	/// Version of the runtime specification, bumped on every change of
+	/// block execution logic.
+	pub spec_version: u32,
+	/// Version of the engine ABI the runtime implements.
+	pub abi_version: u32,
+}
+
+impl RuntimeVersion {
+	pub fn decode(bytes: &[u8]) -> Option<Self> {
+		fn decode_u32(bytes: &[u8]) -> Option<u32> {
+			let mut arr = 0u32.to_le_bytes();
+			if arr.len() != bytes.len() {
+				return None
+			}
+			arr.copy_from_slice(bytes);
+			Some(u32::from_le_bytes(arr))
+		}
+
+		if bytes.len() < 8 {
+			return None
+		}
+
+		Some(RuntimeVersion {
+			spec_version: decode_u32(&bytes[0..4])?,
+			abi_version: decode_u32(&bytes[4..8])?,
+			spec_name: bytes[8..].to_vec(),
+		})
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut ret = Vec::new();
+		ret.extend_from_slice(&self.spec_version.to_le_bytes());
+		ret.extend_from_slice(&self.abi_version.to_le_bytes());
+		ret.extend_from_slice(&self.spec_name);
+		ret
+	}
+}
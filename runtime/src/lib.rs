@@ -14,16 +14,34 @@ use alloc::{vec, vec::Vec};
 use parity_codec::{Encode, Decode};
 use blockchain_core::{Block as BlockT, BlockExecutor, AsExternalities, ExtrinsicBuilder};
 use sha3::Sha3_256;
-use primitive_types::H256;
+use primitive_types::{H256, H512};
 use bm::{
 	CompactValue, ProvingState, Proofs, ReadBackend, WriteBackend, DynBackend,
-	InMemoryBackend, ProvingBackend, Tree
+	InMemoryBackend, ProvingBackend, Sequence, Tree, Index, Leak
 };
 use bm_le::{FromTree, IntoTree, Value, tree_root};
-use metadata::GenericBlock;
+use metadata::{GenericBlock, RuntimeError, RuntimeVersion};
 
 pub type Construct = bm_le::DigestConstruct<Sha3_256>;
 
+/// Name of the runtime specification.
+pub const SPEC_NAME: &[u8] = b"solri";
+/// Version of the runtime specification. Must be bumped whenever block
+/// execution logic changes, so that nodes stop using an outdated native
+/// executor.
+pub const SPEC_VERSION: u32 = 3;
+/// Version of the engine ABI implemented by the runtime.
+pub const ABI_VERSION: u32 = 2;
+
+/// Version of this runtime.
+pub fn version() -> RuntimeVersion {
+	RuntimeVersion {
+		spec_name: SPEC_NAME.to_vec(),
+		spec_version: SPEC_VERSION,
+		abi_version: ABI_VERSION,
+	}
+}
+
 pub trait TrieExternalities {
 	fn db(&self) -> &dyn ReadBackend<Construct=Construct, Error=()>;
 	fn db_mut(&mut self) -> &mut dyn WriteBackend<Construct=Construct, Error=()>;
@@ -48,10 +66,25 @@ impl AsExternalities<dyn TrieExternalities> for InMemoryTrie {
 #[derive(Debug)]
 pub enum Error {
 	InvalidBlock,
+	InvalidQuery,
 	DifficultyTooLow,
+	InvalidSignature,
+	InvalidNonce,
 	Backend
 }
 
+impl From<&Error> for RuntimeError {
+	fn from(err: &Error) -> RuntimeError {
+		match err {
+			Error::InvalidBlock => RuntimeError::InvalidBlock,
+			Error::InvalidQuery => RuntimeError::InvalidQuery,
+			Error::DifficultyTooLow => RuntimeError::DifficultyTooLow,
+			Error::InvalidSignature | Error::InvalidNonce => RuntimeError::InvalidExtrinsic,
+			Error::Backend => RuntimeError::Backend,
+		}
+	}
+}
+
 #[cfg(feature = "std")]
 impl std::fmt::Display for Error {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -179,11 +212,235 @@ impl Into<GenericBlock> for Block {
 	}
 }
 
-#[derive(Clone, Debug, FromTree, IntoTree, Encode, Decode)]
-pub enum Extrinsic {
+/// Signing context of extrinsic signatures.
+pub const SIGNING_CONTEXT: &[u8] = b"solri extrinsic";
+
+lazy_static::lazy_static! {
+	/// Id of the genesis block, included in every signing payload.
+	static ref GENESIS_ID: H256 = Block::genesis().id();
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, FromTree, IntoTree, Encode, Decode)]
+pub enum Call {
 	Add(u64),
 }
 
+/// Call signed by an sr25519 account.
+#[derive(Clone, Debug, Eq, PartialEq, FromTree, IntoTree, Encode, Decode)]
+pub struct Extrinsic {
+	pub call: Call,
+	/// Public key of the signer.
+	pub signer: H256,
+	/// Nonce of the signer, which must match its nonce in the state.
+	pub nonce: u64,
+	pub signature: H512,
+}
+
+impl Extrinsic {
+	/// Message signed for a call: the encoded call, nonce and genesis block
+	/// id, so that signatures cannot be replayed on another chain.
+	pub fn signing_payload(call: &Call, nonce: u64) -> Vec<u8> {
+		(call, nonce, *GENESIS_ID).encode()
+	}
+
+	/// Sign a call with the given nonce.
+	#[cfg(feature = "std")]
+	pub fn sign(call: Call, nonce: u64, keypair: &schnorrkel::Keypair) -> Self {
+		let payload = Self::signing_payload(&call, nonce);
+		let signature = keypair.sign(schnorrkel::signing_context(SIGNING_CONTEXT).bytes(&payload));
+
+		Extrinsic {
+			call, nonce,
+			signer: H256::from_slice(&keypair.public.to_bytes()),
+			signature: H512::from_slice(&signature.to_bytes()),
+		}
+	}
+
+	pub fn verify(&self) -> bool {
+		verify_sr25519(
+			&Self::signing_payload(&self.call, self.nonce),
+			&self.signature,
+			&self.signer,
+		)
+	}
+}
+
+#[cfg(feature = "std")]
+fn verify_sr25519(message: &[u8], signature: &H512, public: &H256) -> bool {
+	let public = match schnorrkel::PublicKey::from_bytes(public.as_bytes()) {
+		Ok(public) => public,
+		Err(_) => return false,
+	};
+	let signature = match schnorrkel::Signature::from_bytes(signature.as_bytes()) {
+		Ok(signature) => signature,
+		Err(_) => return false,
+	};
+	public.verify(schnorrkel::signing_context(SIGNING_CONTEXT).bytes(message), &signature)
+}
+
+#[cfg(all(not(feature = "std"), target_arch = "wasm32"))]
+fn verify_sr25519(message: &[u8], signature: &H512, public: &H256) -> bool {
+	unsafe {
+		wasm::ext_sr25519_verify(
+			SIGNING_CONTEXT.as_ptr() as u32, SIGNING_CONTEXT.len() as u32,
+			message.as_ptr() as u32, message.len() as u32,
+			signature.as_ptr() as u32, public.as_ptr() as u32,
+		) == 1
+	}
+}
+
+const COUNTERS_INDEX: Index = Index::root().left();
+const ACCOUNTS_INDEX: Index = Index::root().right();
+/// Number of leading bits of a public key selecting its account bucket.
+const ACCOUNT_BUCKET_BITS: usize = 24;
+
+/// Runtime state. Its root is a pair of the counter list root and the
+/// account tree root. The account tree is a sparse tree of buckets indexed
+/// by the leading bits of public keys, where empty subtrees have a zero root.
+/// Each bucket is a list where an account takes two consecutive entries:
+/// its public key and its nonce.
+struct State {
+	counters: bm::OwnedList<Construct>,
+	accounts: bm::DanglingRaw<Construct>,
+}
+
+fn bucket_prefix(signer: &H256) -> usize {
+	signer.as_bytes()[..ACCOUNT_BUCKET_BITS / 8].iter()
+		.fold(0, |prefix, byte| (prefix << 8) | *byte as usize)
+}
+
+impl State {
+	fn open<DB: WriteBackend<Construct=Construct> + ?Sized>(
+		root: Value,
+		db: &mut DB,
+	) -> Result<Self, Error> {
+		if root == Default::default() {
+			return Ok(Self {
+				counters: bm::OwnedList::create(db, None).map_err(|_| Error::Backend)?,
+				accounts: Default::default(),
+			})
+		}
+
+		let raw = bm::DanglingRaw::<Construct>::from_leaked(root);
+		let counters = raw.get(db, COUNTERS_INDEX).map_err(|_| Error::Backend)?
+			.ok_or(Error::Backend)?;
+		let accounts = raw.get(db, ACCOUNTS_INDEX).map_err(|_| Error::Backend)?
+			.ok_or(Error::Backend)?;
+
+		Ok(Self {
+			counters: bm::OwnedList::reconstruct(counters, db, None).map_err(|_| Error::Backend)?,
+			accounts: bm::DanglingRaw::from_leaked(accounts),
+		})
+	}
+
+	fn root<DB: WriteBackend<Construct=Construct> + ?Sized>(
+		&self,
+		db: &mut DB,
+	) -> Result<Value, Error> {
+		let pair = (self.counters.root(), self.accounts.root());
+		let root = <Construct as bm::Construct>::intermediate_of(&pair.0, &pair.1);
+		db.insert(root.clone(), pair).map_err(|_| Error::Backend)?;
+		Ok(root)
+	}
+
+	/// Account bucket of a signer, or `None` if the bucket is empty.
+	fn bucket<DB: WriteBackend<Construct=Construct> + ?Sized>(
+		&self,
+		db: &mut DB,
+		signer: &H256,
+	) -> Result<Option<bm::OwnedList<Construct>>, Error> {
+		let prefix = bucket_prefix(signer);
+		let mut current = self.accounts.root();
+		for bit in (0..ACCOUNT_BUCKET_BITS).rev() {
+			if current == Default::default() {
+				return Ok(None)
+			}
+			let (left, right) = db.get(&current).map_err(|_| Error::Backend)?
+				.ok_or(Error::Backend)?;
+			current = if (prefix >> bit) & 1 == 0 { left } else { right };
+		}
+
+		if current == Default::default() {
+			return Ok(None)
+		}
+		Ok(Some(bm::OwnedList::reconstruct(current, db, None).map_err(|_| Error::Backend)?))
+	}
+
+	/// Account bucket of a signer, position of the signer's nonce entry in
+	/// the bucket, and its nonce. Accounts not in the state have nonce zero.
+	fn account<DB: WriteBackend<Construct=Construct> + ?Sized>(
+		&self,
+		db: &mut DB,
+		signer: &H256,
+	) -> Result<(Option<bm::OwnedList<Construct>>, Option<usize>, u64), Error> {
+		let bucket = match self.bucket(db, signer)? {
+			Some(bucket) => bucket,
+			None => return Ok((None, None, 0)),
+		};
+
+		for index in (0..bucket.len()).step_by(2) {
+			let key = bucket.get(db, index).map_err(|_| Error::Backend)?;
+			if &key.0 == signer {
+				let nonce = bucket.get(db, index + 1).map_err(|_| Error::Backend)?;
+				return Ok((Some(bucket), Some(index + 1), nonce.0.to_low_u64_le()))
+			}
+		}
+
+		Ok((Some(bucket), None, 0))
+	}
+
+	fn nonce<DB: WriteBackend<Construct=Construct> + ?Sized>(
+		&self,
+		db: &mut DB,
+		signer: &H256,
+	) -> Result<u64, Error> {
+		Ok(self.account(db, signer)?.2)
+	}
+
+	fn apply<DB: WriteBackend<Construct=Construct> + ?Sized>(
+		&mut self,
+		db: &mut DB,
+		extrinsic: &Extrinsic,
+	) -> Result<(), Error> {
+		if !extrinsic.verify() {
+			return Err(Error::InvalidSignature)
+		}
+
+		let (bucket, index, nonce) = self.account(db, &extrinsic.signer)?;
+		if extrinsic.nonce != nonce {
+			return Err(Error::InvalidNonce)
+		}
+
+		let mut bucket = match bucket {
+			Some(bucket) => bucket,
+			None => bm::OwnedList::create(db, None).map_err(|_| Error::Backend)?,
+		};
+		let next_nonce = Value(H256::from_low_u64_le(nonce + 1));
+		match index {
+			Some(index) => {
+				bucket.set(db, index, next_nonce).map_err(|_| Error::Backend)?;
+			},
+			None => {
+				bucket.push(db, Value(extrinsic.signer)).map_err(|_| Error::Backend)?;
+				bucket.push(db, next_nonce).map_err(|_| Error::Backend)?;
+			},
+		}
+
+		let bucket_index = Index::from_one((1 << ACCOUNT_BUCKET_BITS) | bucket_prefix(&extrinsic.signer))
+			.expect("Index is never zero; qed");
+		self.accounts.set(db, bucket_index, bucket.root()).map_err(|_| Error::Backend)?;
+
+		match extrinsic.call {
+			Call::Add(add) => {
+				self.counters.push(db, Value(H256::from_low_u64_le(add)))
+					.map_err(|_| Error::Backend)?;
+			},
+		}
+
+		Ok(())
+	}
+}
+
 #[derive(Default, Clone)]
 pub struct Executor;
 
@@ -204,23 +461,20 @@ impl BlockExecutor for Executor {
 		let parent_state_root = Value(tree_root::<Sha3_256, _>(&block.parent_state));
 		let state_root = block.state.clone();
 
-		let mut trie = if parent_state_root == Default::default() {
-			bm::List::create(state.db_mut(), None).map_err(|_| Error::Backend)?
-		} else {
-			bm::List::reconstruct(parent_state_root, state.db_mut(), None)
-				.map_err(|_| Error::Backend)?
-		};
+		// The proof must be of the state committed in the parent, or the
+		// block could be executed on any earlier state.
+		if let Some(parent) = block.parent.as_ref() {
+			if parent_state_root.0 != parent.state {
+				return Err(Error::InvalidBlock)
+			}
+		}
 
+		let mut trie = State::open(parent_state_root, state.db_mut())?;
 		for extrinsic in &block.extrinsics {
-			match extrinsic {
-				Extrinsic::Add(add) => {
-					trie.push(state.db_mut(), Value(H256::from_low_u64_le(*add)))
-						.map_err(|_| Error::Backend)?;
-				},
-			}
+			trie.apply(state.db_mut(), extrinsic)?;
 		}
 
-		if trie.root() != state_root {
+		if trie.root(state.db_mut())? != state_root {
 			return Err(Error::InvalidBlock)
 		}
 
@@ -242,16 +496,12 @@ impl ExtrinsicBuilder for Executor {
 		let parent_state_root = parent_block.state.clone();
 
 		let mut proving = ProvingBackend::new(state.db_mut());
-		let trie = if parent_state_root == Default::default() {
-			bm::List::create(&mut proving, None).map_err(|_| Error::Backend)?
-		} else {
-			bm::List::reconstruct(parent_state_root.clone(), &mut proving, None)
-				.map_err(|_| Error::Backend)?
-		};
+		let trie = State::open(parent_state_root.clone(), &mut proving)?;
+		let state_root = trie.root(&mut proving)?;
 		let proving_state = proving.into_state();
 
 		Ok(UnsealedBlock {
-			state: trie.root(),
+			state: state_root,
 			parent_state: (parent_state_root, proving_state),
 			timestamp: inherent,
 			parent: Some(parent_block.clone().into()),
@@ -266,18 +516,12 @@ impl ExtrinsicBuilder for Executor {
 		state: &mut Self::Externalities,
 	) -> Result<(), Self::Error> {
 		let mut proving = ProvingBackend::from_state(block.parent_state.1.clone(), state.db_mut());
-		let mut trie = bm::OwnedList::reconstruct(block.state.clone(), &mut proving, None)
-			.map_err(|_| Error::Backend)?;
+		let mut trie = State::open(block.state.clone(), &mut proving)?;
 
-		match extrinsic {
-			Extrinsic::Add(add) => {
-				trie.push(&mut proving, Value(H256::from_low_u64_le(add)))
-					.map_err(|_| Error::Backend)?;
-			},
-		}
+		trie.apply(&mut proving, &extrinsic)?;
 		block.extrinsics.push(extrinsic);
+		block.state = trie.root(&mut proving)?;
 		block.parent_state.1 = proving.into_state();
-		block.state = trie.root();
 
 		Ok(())
 	}
@@ -298,7 +542,12 @@ pub fn execute(block: &[u8], _code: &mut Vec<u8>) -> Result<Metadata, Error> {
 	let mut trie = InMemoryTrie::default();
 	trie.0.populate(proofs.into());
 
-	executor.execute_block(&block, &mut trie)?;
+	// The state holds nothing but the proof carried by the block, so a
+	// missing node means the proof is incomplete.
+	executor.execute_block(&block, &mut trie).map_err(|e| match e {
+		Error::Backend => Error::InvalidBlock,
+		e => e,
+	})?;
 
 	Ok(Metadata {
 		timestamp: block.timestamp,
@@ -311,6 +560,87 @@ pub fn execute(block: &[u8], _code: &mut Vec<u8>) -> Result<Metadata, Error> {
 	})
 }
 
+/// Read-only request answered by the runtime from a state proof.
+#[derive(Clone, Debug, Eq, PartialEq, Encode, Decode)]
+pub enum Query {
+	/// Number of values in the counter list.
+	Length,
+	/// Value at an index of the counter list.
+	Get(u64),
+	/// Nonce of an account, for signing its next extrinsic.
+	Nonce(H256),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Encode, Decode)]
+pub enum QueryResponse {
+	Length(u64),
+	Get(Option<H256>),
+	Nonce(u64),
+}
+
+fn answer<DB: WriteBackend<Construct=Construct> + ?Sized>(
+	query: &Query,
+	root: Value,
+	db: &mut DB,
+) -> Result<QueryResponse, Error> {
+	let trie = State::open(root, db)?;
+
+	match query {
+		Query::Length => Ok(QueryResponse::Length(trie.counters.len() as u64)),
+		Query::Get(index) => {
+			if *index >= trie.counters.len() as u64 {
+				return Ok(QueryResponse::Get(None))
+			}
+			let value = trie.counters.get(db, *index as usize).map_err(|_| Error::Backend)?;
+			Ok(QueryResponse::Get(Some(value.0)))
+		},
+		Query::Nonce(signer) => Ok(QueryResponse::Nonce(trie.nonce(db, signer)?)),
+	}
+}
+
+/// Nonce of an account in the state with the given root.
+pub fn account_nonce(
+	signer: &H256,
+	root: Value,
+	state: &mut dyn TrieExternalities,
+) -> Result<u64, Error> {
+	let db = state.db_mut();
+	State::open(root, db)?.nonce(db, signer)
+}
+
+/// Create the state proof needed to answer a query on the state with the
+/// given root.
+pub fn query_proof(
+	query: &Query,
+	root: Value,
+	state: &mut dyn TrieExternalities,
+) -> Result<CompactValue<Value>, Error> {
+	let mut proving = ProvingBackend::new(state.db_mut());
+	answer(query, root.clone(), &mut proving)?;
+	Ok(Proofs::from(proving.into_state()).into_compact(root))
+}
+
+/// Answer an encoded query from an encoded proof of the state with the
+/// given root.
+pub fn query(query: &[u8], proof: &[u8], root: &[u8]) -> Result<Vec<u8>, Error> {
+	let query = Query::decode(&mut &query[..]).ok_or(Error::InvalidQuery)?;
+	let proof = CompactValue::<Value>::decode(&mut &proof[..]).ok_or(Error::InvalidQuery)?;
+	let expected_root = Value::decode(&mut &root[..]).ok_or(Error::InvalidQuery)?;
+	let (proofs, root) = Proofs::from_compact::<Construct>(proof);
+	if root != expected_root {
+		return Err(Error::InvalidQuery)
+	}
+	let mut trie = InMemoryTrie::default();
+	trie.0.populate(proofs.into());
+
+	// Missing nodes mean the proof does not cover the query.
+	let response = answer(&query, root, trie.db_mut()).map_err(|e| match e {
+		Error::Backend => Error::InvalidQuery,
+		e => e,
+	})?;
+	Ok(response.encode())
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -324,4 +654,34 @@ mod tests {
 			tree_root::<Sha3_256, _>(&Header::from(block.clone()))
 		);
 	}
+
+	#[test]
+	fn should_reject_replay_on_stale_parent_state() {
+		let executor = Executor;
+		let keypair = schnorrkel::MiniSecretKey::from_bytes(&[1; 32]).unwrap()
+			.expand_to_keypair();
+		let extrinsic = Extrinsic::sign(Call::Add(1), 0, &keypair);
+
+		let genesis = Block::genesis();
+		let mut trie = InMemoryTrie::default();
+		let mut unsealed = executor.initialize_block(&genesis, &mut trie, 1).unwrap();
+		executor.apply_extrinsic(&mut unsealed, extrinsic, &mut trie).unwrap();
+		let block = unsealed.seal();
+		execute(&block.encode(), &mut Vec::new()).unwrap();
+
+		// Same extrinsic on top of the block, proven against the genesis
+		// state where its nonce is still valid.
+		let replay = Block {
+			parent: Some(block.clone().into()),
+			timestamp: 2,
+			parent_state: block.parent_state.clone(),
+			state: block.state.clone(),
+			extrinsics: block.extrinsics.clone(),
+			nonce: 0,
+		};
+		match execute(&replay.encode(), &mut Vec::new()) {
+			Err(Error::InvalidBlock) => (),
+			result => panic!("Replayed block is not rejected: {:?}", result),
+		}
+	}
 }
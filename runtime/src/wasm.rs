@@ -1,6 +1,19 @@
 use alloc::vec::Vec;
 use alloc::format;
-use metadata::{RawMetadata, RawArray};
+use metadata::{RawMetadata, RawArray, RuntimeError};
+
+#[link(wasm_import_module = "solri_host_v2")]
+extern "C" {
+	/// Verify an sr25519 signature, returning 1 if it is valid.
+	pub fn ext_sr25519_verify(
+		context_ptr: u32,
+		context_len: u32,
+		message_ptr: u32,
+		message_len: u32,
+		signature_ptr: u32,
+		public_ptr: u32,
+	) -> u32;
+}
 
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
@@ -101,7 +114,7 @@ unsafe extern fn execute() -> u32 {
 				DEBUG_ERROR_ARG = Some(estr.as_bytes().to_vec());
 			}
 
-			1
+			RuntimeError::from(&e).encode()
 		},
 	}
 }
@@ -118,6 +131,11 @@ unsafe extern fn free() {
 	ID_ARG = None;
 	PARENT_ID_ARG = None;
 	METADATA_ARG = None;
+	QUERY_ARG = None;
+	PROOF_ARG = None;
+	ROOT_ARG = None;
+	QUERY_RESPONSE_ARG = None;
+	QUERY_RESPONSE_METADATA_ARG = None;
 
 	#[cfg(feature = "debug-error")] {
 		DEBUG_ERROR_ARG = None;
@@ -125,6 +143,96 @@ unsafe extern fn free() {
 	}
 }
 
+static mut QUERY_ARG: Option<Vec<u8>> = None;
+
+#[no_mangle]
+unsafe extern fn write_query(len: u32) -> u32 {
+	let ptr = {
+		QUERY_ARG = None;
+		let mut arg = Vec::with_capacity(len as usize);
+		arg.resize(len as usize, 0u8);
+		QUERY_ARG = Some(arg);
+		QUERY_ARG.as_mut().unwrap().as_mut_ptr()
+	};
+	ptr as u32
+}
+
+static mut PROOF_ARG: Option<Vec<u8>> = None;
+
+#[no_mangle]
+unsafe extern fn write_proof(len: u32) -> u32 {
+	let ptr = {
+		PROOF_ARG = None;
+		let mut arg = Vec::with_capacity(len as usize);
+		arg.resize(len as usize, 0u8);
+		PROOF_ARG = Some(arg);
+		PROOF_ARG.as_mut().unwrap().as_mut_ptr()
+	};
+	ptr as u32
+}
+
+static mut ROOT_ARG: Option<Vec<u8>> = None;
+
+#[no_mangle]
+unsafe extern fn write_root(len: u32) -> u32 {
+	let ptr = {
+		ROOT_ARG = None;
+		let mut arg = Vec::with_capacity(len as usize);
+		arg.resize(len as usize, 0u8);
+		ROOT_ARG = Some(arg);
+		ROOT_ARG.as_mut().unwrap().as_mut_ptr()
+	};
+	ptr as u32
+}
+
+static mut QUERY_RESPONSE_ARG: Option<Vec<u8>> = None;
+static mut QUERY_RESPONSE_METADATA_ARG: Option<Vec<u8>> = None;
+
+#[no_mangle]
+unsafe extern fn query() -> u32 {
+	match crate::query(
+		QUERY_ARG.as_ref().unwrap(),
+		PROOF_ARG.as_ref().unwrap(),
+		ROOT_ARG.as_ref().unwrap(),
+	) {
+		Ok(response) => {
+			QUERY_RESPONSE_ARG = Some(response);
+			0
+		},
+		Err(e) => RuntimeError::from(&e).encode(),
+	}
+}
+
+#[no_mangle]
+unsafe extern fn read_query_response() -> u32 {
+	let response = QUERY_RESPONSE_ARG.as_ref().unwrap();
+
+	let metadata = RawArray {
+		ptr: response.as_ptr() as u32,
+		len: response.len() as u32,
+	};
+	QUERY_RESPONSE_METADATA_ARG = Some(metadata.encode());
+
+	QUERY_RESPONSE_METADATA_ARG.as_ref().unwrap().as_ptr() as u32
+}
+
+static mut VERSION_ARG: Option<Vec<u8>> = None;
+static mut VERSION_METADATA_ARG: Option<Vec<u8>> = None;
+
+#[no_mangle]
+unsafe extern fn version() -> u32 {
+	VERSION_ARG = Some(crate::version().encode());
+	let version = VERSION_ARG.as_ref().unwrap();
+
+	let metadata = RawArray {
+		ptr: version.as_ptr() as u32,
+		len: version.len() as u32,
+	};
+	VERSION_METADATA_ARG = Some(metadata.encode());
+
+	VERSION_METADATA_ARG.as_ref().unwrap().as_ptr() as u32
+}
+
 #[cfg(feature = "debug-error")]
 static mut DEBUG_ERROR_ARG: Option<Vec<u8>> = None;
 #[cfg(feature = "debug-error")]
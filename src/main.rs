@@ -1,17 +1,69 @@
 use blockchain::backend::{
 	SharedMemoryBackend, ChainQuery, ImportLock, Store,
-	Operation, SharedCommittable
+	Operation, SharedCommittable, tree_route
 };
-use blockchain::{Block as BlockT, ExtrinsicBuilder, AsExternalities, BlockExecutor};
+use blockchain::{Block as BlockT, ExtrinsicBuilder, AsExternalities, BlockExecutor, DifficultyBlock};
 use blockchain::import::{BlockImporter, ImportAction};
-use blockchain_network::sync::{BestDepthError, BestDepthStatusProducer};
+use blockchain::pool::{SharedPool, ExtrinsicValidator, Validity};
+use blockchain_network::sync::{
+	BestDepthError, BestDepthStatusProducer, BestDifficultyError, BestDifficultyStatusProducer,
+	TotalDifficulty, total_difficulty_at,
+};
+use blockchain_rocksdb::SharedRocksBackend;
+use blockchain_network_libp2p::NetworkConfig;
+use blockchain_network_tcp::TcpConfig;
 use std::thread;
-use std::collections::HashMap;
-use clap::{App, SubCommand, AppSettings, Arg};
-use parity_codec::Decode;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, mpsc::{Sender, channel}};
+use clap::{App, SubCommand, AppSettings, Arg, ArgMatches};
+use parity_codec::{Encode, Decode, Input, Output};
 use runtime::TrieExternalities;
 use engine::{GenericBlock, CodeExternalities};
 use bm::{InMemoryBackend, ReadBackend, WriteBackend, DynBackend};
+use bm_le::{Value, tree_root};
+use primitive_types::H256;
+use sha3::Sha3_256;
+
+const MAX_POOL_COUNT: usize = 4096;
+const MAX_POOL_BYTES: usize = 16 * 1024 * 1024;
+const MAX_BLOCK_EXTRINSICS: usize = 256;
+
+/// Executor of runtimes that are not the native one.
+#[cfg(not(feature = "jit"))]
+type GenericExecutor = engine::Executor;
+#[cfg(feature = "jit")]
+type GenericExecutor = engine::JitExecutor;
+
+/// Dispatcher telling whether code can be run by the native runtime. Pool
+/// validation and block building only ever run natively.
+type NativeDispatcher = engine::Dispatcher<runtime::Executor, ()>;
+
+fn native_dispatcher() -> NativeDispatcher {
+	engine::Dispatcher::new(runtime::Executor, runtime::version(), ())
+}
+
+/// Auxiliary of the backends. Total difficulty is recorded under every fork
+/// choice, so that a database can be reopened with another one.
+type Auxiliary = TotalDifficulty<GenericBlock>;
+
+/// Rule picking the best chain.
+#[derive(Clone, Copy, Debug)]
+enum ForkChoice {
+	/// Longest chain.
+	Depth,
+	/// Chain with the most total difficulty.
+	Difficulty,
+}
+
+impl ForkChoice {
+	fn from_matches(matches: &ArgMatches) -> Self {
+		match matches.value_of("fork-choice") {
+			Some("difficulty") => ForkChoice::Difficulty,
+			_ => ForkChoice::Depth,
+		}
+	}
+}
 
 fn main() {
 	let matches = App::new("Solri")
@@ -19,6 +71,25 @@ fn main() {
 		.subcommand(
 			SubCommand::with_name("local")
 				.about("Start a local test network")
+				.arg(
+					Arg::with_name("data-dir")
+						.long("data-dir")
+						.takes_value(true)
+						.help("Directory to store the chain database of each peer")
+				)
+				.arg(
+					Arg::with_name("trace")
+						.long("trace")
+						.help("Execute wasm blocks with the interpreter, printing a trace of each")
+				)
+				.arg(
+					Arg::with_name("fork-choice")
+						.long("fork-choice")
+						.takes_value(true)
+						.possible_values(&["depth", "difficulty"])
+						.default_value("depth")
+						.help("Rule picking the best chain")
+				)
 		)
 		.subcommand(
 			SubCommand::with_name("libp2p")
@@ -28,43 +99,304 @@ fn main() {
 						.short("p")
 						.long("port")
 						.takes_value(true)
-						.help("Port to listen on")
+						.help("Port to listen on, if no listen address is given")
+				)
+				.arg(
+					Arg::with_name("listen")
+						.long("listen")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.help("Multiaddr to listen on")
+				)
+				.arg(
+					Arg::with_name("bootnode")
+						.long("bootnode")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.help("Multiaddr of a bootnode, ending with /p2p/<peer id>")
+				)
+				.arg(
+					Arg::with_name("node-key-file")
+						.long("node-key-file")
+						.takes_value(true)
+						.help("File to load the node key from, generated if it does not exist")
+				)
+				.arg(
+					Arg::with_name("no-mdns")
+						.long("no-mdns")
+						.help("Disable mDNS discovery")
+				)
+				.arg(
+					Arg::with_name("author")
+						.long("author")
+						.help("Whether to author blocks")
+				)
+				.arg(
+					Arg::with_name("data-dir")
+						.long("data-dir")
+						.takes_value(true)
+						.help("Directory to store the chain database")
+				)
+				.arg(
+					Arg::with_name("trace")
+						.long("trace")
+						.help("Execute wasm blocks with the interpreter, printing a trace of each")
+				)
+				.arg(
+					Arg::with_name("fork-choice")
+						.long("fork-choice")
+						.takes_value(true)
+						.possible_values(&["depth", "difficulty"])
+						.default_value("depth")
+						.help("Rule picking the best chain")
+				)
+		)
+		.subcommand(
+			SubCommand::with_name("tcp")
+				.about("Start a plain TCP instance with a static peer list")
+				.arg(
+					Arg::with_name("listen")
+						.long("listen")
+						.takes_value(true)
+						.required(true)
+						.help("Address to listen on, as listed by peers")
+				)
+				.arg(
+					Arg::with_name("peer")
+						.long("peer")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.help("Listen address of a peer, which must list this node too")
 				)
 				.arg(
 					Arg::with_name("author")
 						.long("author")
 						.help("Whether to author blocks")
 				)
+				.arg(
+					Arg::with_name("data-dir")
+						.long("data-dir")
+						.takes_value(true)
+						.help("Directory to store the chain database")
+				)
+				.arg(
+					Arg::with_name("trace")
+						.long("trace")
+						.help("Execute wasm blocks with the interpreter, printing a trace of each")
+				)
+				.arg(
+					Arg::with_name("fork-choice")
+						.long("fork-choice")
+						.takes_value(true)
+						.possible_values(&["depth", "difficulty"])
+						.default_value("depth")
+						.help("Rule picking the best chain")
+				)
+		)
+		.subcommand(
+			SubCommand::with_name("generate-key")
+				.about("Generate an sr25519 account keypair, printing its public key")
+				.arg(
+					Arg::with_name("output")
+						.long("output")
+						.takes_value(true)
+						.required(true)
+						.help("File to write the keypair to, readable only by the owner")
+				)
+		)
+		.subcommand(
+			SubCommand::with_name("sign")
+				.about("Sign a call, printing the encoded extrinsic")
+				.arg(
+					Arg::with_name("keypair-file")
+						.long("keypair-file")
+						.takes_value(true)
+						.help("File written by generate-key. The hex-encoded keypair is read \
+							from stdin if not given")
+				)
+				.arg(
+					Arg::with_name("nonce")
+						.long("nonce")
+						.takes_value(true)
+						.required(true)
+						.help("Current nonce of the account")
+				)
+				.arg(
+					Arg::with_name("add")
+						.long("add")
+						.takes_value(true)
+						.required(true)
+						.help("Value to add to the counter list")
+				)
 		)
 		.get_matches();
 
-	if let Some(_) = matches.subcommand_matches("local") {
-		local_sync();
+	if let Some(matches) = matches.subcommand_matches("local") {
+		let data_dir = matches.value_of("data-dir");
+		let trace = matches.is_present("trace");
+		let fork_choice = ForkChoice::from_matches(matches);
+		local_sync(data_dir, trace, fork_choice);
 		return
 	}
 
 	if let Some(matches) = matches.subcommand_matches("libp2p") {
 		let port = matches.value_of("port").unwrap_or("37365");
 		let author = matches.is_present("author");
-		libp2p_sync(port, author);
+		let trace = matches.is_present("trace");
+		let fork_choice = ForkChoice::from_matches(matches);
+		let data_dir = matches.value_of("data-dir");
+
+		let mut config = NetworkConfig::new_with_port(port).expect("Invalid port");
+		if let Some(listen) = matches.values_of("listen") {
+			config.listen_addresses = listen
+				.map(|address| address.parse().expect("Invalid listen address"))
+				.collect();
+		}
+		if let Some(bootnodes) = matches.values_of("bootnode") {
+			config.bootnodes = bootnodes
+				.map(|address| address.parse().expect("Invalid bootnode address"))
+				.collect();
+		}
+		config.key_file = matches.value_of("node-key-file").map(PathBuf::from);
+		config.mdns = !matches.is_present("no-mdns");
+
+		libp2p_sync(config, author, trace, fork_choice, data_dir);
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("tcp") {
+		let author = matches.is_present("author");
+		let trace = matches.is_present("trace");
+		let fork_choice = ForkChoice::from_matches(matches);
+		let data_dir = matches.value_of("data-dir");
+
+		let config = TcpConfig {
+			listen: matches.value_of("listen").expect("Listen address is required")
+				.parse().expect("Invalid listen address"),
+			peers: matches.values_of("peer")
+				.map(|peers| peers.map(|peer| peer.parse().expect("Invalid peer address")).collect())
+				.unwrap_or_default(),
+		};
+
+		tcp_sync(config, author, trace, fork_choice, data_dir);
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("generate-key") {
+		let output = matches.value_of("output").expect("Output file is required");
+		let keypair = schnorrkel::Keypair::generate(
+			&mut rand::rngs::OsRng::new().expect("OS randomness is available")
+		);
+		write_secret(Path::new(output), format!("{}\n", to_hex(&keypair.to_bytes())).as_bytes())
+			.expect("Failed to write keypair file");
+		println!("Public key: {}", to_hex(&keypair.public.to_bytes()));
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("sign") {
+		let keypair = match matches.value_of("keypair-file") {
+			Some(path) => std::fs::read_to_string(path).expect("Failed to read keypair file"),
+			None => {
+				let mut keypair = String::new();
+				std::io::stdin().read_to_string(&mut keypair)
+					.expect("Failed to read keypair from stdin");
+				keypair
+			},
+		};
+		let keypair = from_hex(keypair.trim())
+			.and_then(|bytes| schnorrkel::Keypair::from_bytes(&bytes).ok())
+			.expect("Invalid keypair");
+		let nonce = matches.value_of("nonce").expect("Nonce is required")
+			.parse().expect("Invalid nonce");
+		let add = matches.value_of("add").expect("Value is required")
+			.parse().expect("Invalid value");
+
+		let extrinsic = runtime::Extrinsic::sign(runtime::Call::Add(add), nonce, &keypair);
+		println!("{}", to_hex(&extrinsic.encode()));
 		return
 	}
 }
 
+/// Write a secret to a new file, readable only by its owner on unix.
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)] {
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	options.open(path)?.write_all(secret)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 {
+		return None
+	}
+
+	(0..hex.len()).step_by(2)
+		.map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+		.collect()
+}
+
 #[derive(Debug)]
 pub enum Error {
 	StateNotAvailable,
 	OutdatedRuntime,
+	InvalidSignature,
+	StaleNonce,
 	Backend(Box<dyn std::error::Error>),
 	NativeExecutor(Box<dyn std::error::Error>),
 }
 
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl std::error::Error for Error { }
+
 #[derive(Clone)]
 pub struct State {
 	code: Vec<u8>,
 	trie: Option<DynBackend<InMemoryBackend<runtime::Construct>>>,
 }
 
+impl Encode for State {
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.code.encode_to(dest);
+		self.trie.as_ref().map(|trie| {
+			trie.0.as_ref().iter()
+				.filter_map(|(key, (value, _))| {
+					value.as_ref().map(|(left, right)| (key.clone(), left.clone(), right.clone()))
+				})
+				.collect::<Vec<_>>()
+		}).encode_to(dest);
+	}
+}
+
+impl Decode for State {
+	fn decode<I: Input>(input: &mut I) -> Option<Self> {
+		let code = Vec::<u8>::decode(input)?;
+		let trie = Option::<Vec<(Value, Value, Value)>>::decode(input)?.map(|nodes| {
+			let mut trie = DynBackend(InMemoryBackend::default());
+			trie.0.populate(
+				nodes.into_iter().map(|(key, left, right)| (key, (left, right))).collect()
+			);
+			trie
+		});
+
+		Some(State { code, trie })
+	}
+}
+
 impl CodeExternalities for State {
 	fn code(&self) -> &Vec<u8> { &self.code }
 	fn code_mut(&mut self) -> &mut Vec<u8> { &mut self.code }
@@ -90,25 +422,208 @@ impl AsExternalities<dyn TrieExternalities> for State {
 	}
 }
 
+pub struct RuntimeValidator<Ba> {
+	backend: Ba,
+	executor: NativeDispatcher,
+	/// Head last validated against, with its state root and state. The
+	/// state is only loaded again once the head moves, so revalidating the
+	/// whole pool loads it once.
+	head: Mutex<Option<(Vec<u8>, Value, State)>>,
+}
+
+impl<Ba> RuntimeValidator<Ba> {
+	pub fn new(backend: Ba) -> Self {
+		Self { backend, executor: native_dispatcher(), head: Mutex::new(None) }
+	}
+}
+
+impl<Ba> ExtrinsicValidator for RuntimeValidator<Ba> where
+	Ba: Store<Block=GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+{
+	type Extrinsic = runtime::Extrinsic;
+	type Hash = H256;
+	type Sender = H256;
+	type Error = Error;
+
+	fn hash(&self, extrinsic: &runtime::Extrinsic) -> H256 {
+		tree_root::<Sha3_256, _>(extrinsic)
+	}
+
+	fn validate(
+		&self,
+		extrinsic: &runtime::Extrinsic
+	) -> Result<Validity<H256, H256>, Error> {
+		let head = self.backend.head();
+		let mut cached = self.head.lock().expect("Lock is poisoned");
+		if cached.as_ref().map(|(id, _, _)| id != &head).unwrap_or(true) {
+			let state = self.backend.state_at(&head).map_err(|e| Error::Backend(Box::new(e)))?;
+			if let engine::Dispatch::Wasm(_) = self.executor.dispatch(&state.code) {
+				return Err(Error::OutdatedRuntime)
+			}
+			if state.trie.is_none() {
+				return Err(Error::StateNotAvailable)
+			}
+			let head_block = runtime::Block::decode(
+				&mut &self.backend.block_at(&head).map_err(|e| Error::Backend(Box::new(e)))?.data[..]
+			).ok_or(Error::OutdatedRuntime)?;
+			*cached = Some((head, head_block.state, state));
+		}
+		let (_, root, state) = cached.as_mut().expect("Head state is loaded above; qed");
+
+		if !extrinsic.verify() {
+			return Err(Error::InvalidSignature)
+		}
+		let nonce = runtime::account_nonce(&extrinsic.signer, root.clone(), state)
+			.map_err(|e| Error::NativeExecutor(Box::new(e)))?;
+		if extrinsic.nonce < nonce {
+			return Err(Error::StaleNonce)
+		}
+
+		Ok(Validity {
+			hash: self.hash(extrinsic),
+			sender: extrinsic.signer,
+			nonce: extrinsic.nonce,
+			size: extrinsic.encode().len(),
+		})
+	}
+}
+
+type Pool<Ba> = SharedPool<RuntimeValidator<Ba>>;
+
+fn new_pool<Ba>(backend: Ba) -> Pool<Ba> where
+	Ba: Store<Block=GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+{
+	SharedPool::new(RuntimeValidator::new(backend), MAX_POOL_COUNT, MAX_POOL_BYTES)
+}
+
+fn block_extrinsics<Ba>(backend: &Ba, ids: &[Vec<u8>]) -> Vec<runtime::Extrinsic> where
+	Ba: Store<Block=GenericBlock> + ChainQuery,
+{
+	ids.iter()
+		.filter_map(|id| backend.block_at(id).ok())
+		.filter_map(|block| runtime::Block::decode(&mut &block.data[..]))
+		.flat_map(|block| block.extrinsics)
+		.collect()
+}
+
+/// Update the pool after the head moved from `old_head` to `new_head`.
+fn update_pool<Ba>(
+	backend: &Ba, pool: &Pool<Ba>, old_head: &Vec<u8>, new_head: &Vec<u8>
+) -> Result<(), Ba::Error> where
+	Ba: Store<Block=GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+{
+	let route = tree_route(backend, old_head, new_head)?;
+	let enacted = block_extrinsics(backend, route.enacted());
+	let retracted = block_extrinsics(backend, route.retracted());
+	pool.lock().update(&enacted, retracted);
+	Ok(())
+}
+
+#[derive(Debug)]
+pub enum ExecuteError {
+	/// The block or its runtime code is at fault.
+	InvalidBlock(Box<dyn std::error::Error>),
+	/// The node failed to execute the block.
+	Backend(Box<dyn std::error::Error>),
+}
+
+impl From<ExecuteError> for BestDepthError {
+	fn from(err: ExecuteError) -> BestDepthError {
+		match err {
+			ExecuteError::InvalidBlock(e) => BestDepthError::Executor(e),
+			ExecuteError::Backend(e) => BestDepthError::Backend(e),
+		}
+	}
+}
+
+impl From<ExecuteError> for BestDifficultyError {
+	fn from(err: ExecuteError) -> BestDifficultyError {
+		match err {
+			ExecuteError::InvalidBlock(e) => BestDifficultyError::Executor(e),
+			ExecuteError::Backend(e) => BestDifficultyError::Backend(e),
+		}
+	}
+}
+
+/// Executor of imported blocks, shared by the importers of every fork
+/// choice.
+pub struct RuntimeExecutor {
+	executor: engine::Dispatcher<runtime::Executor, GenericExecutor>,
+	/// Interpreter executing wasm blocks in place of the generic executor
+	/// when tracing is enabled.
+	tracer: Option<engine::Executor>,
+}
+
+impl RuntimeExecutor {
+	pub fn new(trace: bool) -> Self {
+		Self {
+			executor: engine::Dispatcher::new(
+				runtime::Executor,
+				runtime::version(),
+				GenericExecutor::default(),
+			),
+			tracer: if trace { Some(engine::Executor::default()) } else { None },
+		}
+	}
+
+	/// Execute a block on top of its parent state.
+	pub fn execute_block(
+		&self,
+		block: &GenericBlock,
+		state: &mut State,
+	) -> Result<(), ExecuteError> {
+		match self.executor.dispatch(&state.code) {
+			// The native executor needs the full state trie, which is not
+			// kept for blocks executed by a wasm runtime.
+			engine::Dispatch::Native(native) if state.trie.is_some() => {
+				let decoded = runtime::Block::decode(&mut &block.data[..])
+					.ok_or(ExecuteError::InvalidBlock(Box::new(
+						engine::Error::Runtime(engine::RuntimeError::InvalidBlock)
+					)))?;
+				native.execute_block(&decoded, state)
+					.map_err(|e| match e {
+						runtime::Error::Backend => ExecuteError::Backend(Box::new(e)),
+						e => ExecuteError::InvalidBlock(Box::new(e)),
+					})?;
+			},
+			// Only faults of the block or its runtime code mark the block as
+			// invalid; executor failures are the node's own.
+			_ => {
+				match self.tracer {
+					Some(ref tracer) => {
+						let (result, trace) = tracer.execute_block_traced(block, state);
+						println!("Trace of block {:?}: {}", block.id(), trace.to_json());
+						result
+					},
+					None => self.executor.wasm().execute_block(block, state),
+				}.map_err(|e| if e.is_invalid_block() {
+					ExecuteError::InvalidBlock(Box::new(e))
+				} else {
+					ExecuteError::Backend(Box::new(e))
+				})?;
+				state.trie = None;
+			},
+		}
+
+		Ok(())
+	}
+}
+
 pub struct BestDepthImporter<Ba> {
 	backend: Ba,
 	import_lock: ImportLock,
-	native: runtime::Executor,
-	generic: engine::Executor,
+	pool: Pool<Ba>,
+	executor: RuntimeExecutor,
 }
 
 impl<Ba> BestDepthImporter<Ba> {
-	pub fn new(backend: Ba, import_lock: ImportLock) -> Self {
-		Self {
-			backend, import_lock,
-			native: runtime::Executor,
-			generic: engine::Executor,
-		}
+	pub fn new(backend: Ba, import_lock: ImportLock, pool: Pool<Ba>, trace: bool) -> Self {
+		Self { backend, import_lock, pool, executor: RuntimeExecutor::new(trace) }
 	}
 }
 
-impl<Ba: ChainQuery + Store<Block=GenericBlock, State=State, Auxiliary=()>> BlockImporter for BestDepthImporter<Ba> where
-	Ba: SharedCommittable<Operation=Operation<GenericBlock, State, ()>>,
+impl<Ba: ChainQuery + Store<Block=GenericBlock, State=State, Auxiliary=Auxiliary>> BlockImporter for BestDepthImporter<Ba> where
+	Ba: SharedCommittable<Operation=Operation<GenericBlock, State, Auxiliary>>,
 {
 	type Block = GenericBlock;
 	type Error = BestDepthError;
@@ -119,118 +634,363 @@ impl<Ba: ChainQuery + Store<Block=GenericBlock, State=State, Auxiliary=()>> Bloc
 			self.import_lock.lock()
 		);
 		let new_hash = block.id();
-		let (current_best_depth, current_best_state, new_depth) = {
+		let (current_best_depth, parent_state, new_depth, parent_total_difficulty) = {
 			let backend = importer.backend();
 			let current_best_hash = backend.head();
 			let current_best_depth = backend.depth_at(&current_best_hash)
 				.expect("Best block depth hash cannot fail");
-			let current_best_state = backend.state_at(&current_best_hash)
-				.expect("Best block depth state cannot fail");
-			let new_parent_depth = block.parent_id()
-				.map(|parent_hash| {
-					backend.depth_at(&parent_hash).unwrap()
-				})
-				.unwrap_or(0);
-			(current_best_depth, current_best_state, new_parent_depth + 1)
+			let parent_hash = block.parent_id()
+				.ok_or(BestDepthError::IsGenesis)?;
+			if !backend.contains(&parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?
+			{
+				return Err(BestDepthError::UnknownParent)
+			}
+			let parent_depth = backend.depth_at(&parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?;
+			let parent_state = backend.state_at(&parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?;
+			let parent_total_difficulty = total_difficulty_at(backend, &parent_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?;
+			(current_best_depth, parent_state, parent_depth + 1, parent_total_difficulty)
 		};
 
-		let mut pending_state = current_best_state;
-		if pending_state.trie.is_some() && &pending_state.code[..] == runtime::WASM_BINARY {
-			let decoded = runtime::Block::decode(&mut &block.data[..])
-				.ok_or(BestDepthError::Executor(Box::new(engine::Error::ExecutionFailed)))?;
-			self.native.execute_block(&decoded, &mut pending_state)
-				.map_err(|e| BestDepthError::Executor(Box::new(e)))?;
-		} else {
-			self.generic.execute_block(&block, &mut pending_state)
-				.map_err(|e| BestDepthError::Executor(Box::new(e)))?;
-			pending_state.trie = None;
-		}
+		let mut pending_state = parent_state;
+		self.executor.execute_block(&block, &mut pending_state)?;
 
+		let old_head = self.backend.head();
+		let is_new_head = new_depth > current_best_depth;
+		// Total difficulty is recorded as well, so that the database can be
+		// reopened with the other fork choice.
+		if let Some(parent_total_difficulty) = parent_total_difficulty {
+			importer.insert_auxiliary(TotalDifficulty {
+				id: new_hash.clone(),
+				total_difficulty: parent_total_difficulty.saturating_add(block.difficulty()),
+			});
+		}
 		importer.import_block(block, pending_state);
-		if new_depth > current_best_depth {
-			importer.set_head(new_hash);
+		if is_new_head {
+			importer.set_head(new_hash.clone());
 		}
 		importer.commit().map_err(|e| BestDepthError::Backend(Box::new(e)))?;
 
+		if is_new_head {
+			update_pool(&self.backend, &self.pool, &old_head, &new_hash)
+				.map_err(|e| BestDepthError::Backend(Box::new(e)))?;
+		}
+
+		Ok(())
+	}
+}
+
+pub struct BestDifficultyImporter<Ba> {
+	backend: Ba,
+	import_lock: ImportLock,
+	pool: Pool<Ba>,
+	executor: RuntimeExecutor,
+}
+
+impl<Ba> BestDifficultyImporter<Ba> {
+	pub fn new(backend: Ba, import_lock: ImportLock, pool: Pool<Ba>, trace: bool) -> Self {
+		Self { backend, import_lock, pool, executor: RuntimeExecutor::new(trace) }
+	}
+}
+
+impl<Ba: ChainQuery + Store<Block=GenericBlock, State=State, Auxiliary=Auxiliary>> BlockImporter for BestDifficultyImporter<Ba> where
+	Ba: SharedCommittable<Operation=Operation<GenericBlock, State, Auxiliary>>,
+{
+	type Block = GenericBlock;
+	type Error = BestDifficultyError;
+
+	fn import_block(&mut self, block: GenericBlock) -> Result<(), Self::Error> {
+		let mut importer = ImportAction::new(
+			&self.backend,
+			self.import_lock.lock()
+		);
+		let new_hash = block.id();
+		let (current_best_total_difficulty, parent_state, new_total_difficulty) = {
+			let backend = importer.backend();
+			let current_best_hash = backend.head();
+			let current_best_total_difficulty = total_difficulty_at(backend, &current_best_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?
+				.ok_or(BestDifficultyError::UnknownTotalDifficulty)?;
+			let parent_hash = block.parent_id()
+				.ok_or(BestDifficultyError::IsGenesis)?;
+			if !backend.contains(&parent_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?
+			{
+				return Err(BestDifficultyError::UnknownParent)
+			}
+			let parent_total_difficulty = total_difficulty_at(backend, &parent_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?
+				.ok_or(BestDifficultyError::UnknownTotalDifficulty)?;
+			let parent_state = backend.state_at(&parent_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?;
+			(
+				current_best_total_difficulty,
+				parent_state,
+				parent_total_difficulty.saturating_add(block.difficulty())
+			)
+		};
+
+		let mut pending_state = parent_state;
+		self.executor.execute_block(&block, &mut pending_state)?;
+
+		let old_head = self.backend.head();
+		let is_new_head = new_total_difficulty > current_best_total_difficulty;
+		importer.import_block(block, pending_state);
+		importer.insert_auxiliary(TotalDifficulty {
+			id: new_hash.clone(),
+			total_difficulty: new_total_difficulty,
+		});
+		if is_new_head {
+			importer.set_head(new_hash.clone());
+		}
+		importer.commit().map_err(|e| BestDifficultyError::Backend(Box::new(e)))?;
+
+		if is_new_head {
+			update_pool(&self.backend, &self.pool, &old_head, &new_hash)
+				.map_err(|e| BestDifficultyError::Backend(Box::new(e)))?;
+		}
+
 		Ok(())
 	}
 }
 
-fn local_sync() {
+fn genesis() -> (GenericBlock, State) {
 	let runtime_genesis_block = runtime::Block::genesis();
 	let genesis_block: engine::GenericBlock = runtime_genesis_block.clone().into();
 	let genesis_state = State {
 		code: runtime::WASM_BINARY.to_vec(),
 		trie: Some(Default::default()),
 	};
-	let (backend_build, lock_build) = (
-		SharedMemoryBackend::<_, (), State>::new_with_genesis(
-			genesis_block.clone(),
-			genesis_state.clone(),
-		),
-		ImportLock::new()
-	);
-	let mut peers = HashMap::new();
+	(genesis_block, genesis_state)
+}
+
+fn local_sync(data_dir: Option<&str>, trace: bool, fork_choice: ForkChoice) {
+	let (genesis_block, genesis_state) = genesis();
+	match data_dir {
+		Some(data_dir) => local_sync_with(trace, fork_choice, |peer_id| {
+			SharedRocksBackend::<_, Auxiliary, State>::open_with_genesis(
+				Path::new(data_dir).join(format!("peer{}", peer_id)),
+				genesis_block.clone(),
+				genesis_state.clone(),
+			).expect("Failed to open database")
+		}),
+		None => local_sync_with(trace, fork_choice, |_| {
+			SharedMemoryBackend::<_, Auxiliary, State>::new_with_genesis(
+				genesis_block.clone(),
+				genesis_state.clone(),
+			)
+		}),
+	}
+}
+
+fn local_sync_with<Ba, F: Fn(usize) -> Ba>(trace: bool, fork_choice: ForkChoice, open: F) where
+	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+	Ba: SharedCommittable<Operation=Operation<engine::GenericBlock, State, Auxiliary>>,
+	Ba: Send + Sync + 'static,
+{
+	let (backend_build, lock_build) = (open(0), ImportLock::new());
+	let pool_build = new_pool(backend_build.clone());
+	let (authored_build, authored) = channel();
+	let mut authored = Some(authored);
+	let mut pool_submit = None;
+	let mut peers = Vec::new();
 	for peer_id in 0..4 {
-		let (backend, lock) = if peer_id == 0 {
-			(backend_build.clone(), lock_build.clone())
+		let (backend, lock, pool) = if peer_id == 0 {
+			(backend_build.clone(), lock_build.clone(), pool_build.clone())
 		} else {
-			(
-				SharedMemoryBackend::<_, (), State>::new_with_genesis(
-					genesis_block.clone(),
-					genesis_state.clone(),
-				),
-				ImportLock::new()
-			)
+			let backend = open(peer_id);
+			let pool = new_pool(backend.clone());
+			(backend, ImportLock::new(), pool)
 		};
-		let importer = BestDepthImporter::new(backend.clone(), lock.clone());
-		let status = BestDepthStatusProducer::new(backend.clone());
-		peers.insert(peer_id, (backend, lock, importer, status));
+		if peer_id == 1 {
+			pool_submit = Some(pool.clone());
+		}
+		// Only the first peer authors blocks, so the others never receive any.
+		let authored = authored.take().unwrap_or_else(|| channel().1);
+		peers.push((peer_id, backend, lock, pool, authored));
 	}
 	thread::spawn(move || {
-		builder_thread(backend_build, lock_build);
+		builder_thread(backend_build, lock_build, pool_build, authored_build);
+	});
+	let pool_submit = pool_submit.expect("Peer 1 exists");
+	thread::spawn(move || {
+		submitter_thread(pool_submit);
 	});
 
-	blockchain_network_local::start_local_simple_sync(peers);
+	match fork_choice {
+		ForkChoice::Depth => blockchain_network_local::start_local_simple_sync(
+			peers.into_iter().map(|(peer_id, backend, lock, pool, authored)| {
+				let importer = BestDepthImporter::new(backend.clone(), lock.clone(), pool.clone(), trace);
+				let status = BestDepthStatusProducer::new(backend.clone());
+				(peer_id, (backend, lock, importer, status, pool, authored))
+			}).collect()
+		),
+		ForkChoice::Difficulty => blockchain_network_local::start_local_simple_sync(
+			peers.into_iter().map(|(peer_id, backend, lock, pool, authored)| {
+				let importer = BestDifficultyImporter::new(backend.clone(), lock.clone(), pool.clone(), trace);
+				let status = BestDifficultyStatusProducer::new(backend.clone());
+				(peer_id, (backend, lock, importer, status, pool, authored))
+			}).collect()
+		),
+	}
 }
 
-fn libp2p_sync(port: &str, author: bool) {
-	let runtime_genesis_block = runtime::Block::genesis();
-	let genesis_block: engine::GenericBlock = runtime_genesis_block.clone().into();
-	let genesis_state = State {
-		code: runtime::WASM_BINARY.to_vec(),
-		trie: Some(Default::default()),
-	};
-	let backend = SharedMemoryBackend::<_, (), State>::new_with_genesis(
-		genesis_block.clone(),
-		genesis_state,
-	);
+fn libp2p_sync(
+	config: NetworkConfig, author: bool, trace: bool, fork_choice: ForkChoice, data_dir: Option<&str>,
+) {
+	let (genesis_block, genesis_state) = genesis();
+	match data_dir {
+		Some(data_dir) => libp2p_sync_with(
+			config, author, trace, fork_choice,
+			SharedRocksBackend::<_, Auxiliary, State>::open_with_genesis(
+				data_dir,
+				genesis_block,
+				genesis_state,
+			).expect("Failed to open database"),
+		),
+		None => libp2p_sync_with(
+			config, author, trace, fork_choice,
+			SharedMemoryBackend::<_, Auxiliary, State>::new_with_genesis(
+				genesis_block,
+				genesis_state,
+			),
+		),
+	}
+}
+
+fn libp2p_sync_with<Ba>(
+	config: NetworkConfig, author: bool, trace: bool, fork_choice: ForkChoice, backend: Ba,
+) where
+	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+	Ba: SharedCommittable<Operation=Operation<engine::GenericBlock, State, Auxiliary>>,
+	Ba: Send + Sync + 'static,
+{
 	let lock = ImportLock::new();
-	let importer = BestDepthImporter::new(backend.clone(), lock.clone());
-	let status = BestDepthStatusProducer::new(backend.clone());
+	let pool = new_pool(backend.clone());
+	let (authored_build, authored) = channel();
 	if author {
 		let backend_build = backend.clone();
 		let lock_build = lock.clone();
+		let pool_build = pool.clone();
 		thread::spawn(move || {
-			builder_thread(backend_build, lock_build);
+			builder_thread(backend_build, lock_build, pool_build, authored_build);
 		});
 	}
-	blockchain_network_libp2p::start_network_simple_sync(port, backend, lock, importer, status);
+	match fork_choice {
+		ForkChoice::Depth => {
+			let importer = BestDepthImporter::new(backend.clone(), lock.clone(), pool.clone(), trace);
+			let status = BestDepthStatusProducer::new(backend.clone());
+			blockchain_network_libp2p::start_network_simple_sync(config, backend, lock, importer, status, pool, authored);
+		},
+		ForkChoice::Difficulty => {
+			let importer = BestDifficultyImporter::new(backend.clone(), lock.clone(), pool.clone(), trace);
+			let status = BestDifficultyStatusProducer::new(backend.clone());
+			blockchain_network_libp2p::start_network_simple_sync(config, backend, lock, importer, status, pool, authored);
+		},
+	}
+}
+
+fn tcp_sync(
+	config: TcpConfig, author: bool, trace: bool, fork_choice: ForkChoice, data_dir: Option<&str>,
+) {
+	let (genesis_block, genesis_state) = genesis();
+	match data_dir {
+		Some(data_dir) => tcp_sync_with(
+			config, author, trace, fork_choice,
+			SharedRocksBackend::<_, Auxiliary, State>::open_with_genesis(
+				data_dir,
+				genesis_block,
+				genesis_state,
+			).expect("Failed to open database"),
+		),
+		None => tcp_sync_with(
+			config, author, trace, fork_choice,
+			SharedMemoryBackend::<_, Auxiliary, State>::new_with_genesis(
+				genesis_block,
+				genesis_state,
+			),
+		),
+	}
+}
+
+fn tcp_sync_with<Ba>(
+	config: TcpConfig, author: bool, trace: bool, fork_choice: ForkChoice, backend: Ba,
+) where
+	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+	Ba: SharedCommittable<Operation=Operation<engine::GenericBlock, State, Auxiliary>>,
+	Ba: Send + Sync + 'static,
+{
+	let lock = ImportLock::new();
+	let pool = new_pool(backend.clone());
+	let (authored_build, authored) = channel();
+	if author {
+		let backend_build = backend.clone();
+		let lock_build = lock.clone();
+		let pool_build = pool.clone();
+		thread::spawn(move || {
+			builder_thread(backend_build, lock_build, pool_build, authored_build);
+		});
+	}
+	match fork_choice {
+		ForkChoice::Depth => {
+			let importer = BestDepthImporter::new(backend.clone(), lock.clone(), pool.clone(), trace);
+			let status = BestDepthStatusProducer::new(backend.clone());
+			blockchain_network_tcp::start_tcp_simple_sync(config, backend, lock, importer, status, pool, authored);
+		},
+		ForkChoice::Difficulty => {
+			let importer = BestDifficultyImporter::new(backend.clone(), lock.clone(), pool.clone(), trace);
+			let status = BestDifficultyStatusProducer::new(backend.clone());
+			blockchain_network_tcp::start_tcp_simple_sync(config, backend, lock, importer, status, pool, authored);
+		},
+	}
 }
 
-fn builder_thread(backend_build: SharedMemoryBackend<engine::GenericBlock, (), State>, lock: ImportLock) {
+fn builder_thread<Ba>(
+	backend_build: Ba, lock: ImportLock, pool: Pool<Ba>, authored: Sender<engine::GenericBlock>
+) where
+	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+	Ba: SharedCommittable<Operation=Operation<engine::GenericBlock, State, Auxiliary>>,
+{
+	let executor = native_dispatcher();
 	loop {
-		build_one(&backend_build, &lock).unwrap();
+		let block = build_one(&backend_build, &lock, &pool, &executor).unwrap();
+		authored.send(block).expect("Network is running");
+		std::thread::sleep(std::time::Duration::new(1, 0));
+	}
+}
+
+fn submitter_thread<Ba>(pool: Pool<Ba>) where
+	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+{
+	let keypair = schnorrkel::Keypair::generate(
+		&mut rand::rngs::OsRng::new().expect("OS randomness is available")
+	);
+	let mut value = 1;
+	let mut nonce = 0;
+	loop {
+		let extrinsic = runtime::Extrinsic::sign(runtime::Call::Add(value), nonce, &keypair);
+		match pool.submit(extrinsic) {
+			Ok(hash) => {
+				println!("Submitted extrinsic {:?}", hash);
+				nonce += 1;
+			},
+			Err(e) => println!("warn: failed to submit extrinsic: {:?}", e),
+		}
+		value += 1;
 		std::thread::sleep(std::time::Duration::new(1, 0));
 	}
 }
 
-fn build_one<Ba>(backend_build: &Ba, lock: &ImportLock) -> Result<(), Error> where
-	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=()> + ChainQuery + ?Sized,
-	Ba: SharedCommittable<Operation=Operation<engine::GenericBlock, State, ()>>,
+fn build_one<Ba>(
+	backend_build: &Ba, lock: &ImportLock, pool: &Pool<Ba>, executor: &NativeDispatcher,
+) -> Result<engine::GenericBlock, Error> where
+	Ba: Store<Block=engine::GenericBlock, State=State, Auxiliary=Auxiliary> + ChainQuery,
+	Ba: SharedCommittable<Operation=Operation<engine::GenericBlock, State, Auxiliary>>,
 {
 	let head = backend_build.head();
-	let runtime_executor = runtime::Executor;
 	println!("Building on top of {:?}", head);
 
 	// Build a block.
@@ -238,20 +998,41 @@ fn build_one<Ba>(backend_build: &Ba, lock: &ImportLock) -> Result<(), Error> whe
 		&mut &backend_build.block_at(&head).map_err(|e| Error::Backend(Box::new(e)))?.data[..]
 	).ok_or(Error::OutdatedRuntime)?;
 	let mut pending_state = backend_build.state_at(&head).map_err(|e| Error::Backend(Box::new(e)))?;
-	if &pending_state.code()[..] != runtime::WASM_BINARY {
-		return Err(Error::OutdatedRuntime)
-	}
+	let runtime_executor = match executor.dispatch(&pending_state.code) {
+		engine::Dispatch::Native(native) => native,
+		engine::Dispatch::Wasm(_) => return Err(Error::OutdatedRuntime),
+	};
 	if pending_state.trie.is_none() {
 		return Err(Error::StateNotAvailable)
 	}
+	let parent_total_difficulty = total_difficulty_at(backend_build, &head)
+		.map_err(|e| Error::Backend(Box::new(e)))?;
 
 	let mut unsealed_block = runtime_executor.initialize_block(
 		&parent_block, &mut pending_state, 1234
 	).map_err(|e| Error::NativeExecutor(Box::new(e)))?;
 
-	runtime_executor.apply_extrinsic(
-		&mut unsealed_block, runtime::Extrinsic::Add(1), &mut pending_state
-	).map_err(|e| Error::NativeExecutor(Box::new(e)))?;
+	for extrinsic in pool.ready().into_iter().take(MAX_BLOCK_EXTRINSICS) {
+		let hash = tree_root::<Sha3_256, _>(&extrinsic);
+		let (signer, nonce) = (extrinsic.signer, extrinsic.nonce);
+		if let Err(e) = runtime_executor.apply_extrinsic(
+			&mut unsealed_block, extrinsic, &mut pending_state
+		) {
+			// Extrinsics waiting for an earlier nonce of their sender, or
+			// failing on the node's own state, are kept for later blocks.
+			let keep = match e {
+				runtime::Error::InvalidNonce => runtime::account_nonce(
+					&signer, unsealed_block.state.clone(), &mut pending_state,
+				).map(|expected| nonce > expected).unwrap_or(true),
+				runtime::Error::Backend => true,
+				_ => false,
+			};
+			if !keep {
+				println!("warn: removing extrinsic {:?} failing to apply: {:?}", hash, e);
+				pool.remove(&hash);
+			}
+		}
+	}
 
 	runtime_executor.finalize_block(
 		&mut unsealed_block, &mut pending_state
@@ -264,9 +1045,16 @@ fn build_one<Ba>(backend_build: &Ba, lock: &ImportLock) -> Result<(), Error> whe
 		backend_build, lock.lock()
 	);
 	let new_block_hash = block.id()[..].to_vec();
+	if let Some(parent_total_difficulty) = parent_total_difficulty {
+		build_importer.insert_auxiliary(TotalDifficulty {
+			id: new_block_hash.clone(),
+			total_difficulty: parent_total_difficulty.saturating_add(block.difficulty as u128),
+		});
+	}
 	build_importer.import_block(block.clone().into(), pending_state);
 	build_importer.set_head(new_block_hash);
 	build_importer.commit().map_err(|e| Error::Backend(Box::new(e)))?;
+	pool.lock().update(&block.extrinsics, Vec::new());
 
-	Ok(())
+	Ok(block.into())
 }